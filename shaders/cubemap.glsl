@@ -0,0 +1,16 @@
+// Shared by the compute shaders writing cube faces as the layers of an image2DArray
+
+const float PI = 3.14159265359;
+
+vec3 cubeDirection(uvec3 id, vec2 size) {
+    vec2 uv = (vec2(id.xy) + 0.5) / size * 2.0 - 1.0;
+
+    switch (id.z) {
+        case 0: return normalize(vec3(1.0, -uv.y, -uv.x));
+        case 1: return normalize(vec3(-1.0, -uv.y, uv.x));
+        case 2: return normalize(vec3(uv.x, 1.0, uv.y));
+        case 3: return normalize(vec3(uv.x, -1.0, -uv.y));
+        case 4: return normalize(vec3(uv.x, -uv.y, 1.0));
+        default: return normalize(vec3(-uv.x, -uv.y, -1.0));
+    }
+}
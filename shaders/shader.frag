@@ -1,15 +1,152 @@
 #version 450
 
-layout(binding = 1) uniform sampler2D texSampler;
+// MAX_LIGHTS is defined by the application
+
+// Point lights have w = 1, directional ones store the direction towards the light
+struct Light {
+    vec4 position;
+    vec4 color;
+};
+
+layout(set = 0, binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    Light lights[MAX_LIGHTS];
+    uint lightCount;
+} ubo;
+
+layout(set = 1, binding = 0) uniform MaterialFactors {
+    vec4 baseColor;
+    float metallic;
+    float roughness;
+    float aoStrength;
+    float alphaCutoff;
+} material;
+
+layout(set = 1, binding = 1) uniform sampler2D albedoMap;
+layout(set = 1, binding = 2) uniform sampler2D metallicMap;
+layout(set = 1, binding = 3) uniform sampler2D roughnessMap;
+layout(set = 1, binding = 4) uniform sampler2D aoMap;
+layout(set = 1, binding = 5) uniform sampler2D opacityMap;
+layout(set = 1, binding = 6) uniform sampler2D normalMap;
+
+layout(set = 2, binding = 0) uniform samplerCube irradianceMap;
+layout(set = 2, binding = 1) uniform samplerCube prefilteredMap;
+layout(set = 2, binding = 2) uniform sampler2D brdfLut;
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec2 fragTexCoord;
 layout(location = 2) in vec3 fragNormal;
+layout(location = 3) in vec3 fragPosition;
+layout(location = 4) in vec3 fragCameraPosition;
+layout(location = 5) in vec4 fragTangent;
 
 layout(location = 0) out vec4 outColor;
 
+const float PI = 3.14159265359;
+
+float distributionGGX(float NdotH, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float denom = NdotH * NdotH * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+float geometrySchlickGGX(float NdotX, float roughness) {
+    float r = roughness + 1.0;
+    float k = (r * r) / 8.0;
+    return NdotX / (NdotX * (1.0 - k) + k);
+}
+
+vec3 fresnelSchlick(float cosTheta, vec3 F0) {
+    return F0 + (1.0 - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+vec3 fresnelSchlickRoughness(float cosTheta, vec3 F0, float roughness) {
+    return F0 + (max(vec3(1.0 - roughness), F0) - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+// Cook-Torrance contribution of one light arriving from L with the given radiance
+vec3 shade(vec3 N, vec3 V, vec3 L, vec3 radiance, vec3 albedo, float metallic, float roughness, vec3 F0) {
+    vec3 H = normalize(V + L);
+
+    float NdotV = max(dot(N, V), 1e-4);
+    float NdotL = max(dot(N, L), 0.0);
+    float NdotH = max(dot(N, H), 0.0);
+
+    vec3 F = fresnelSchlick(max(dot(H, V), 0.0), F0);
+    float D = distributionGGX(NdotH, roughness);
+    float G = geometrySchlickGGX(NdotV, roughness) * geometrySchlickGGX(NdotL, roughness);
+
+    vec3 specular = D * G * F / (4.0 * NdotV * NdotL + 1e-4);
+    vec3 kD = (vec3(1.0) - F) * (1.0 - metallic);
+    return (kD * albedo / PI + specular) * radiance * NdotL;
+}
+
+// Tangent-space normal map, the bitangent sign is stored in the tangent's w
+vec3 perturbNormal() {
+    vec3 N = normalize(fragNormal);
+    vec3 T = fragTangent.xyz - dot(fragTangent.xyz, N) * N;
+    if (dot(T, T) < 1e-8) {
+        return N;
+    }
+
+    T = normalize(T);
+    vec3 B = cross(N, T) * fragTangent.w;
+    vec3 tangentNormal = texture(normalMap, fragTexCoord).xyz * 2.0 - 1.0;
+    return normalize(mat3(T, B, N) * tangentNormal);
+}
+
+// The world is Z-up while the environment cubes are Y-up
+vec3 worldToEnv(vec3 d) {
+    return vec3(d.x, d.z, -d.y);
+}
+
 void main() {
-    outColor = texture(texSampler, fragTexCoord) * vec4(fragColor, 1.0);
-    // outColor = vec4(fragTexCoord, 0.0, 1.0);
-    // outColor = vec4(fragNormal, 1.0);
-}
\ No newline at end of file
+    vec4 albedo = texture(albedoMap, fragTexCoord) * material.baseColor * vec4(fragColor, 1.0);
+    float alpha = albedo.a * texture(opacityMap, fragTexCoord).r;
+    if (alpha < material.alphaCutoff) {
+        discard;
+    }
+
+    float metallic = texture(metallicMap, fragTexCoord).r * material.metallic;
+    float roughness = clamp(texture(roughnessMap, fragTexCoord).r * material.roughness, 0.04, 1.0);
+    float ao = mix(1.0, texture(aoMap, fragTexCoord).r, material.aoStrength);
+
+    vec3 N = perturbNormal();
+    vec3 V = normalize(fragCameraPosition - fragPosition);
+    float NdotV = max(dot(N, V), 1e-4);
+    vec3 F0 = mix(vec3(0.04), albedo.rgb, metallic);
+
+    vec3 Lo = vec3(0.0);
+    for (uint i = 0; i < min(ubo.lightCount, uint(MAX_LIGHTS)); i++) {
+        Light light = ubo.lights[i];
+        vec3 L = light.position.xyz;
+        vec3 radiance = light.color.rgb;
+
+        // Point lights fall off with the inverse square distance
+        if (light.position.w > 0.0) {
+            vec3 toLight = light.position.xyz - fragPosition;
+            L = toLight;
+            radiance /= max(dot(toLight, toLight), 1e-4);
+        }
+
+        Lo += shade(N, V, normalize(L), radiance, albedo.rgb, metallic, roughness, F0);
+    }
+
+    vec3 R = reflect(-V, N);
+    vec3 kS = fresnelSchlickRoughness(NdotV, F0, roughness);
+    vec3 irradiance = texture(irradianceMap, worldToEnv(N)).rgb;
+    float maxLod = float(textureQueryLevels(prefilteredMap) - 1);
+    vec3 prefiltered = textureLod(prefilteredMap, worldToEnv(R), roughness * maxLod).rgb;
+    vec2 brdf = texture(brdfLut, vec2(NdotV, roughness)).rg;
+
+    vec3 diffuseIBL = (vec3(1.0) - kS) * (1.0 - metallic) * irradiance * albedo.rgb;
+    vec3 specularIBL = prefiltered * (kS * brdf.x + brdf.y);
+    vec3 ambient = (diffuseIBL + specularIBL) * ao;
+
+    vec3 color = ambient + Lo;
+    color = color / (color + vec3(1.0));
+
+    outColor = vec4(color, alpha);
+}
@@ -1,28 +1,47 @@
 #version 450
 
+// MAX_LIGHTS is defined by the application
+
+struct Light {
+    vec4 position;
+    vec4 color;
+};
+
 layout(binding = 0) uniform UniformBufferObject {
-    mat4 model;
     mat4 view;
     mat4 proj;
+    Light lights[MAX_LIGHTS];
+    uint lightCount;
 } ubo;
 
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+} object;
+
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inColor;
 layout(location = 2) in vec2 inTexCoord;
 layout(location = 3) in vec3 inNormal;
+layout(location = 4) in vec4 inTangent;
 
 layout(location = 0) out vec3 fragColor;
 layout(location = 1) out vec2 fragTexCoord;
 layout(location = 2) out vec3 fragNormal;
+layout(location = 3) out vec3 fragPosition;
+layout(location = 4) out vec3 fragCameraPosition;
+layout(location = 5) out vec4 fragTangent;
 
 void main() {
-    gl_Position = ubo.proj * ubo.view * ubo.model * vec4(inPosition, 1.0);
+    vec4 worldPosition = object.model * vec4(inPosition, 1.0);
+    gl_Position = ubo.proj * ubo.view * worldPosition;
+
+    // Keeps normals perpendicular under non-uniform node scales
+    mat3 normalMatrix = transpose(inverse(mat3(object.model)));
 
     fragColor = inColor;
     fragTexCoord = inTexCoord;
-    fragNormal = normalize(
-        ubo.model[0].xyz * inNormal.x +
-        ubo.model[1].xyz * inNormal.y +
-        ubo.model[2].xyz * inNormal.z
-    );
-}
\ No newline at end of file
+    fragNormal = normalize(normalMatrix * inNormal);
+    fragTangent = vec4(mat3(object.model) * inTangent.xyz, inTangent.w);
+    fragPosition = worldPosition.xyz;
+    fragCameraPosition = inverse(ubo.view)[3].xyz;
+}
@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::{ArgGroup, Parser, ValueEnum};
+use vulkanalia::prelude::v1_0::*;
+
+pub const MAX_FRAMES_IN_FLIGHT: usize = 4;
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Physically based Vulkan model viewer")]
+#[command(after_help = "Keys: Tab camera mode, V vsync, P next present mode, [ ] image count, - = frames in flight, M MSAA")]
+#[command(group(ArgGroup::new("source").args(["scene", "model"])))]
+pub struct Args {
+    #[arg(long, value_name = "FILE.ron", help = "Scene description to load [default: resources/scenes/jvctv.ron]")]
+    pub scene: Option<String>,
+
+    #[arg(long, value_name = "FILE", help = "Single OBJ, glTF or GLB model to show instead of a scene")]
+    pub model: Option<String>,
+
+    #[arg(long, value_name = "FILE.png", requires = "model", help = "Albedo texture for a model without materials")]
+    pub texture: Option<String>,
+
+    #[arg(long, value_name = "WIDTHxHEIGHT", default_value = "1024x768", value_parser = parse_resolution,
+        help = "Window or headless image size")]
+    pub resolution: vk::Extent2D,
+
+    #[arg(long, conflicts_with = "headless", help = "Borderless fullscreen on the current monitor")]
+    pub fullscreen: bool,
+
+    #[arg(long, value_enum, help = "Preferred present mode, overrides --no-vsync and falls back to it when unavailable")]
+    pub present_mode: Option<PresentMode>,
+
+    #[arg(long, help = "Allow tearing: prefer IMMEDIATE, then MAILBOX, over FIFO")]
+    pub no_vsync: bool,
+
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..),
+        help = "Preferred swapchain image count, clamped to what the surface allows [default: minimum + 1]")]
+    pub image_count: Option<u32>,
+
+    #[arg(long, value_name = "COUNT", default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..=MAX_FRAMES_IN_FLIGHT as u64),
+        help = "Frames the CPU may record ahead of the GPU")]
+    pub frames_in_flight: u64,
+
+    #[arg(long, value_name = "SAMPLES", value_parser = parse_sample_count,
+        help = "MSAA sample count: 1 (off), 2, 4, 8, 16, 32 or 64, capped by the GPU [default: highest supported]")]
+    pub msaa: Option<vk::SampleCountFlags>,
+
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.2, value_parser = parse_fraction,
+        help = "Minimum fraction of MSAA samples shaded separately, 0 shades once per pixel")]
+    pub min_sample_shading: f32,
+
+    #[arg(long, value_name = "INDEX|NAME", help = "GPU to use, by index or part of its name [default: highest rated]")]
+    pub gpu: Option<String>,
+
+    #[arg(long, overrides_with = "no_validation", help = "Enable the Khronos validation layer [default in debug builds]")]
+    pub validation: bool,
+
+    #[arg(long, overrides_with = "validation", help = "Disable the Khronos validation layer")]
+    pub no_validation: bool,
+
+    #[arg(long, help = "Fail the frame on any validation error")]
+    pub strict_validation: bool,
+
+    #[arg(long, value_name = "ID", help = "Validation message ID (name or 0x hex) to ignore, repeatable")]
+    pub allow_message: Vec<String>,
+
+    #[arg(long, help = "Print GPU memory usage per memory type once the scene is loaded")]
+    pub memory_stats: bool,
+
+    #[arg(long, value_name = "FILE", default_value = "pipeline_cache.bin",
+        help = "Pipeline cache loaded at startup and saved on exit, ignored when written by another GPU or driver")]
+    pub pipeline_cache: PathBuf,
+
+    #[arg(long, help = "Neither load nor save the pipeline cache")]
+    pub no_pipeline_cache: bool,
+
+    #[arg(long, conflicts_with = "headless", help = "Recompile shaders/ when it changes and rebuild the pipeline")]
+    pub watch_shaders: bool,
+
+    #[arg(long, value_name = "FILE.png", help = "Render a single frame offscreen to this file and exit")]
+    pub headless: Option<String>,
+
+    #[arg(long, help = "Print the properties, limits, queue families, formats and extensions of every GPU and exit")]
+    pub list_devices: bool
+}
+
+impl Args {
+    pub fn validation_enabled(&self) -> bool {
+        match (self.validation, self.no_validation) {
+            (true, _) => true,
+            (_, true) => false,
+            _ => cfg!(debug_assertions)
+        }
+    }
+
+    pub fn pipeline_cache_path(&self) -> Option<&Path> {
+        (!self.no_pipeline_cache).then_some(self.pipeline_cache.as_path())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PresentMode {
+    Fifo,
+    FifoRelaxed,
+    Mailbox,
+    Immediate
+}
+
+impl From<PresentMode> for vk::PresentModeKHR {
+    fn from(mode: PresentMode) -> Self {
+        match mode {
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::FifoRelaxed => vk::PresentModeKHR::FIFO_RELAXED,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE
+        }
+    }
+}
+
+fn parse_resolution(value: &str) -> Result<vk::Extent2D> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| anyhow!("expected WIDTHxHEIGHT, e.g. 1920x1080"))?;
+
+    let extent = vk::Extent2D { width: width.trim().parse()?, height: height.trim().parse()? };
+    if extent.width == 0 || extent.height == 0 {
+        return Err(anyhow!("width and height must not be zero"));
+    }
+
+    Ok(extent)
+}
+
+fn parse_sample_count(value: &str) -> Result<vk::SampleCountFlags> {
+    match value.parse::<u32>()? {
+        1 => Ok(vk::SampleCountFlags::_1),
+        2 => Ok(vk::SampleCountFlags::_2),
+        4 => Ok(vk::SampleCountFlags::_4),
+        8 => Ok(vk::SampleCountFlags::_8),
+        16 => Ok(vk::SampleCountFlags::_16),
+        32 => Ok(vk::SampleCountFlags::_32),
+        64 => Ok(vk::SampleCountFlags::_64),
+        _ => Err(anyhow!("expected 1, 2, 4, 8, 16, 32 or 64"))
+    }
+}
+
+fn parse_fraction(value: &str) -> Result<f32> {
+    let fraction = value.parse::<f32>()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(anyhow!("expected a value between 0 and 1"));
+    }
+
+    Ok(fraction)
+}
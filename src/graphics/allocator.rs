@@ -0,0 +1,578 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter;
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use vulkanalia::prelude::v1_1::*;
+
+// Size of the blocks resources are sub-allocated from, heaps of up to 1 GiB use an eighth of their size
+const BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+const SMALL_HEAP_SIZE: vk::DeviceSize = 1024 * 1024 * 1024;
+
+// Resource memory is allocated for, dedicated memory is bound to nothing else
+#[derive(Copy, Clone, Debug)]
+enum Resource {
+    Buffer(vk::Buffer),
+    Image(vk::Image)
+}
+
+// Buffers and linear images may not share a `bufferImageGranularity` page with optimal images
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Linear,
+    Optimal
+}
+
+// A range of device memory bound to a single buffer or image
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    memory_type: u32,
+    // Owns all of `memory` instead of a range of a block
+    dedicated: bool,
+    // Start of the range in the persistent mapping, host-visible memory only
+    mapped: Option<NonNull<c_void>>
+}
+
+impl Allocation {
+    // Host-visible memory is mapped once and stays mapped until it is freed
+    pub fn mapped_ptr(&self) -> Result<*mut c_void> {
+        self.mapped.map(NonNull::as_ptr).ok_or_else(|| anyhow!("Allocation is not host visible."))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MemoryTypeStats {
+    pub blocks: u32,
+    pub block_bytes: vk::DeviceSize,
+    pub suballocations: u32,
+    pub suballocated_bytes: vk::DeviceSize,
+    pub dedicated_allocations: u32,
+    pub dedicated_bytes: vk::DeviceSize,
+    // Most block and dedicated bytes held at once
+    pub peak_bytes: vk::DeviceSize
+}
+
+impl MemoryTypeStats {
+    fn update_peak(&mut self) {
+        self.peak_bytes = self.peak_bytes.max(self.block_bytes + self.dedicated_bytes);
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AllocatorStats {
+    // Every memory type that was used so far, as (index, properties, stats)
+    pub memory_types: Vec<(u32, vk::MemoryPropertyFlags, MemoryTypeStats)>,
+    pub device_allocations: u32,
+    pub max_device_allocations: u32
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mib = |bytes: vk::DeviceSize| bytes as f64 / (1024.0 * 1024.0);
+
+        write!(f, "GPU memory: {} of {} device allocations", self.device_allocations, self.max_device_allocations)?;
+        for (index, properties, stats) in &self.memory_types {
+            write!(
+                f,
+                "\n  Type {} ({:?}): {} block(s) of {:.1} MiB with {} sub-allocation(s) using {:.1} MiB, \
+                 {} dedicated allocation(s) of {:.1} MiB, peak {:.1} MiB",
+                index, properties,
+                stats.blocks, mib(stats.block_bytes),
+                stats.suballocations, mib(stats.suballocated_bytes),
+                stats.dedicated_allocations, mib(stats.dedicated_bytes),
+                mib(stats.peak_bytes)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped: Option<NonNull<c_void>>,
+    // Sub-allocations keyed by offset, as (size, kind)
+    used: BTreeMap<vk::DeviceSize, (vk::DeviceSize, ResourceKind)>
+}
+
+// The mapping is only handed out through allocations of the block and never dereferenced here
+unsafe impl Send for Block {}
+
+impl Block {
+    fn suballocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        kind: ResourceKind,
+        granularity: vk::DeviceSize
+    ) -> Option<vk::DeviceSize> {
+        let offset = self.find_space(size, alignment, kind, granularity)?;
+        self.used.insert(offset, (size, kind));
+        Some(offset)
+    }
+
+    // Free ranges are the gaps between sub-allocations, so neighbouring gaps merge on their own
+    fn free(&mut self, offset: vk::DeviceSize) -> bool {
+        self.used.remove(&offset).is_some()
+    }
+
+    // First fit, keeping resources of another kind off the granularity pages of their neighbours
+    fn find_space(
+        &self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        kind: ResourceKind,
+        granularity: vk::DeviceSize
+    ) -> Option<vk::DeviceSize> {
+        if size == 0 {
+            return None;
+        }
+
+        let neighbours = self.used
+            .iter()
+            .map(|(offset, (size, kind))| (*offset, *size, Some(*kind)))
+            .chain(iter::once((self.size, 0, None)));
+
+        // End and kind of the sub-allocation before the current gap
+        let mut previous: Option<(vk::DeviceSize, ResourceKind)> = None;
+
+        for (next_offset, next_size, next_kind) in neighbours {
+            let mut offset = align_up(previous.map_or(0, |(end, _)| end), alignment);
+            if let Some((end, previous_kind)) = previous {
+                if previous_kind != kind && same_page(end - 1, offset, granularity) {
+                    offset = align_up(offset, granularity.max(alignment));
+                }
+            }
+
+            let end = offset + size;
+            let conflicts = next_kind.is_some_and(|k| k != kind && same_page(end - 1, next_offset, granularity));
+            if end <= next_offset && !conflicts {
+                return Some(offset);
+            }
+
+            previous = next_kind.map(|k| (next_offset + next_size, k));
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pool {
+    blocks: Vec<Block>,
+    stats: MemoryTypeStats
+}
+
+#[derive(Debug, Default)]
+struct AllocatorState {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    buffer_image_granularity: vk::DeviceSize,
+    max_device_allocations: u32,
+    device_allocations: u32,
+    // One per memory type
+    pools: Vec<Pool>
+}
+
+impl AllocatorState {
+    fn memory_type_index(&self, properties: vk::MemoryPropertyFlags, requirements: vk::MemoryRequirements) -> Result<u32> {
+        (0..self.memory_properties.memory_type_count)
+            .find(|i| {
+                let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
+                let memory_type = self.memory_properties.memory_types[*i as usize];
+                suitable && memory_type.property_flags.contains(properties)
+            })
+            .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
+    }
+
+    fn block_size(&self, memory_type: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        if heap_size <= SMALL_HEAP_SIZE { heap_size / 8 } else { BLOCK_SIZE }
+    }
+
+    unsafe fn allocate_memory(
+        &mut self,
+        device: &Device,
+        memory_type: u32,
+        size: vk::DeviceSize,
+        dedicated: Option<Resource>
+    ) -> Result<(vk::DeviceMemory, Option<NonNull<c_void>>)> {
+        if self.device_allocations >= self.max_device_allocations {
+            return Err(anyhow!("Reached the limit of {} device memory allocations.", self.max_device_allocations));
+        }
+
+        let mut dedicated_info = match dedicated {
+            Some(Resource::Buffer(buffer)) => vk::MemoryDedicatedAllocateInfo::builder().buffer(buffer),
+            Some(Resource::Image(image)) => vk::MemoryDedicatedAllocateInfo::builder().image(image),
+            None => vk::MemoryDedicatedAllocateInfo::builder()
+        };
+
+        let mut info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type);
+
+        if dedicated.is_some() {
+            info = info.push_next(&mut dedicated_info);
+        }
+
+        let memory = device.allocate_memory(&info, None)?;
+
+        let properties = self.memory_properties.memory_types[memory_type as usize].property_flags;
+        let mapped = if properties.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            match device.map_memory(memory, 0, vk::WHOLE_SIZE as vk::DeviceSize, vk::MemoryMapFlags::empty()) {
+                Ok(pointer) => NonNull::new(pointer),
+                Err(e) => {
+                    device.free_memory(memory, None);
+                    return Err(anyhow!(e));
+                }
+            }
+        } else {
+            None
+        };
+
+        self.device_allocations += 1;
+        Ok((memory, mapped))
+    }
+
+    unsafe fn free_memory(&mut self, device: &Device, memory: vk::DeviceMemory) {
+        // Freeing also unmaps
+        device.free_memory(memory, None);
+        self.device_allocations -= 1;
+    }
+}
+
+// Sub-allocates buffers and images from large blocks per memory type instead of calling
+// `vkAllocateMemory` for every resource, which drivers cap at `maxMemoryAllocationCount`
+#[derive(Debug, Default)]
+pub struct Allocator {
+    state: Mutex<AllocatorState>,
+    // Whether the device reports `VkMemoryDedicatedRequirements`, core since Vulkan 1.1
+    dedicated_requirements: bool
+}
+
+impl Allocator {
+    pub unsafe fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+        let properties = instance.get_physical_device_properties(physical_device);
+        let limits = properties.limits;
+
+        let state = AllocatorState {
+            memory_properties,
+            buffer_image_granularity: limits.buffer_image_granularity,
+            max_device_allocations: limits.max_memory_allocation_count,
+            device_allocations: 0,
+            pools: (0..memory_properties.memory_type_count).map(|_| Pool::default()).collect()
+        };
+
+        Allocator {
+            state: Mutex::new(state),
+            dedicated_requirements: properties.api_version >= vk::make_version(1, 1, 0)
+        }
+    }
+
+    pub unsafe fn allocate_buffer(
+        &self,
+        device: &Device,
+        buffer: vk::Buffer,
+        properties: vk::MemoryPropertyFlags
+    ) -> Result<Allocation> {
+        self.allocate(device, Resource::Buffer(buffer), properties, ResourceKind::Linear)
+    }
+
+    pub unsafe fn allocate_image(
+        &self,
+        device: &Device,
+        image: vk::Image,
+        properties: vk::MemoryPropertyFlags,
+        kind: ResourceKind
+    ) -> Result<Allocation> {
+        self.allocate(device, Resource::Image(image), properties, kind)
+    }
+
+    // The requirements and whether the driver prefers or requires a dedicated allocation
+    unsafe fn requirements(&self, device: &Device, resource: Resource) -> (vk::MemoryRequirements, bool) {
+        if !self.dedicated_requirements {
+            let requirements = match resource {
+                Resource::Buffer(buffer) => device.get_buffer_memory_requirements(buffer),
+                Resource::Image(image) => device.get_image_memory_requirements(image)
+            };
+
+            return (requirements, false);
+        }
+
+        let mut dedicated = vk::MemoryDedicatedRequirements::builder();
+        let mut requirements = vk::MemoryRequirements2::builder().push_next(&mut dedicated);
+
+        match resource {
+            Resource::Buffer(buffer) => {
+                let info = vk::BufferMemoryRequirementsInfo2::builder().buffer(buffer);
+                device.get_buffer_memory_requirements2(&info, &mut requirements);
+            }
+            Resource::Image(image) => {
+                let info = vk::ImageMemoryRequirementsInfo2::builder().image(image);
+                device.get_image_memory_requirements2(&info, &mut requirements);
+            }
+        }
+
+        let requirements = requirements.memory_requirements;
+        let dedicated = dedicated.prefers_dedicated_allocation == vk::TRUE
+            || dedicated.requires_dedicated_allocation == vk::TRUE;
+
+        (requirements, dedicated)
+    }
+
+    // Images larger than half a block and resources the driver wants on their own get dedicated
+    // allocations, buffers only get memory of their own when they don't fit a block
+    unsafe fn allocate(
+        &self,
+        device: &Device,
+        resource: Resource,
+        properties: vk::MemoryPropertyFlags,
+        kind: ResourceKind
+    ) -> Result<Allocation> {
+        let (requirements, driver_dedicated) = self.requirements(device, resource);
+        let size = requirements.size;
+
+        if size == 0 {
+            return Err(anyhow!("Cannot allocate memory for a resource of size 0."));
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        let memory_type = state.memory_type_index(properties, requirements)?;
+        let block_size = state.block_size(memory_type);
+
+        let large_image = matches!(resource, Resource::Image(_)) && size > block_size / 2;
+        let dedicated = (driver_dedicated || large_image).then_some(resource);
+
+        if dedicated.is_some() || size > block_size {
+            let (memory, mapped) = state.allocate_memory(device, memory_type, size, dedicated)?;
+
+            let stats = &mut state.pools[memory_type as usize].stats;
+            stats.dedicated_allocations += 1;
+            stats.dedicated_bytes += size;
+            stats.update_peak();
+
+            return Ok(Allocation { memory, offset: 0, size, memory_type, dedicated: true, mapped });
+        }
+
+        let granularity = state.buffer_image_granularity;
+        let found = state.pools[memory_type as usize].blocks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, b)| Some((i, b.suballocate(size, requirements.alignment, kind, granularity)?)));
+
+        let (index, offset) = match found {
+            Some(found) => found,
+            None => {
+                let (memory, mapped) = state.allocate_memory(device, memory_type, block_size, None)?;
+
+                let pool = &mut state.pools[memory_type as usize];
+                pool.blocks.push(Block { memory, size: block_size, mapped, used: BTreeMap::from([(0, (size, kind))]) });
+                pool.stats.blocks += 1;
+                pool.stats.block_bytes += block_size;
+                pool.stats.update_peak();
+
+                (pool.blocks.len() - 1, 0)
+            }
+        };
+
+        let pool = &mut state.pools[memory_type as usize];
+        pool.stats.suballocations += 1;
+        pool.stats.suballocated_bytes += size;
+
+        let block = &pool.blocks[index];
+
+        Ok(Allocation {
+            memory: block.memory,
+            offset,
+            size,
+            memory_type,
+            dedicated: false,
+            mapped: block.mapped.and_then(|p| NonNull::new(p.as_ptr().cast::<u8>().add(offset as usize).cast()))
+        })
+    }
+
+    // Null allocations are ignored, like null handles passed to `vkFree*`
+    pub unsafe fn free(&self, device: &Device, allocation: &Allocation) {
+        if allocation.memory.is_null() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let pool = &mut state.pools[allocation.memory_type as usize];
+
+        if allocation.dedicated {
+            pool.stats.dedicated_allocations -= 1;
+            pool.stats.dedicated_bytes -= allocation.size;
+            state.free_memory(device, allocation.memory);
+            return;
+        }
+
+        let Some(index) = pool.blocks.iter().position(|b| b.memory == allocation.memory) else {
+            warn!("Freed an allocation of {:?} that is not part of any block.", allocation.memory);
+            return;
+        };
+
+        if !pool.blocks[index].free(allocation.offset) {
+            warn!("Freed an allocation at offset {} of {:?} twice.", allocation.offset, allocation.memory);
+            return;
+        }
+
+        pool.stats.suballocations -= 1;
+        pool.stats.suballocated_bytes -= allocation.size;
+
+        // One empty block is kept so resources recreated on resize don't allocate it again
+        let empty_blocks = pool.blocks.iter().filter(|b| b.used.is_empty()).count();
+        if pool.blocks[index].used.is_empty() && empty_blocks > 1 {
+            let block = pool.blocks.remove(index);
+            pool.stats.blocks -= 1;
+            pool.stats.block_bytes -= block.size;
+            state.free_memory(device, block.memory);
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let state = self.state.lock().unwrap();
+
+        let memory_types = state.pools
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.stats.peak_bytes > 0)
+            .map(|(i, p)| (i as u32, state.memory_properties.memory_types[i].property_flags, p.stats))
+            .collect();
+
+        AllocatorStats {
+            memory_types,
+            device_allocations: state.device_allocations,
+            max_device_allocations: state.max_device_allocations
+        }
+    }
+
+    // Frees the blocks, every allocation should have been freed before
+    pub unsafe fn destroy(&self, device: &Device) {
+        let mut state = self.state.lock().unwrap();
+
+        let leaked = state.pools
+            .iter()
+            .map(|p| p.stats.suballocations + p.stats.dedicated_allocations)
+            .sum::<u32>();
+
+        if leaked > 0 {
+            warn!("{} allocation(s) were not freed before destroying the allocator.", leaked);
+        }
+
+        let blocks = state.pools.iter_mut().flat_map(|p| p.blocks.drain(..)).collect::<Vec<_>>();
+        for block in blocks {
+            state.free_memory(device, block.memory);
+        }
+    }
+}
+
+fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    offset.div_ceil(alignment.max(1)) * alignment.max(1)
+}
+
+fn same_page(a: vk::DeviceSize, b: vk::DeviceSize, page_size: vk::DeviceSize) -> bool {
+    a / page_size.max(1) == b / page_size.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ResourceKind::*;
+
+    fn block(size: vk::DeviceSize) -> Block {
+        Block { memory: vk::DeviceMemory::null(), size, mapped: None, used: BTreeMap::new() }
+    }
+
+    #[test]
+    fn align_up_rounds_to_the_next_multiple() {
+        assert_eq!(align_up(0, 256), 0);
+        assert_eq!(align_up(1, 256), 256);
+        assert_eq!(align_up(256, 256), 256);
+        assert_eq!(align_up(257, 256), 512);
+        assert_eq!(align_up(7, 1), 7);
+        assert_eq!(align_up(7, 0), 7);
+    }
+
+    #[test]
+    fn find_space_is_first_fit_and_aligned() {
+        let mut block = block(1024);
+        assert_eq!(block.suballocate(100, 64, Linear, 1), Some(0));
+        assert_eq!(block.suballocate(100, 64, Linear, 1), Some(128));
+        assert_eq!(block.suballocate(10, 1, Linear, 1), Some(100));
+
+        // Gaps are left at 110..128, 228..512 and 768..1024
+        block.used.insert(512, (256, Linear));
+        assert_eq!(block.find_space(300, 1, Linear, 1), None);
+        assert_eq!(block.find_space(256, 256, Linear, 1), Some(256));
+        assert_eq!(block.find_space(200, 1, Linear, 1), Some(228));
+        assert_eq!(block.find_space(18, 1, Linear, 1), Some(110));
+    }
+
+    #[test]
+    fn find_space_rejects_zero_and_oversized_requests() {
+        let block = block(1024);
+        assert_eq!(block.find_space(0, 1, Linear, 1), None);
+        assert_eq!(block.find_space(1025, 1, Linear, 1), None);
+        assert_eq!(block.find_space(1024, 1, Linear, 1), Some(0));
+    }
+
+    #[test]
+    fn freed_neighbours_merge_into_one_gap() {
+        let mut block = block(300);
+        assert_eq!(block.suballocate(100, 1, Linear, 1), Some(0));
+        assert_eq!(block.suballocate(100, 1, Linear, 1), Some(100));
+        assert_eq!(block.suballocate(100, 1, Linear, 1), Some(200));
+        assert_eq!(block.find_space(1, 1, Linear, 1), None);
+
+        assert!(block.free(0));
+        assert!(block.free(100));
+        assert!(!block.free(100));
+        assert_eq!(block.suballocate(200, 1, Linear, 1), Some(0));
+
+        assert!(block.free(0));
+        assert!(block.free(200));
+        assert!(block.used.is_empty());
+        assert_eq!(block.find_space(300, 1, Linear, 1), Some(0));
+    }
+
+    #[test]
+    fn other_kinds_keep_off_shared_granularity_pages() {
+        let mut block = block(4096);
+        assert_eq!(block.suballocate(100, 16, Linear, 1024), Some(0));
+        assert_eq!(block.suballocate(100, 16, Optimal, 1024), Some(1024));
+        assert_eq!(block.suballocate(100, 16, Optimal, 1024), Some(1136));
+
+        // Too large for the gap in front, and may not share the page the optimal images end on
+        assert_eq!(block.suballocate(1000, 16, Linear, 1024), Some(2048));
+
+        // Resources of the same kind share pages
+        assert_eq!(block.suballocate(100, 16, Linear, 1024), Some(112));
+        assert_eq!(block.suballocate(100, 16, Optimal, 1024), Some(1248));
+    }
+
+    #[test]
+    fn gaps_before_a_neighbour_of_another_kind_respect_its_page() {
+        let mut block = block(4096);
+        block.used.insert(512, (100, Optimal));
+
+        // Would end on the page the optimal image starts on
+        assert_eq!(block.find_space(100, 16, Linear, 1024), Some(1024));
+        assert_eq!(block.find_space(100, 16, Optimal, 1024), Some(0));
+
+        // Without a granularity restriction the gap in front is used
+        assert_eq!(block.find_space(100, 16, Linear, 1), Some(0));
+    }
+}
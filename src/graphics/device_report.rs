@@ -0,0 +1,71 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use vulkanalia::prelude::v1_0::*;
+
+// Formats the renderer relies on, or might fall back to
+const REPORTED_FORMATS: &[vk::Format] = &[
+    vk::Format::R8_UNORM,
+    vk::Format::R8G8B8A8_UNORM,
+    vk::Format::R8G8B8A8_SRGB,
+    vk::Format::B8G8R8A8_SRGB,
+    vk::Format::R16G16B16A16_SFLOAT,
+    vk::Format::R32G32B32A32_SFLOAT,
+    vk::Format::D32_SFLOAT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT
+];
+
+// Human-readable dump of everything the device reports about itself
+pub unsafe fn device_report(instance: &Instance, physical_device: vk::PhysicalDevice) -> Result<String> {
+    let mut report = String::new();
+
+    let properties = instance.get_physical_device_properties(physical_device);
+    let version = |v: u32| format!("{}.{}.{}", vk::version_major(v), vk::version_minor(v), vk::version_patch(v));
+
+    writeln!(report, "  Name: {}", properties.device_name)?;
+    writeln!(report, "  Type: {:?}", properties.device_type)?;
+    writeln!(report, "  API version: {}", version(properties.api_version))?;
+    writeln!(report, "  Driver version: {:#x}", properties.driver_version)?;
+    writeln!(report, "  Vendor / device ID: {:#06x} / {:#06x}", properties.vendor_id, properties.device_id)?;
+
+    let memory = instance.get_physical_device_memory_properties(physical_device);
+    writeln!(report, "  Memory heaps:")?;
+    for (i, heap) in memory.memory_heaps[..memory.memory_heap_count as usize].iter().enumerate() {
+        writeln!(report, "    {}: {} MiB {:?}", i, heap.size / (1024 * 1024), heap.flags)?;
+    }
+
+    writeln!(report, "  Memory types:")?;
+    for (i, memory_type) in memory.memory_types[..memory.memory_type_count as usize].iter().enumerate() {
+        writeln!(report, "    {}: heap {} {:?}", i, memory_type.heap_index, memory_type.property_flags)?;
+    }
+
+    writeln!(report, "  Queue families:")?;
+    for (i, family) in instance.get_physical_device_queue_family_properties(physical_device).iter().enumerate() {
+        writeln!(report, "    {}: {} queue(s) {:?}", i, family.queue_count, family.queue_flags)?;
+    }
+
+    writeln!(report, "  Formats (optimal tiling):")?;
+    for format in REPORTED_FORMATS {
+        let features = instance.get_physical_device_format_properties(physical_device, *format).optimal_tiling_features;
+        writeln!(report, "    {:?}: {:?}", format, features)?;
+    }
+
+    let mut extensions = instance
+        .enumerate_device_extension_properties(physical_device, None)?
+        .iter()
+        .map(|e| format!("{} (v{})", e.extension_name, e.spec_version))
+        .collect::<Vec<_>>();
+
+    extensions.sort();
+    writeln!(report, "  Extensions ({}):", extensions.len())?;
+    for extension in &extensions {
+        writeln!(report, "    {}", extension)?;
+    }
+
+    let features = instance.get_physical_device_features(physical_device);
+    writeln!(report, "  Features: {:#?}", features)?;
+    writeln!(report, "  Limits: {:#?}", properties.limits)?;
+
+    Ok(report)
+}
@@ -0,0 +1,296 @@
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_void};
+use std::slice;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use thiserror::Error;
+use vulkanalia::prelude::v1_0::*;
+
+#[derive(Clone, Debug)]
+pub struct DiagnosticObject {
+    pub object_type: vk::ObjectType,
+    pub handle: u64,
+    pub name: Option<String>
+}
+
+// One validation-layer message with everything the callback data carries
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    pub message_id_name: Option<String>,
+    pub message_id_number: i32,
+    pub message: String,
+    pub objects: Vec<DiagnosticObject>,
+    // Queue labels followed by command buffer labels, innermost last
+    pub labels: Vec<String>
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+    }
+
+    // The layers print message IDs as 32-bit hex numbers, either form can be allowlisted
+    fn matches(&self, id: &str) -> bool {
+        self.message_id_name.as_deref() == Some(id) || format!("{:#010x}", self.message_id_number as u32) == id
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{} {:#010x}] ({:?}) {}",
+            self.message_id_name.as_deref().unwrap_or("?"),
+            self.message_id_number as u32,
+            self.message_type,
+            self.message
+        )?;
+
+        for object in &self.objects {
+            write!(f, "\n    object {:?} {:#x}", object.object_type, object.handle)?;
+            if let Some(name) = &object.name {
+                write!(f, " `{}`", name)?;
+            }
+        }
+
+        for label in &self.labels {
+            write!(f, "\n    label `{}`", label)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{count} validation error(s) this frame, the first was: {first}")]
+pub struct ValidationError {
+    pub count: usize,
+    pub first: String
+}
+
+#[derive(Debug, Default)]
+struct FrameDiagnostics {
+    messages: Vec<Diagnostic>,
+    errors: usize
+}
+
+// Collects validation messages between calls to `end_frame`, shared with the debug messenger
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    // Any unallowlisted error fails the frame instead of only being logged
+    strict: bool,
+    allowlist: HashSet<String>,
+    frame: Mutex<FrameDiagnostics>
+}
+
+impl Diagnostics {
+    pub fn new(strict: bool, allowlist: impl IntoIterator<Item = String>) -> Self {
+        Diagnostics { strict, allowlist: allowlist.into_iter().collect(), ..Default::default() }
+    }
+
+    fn record(&self, diagnostic: Diagnostic) {
+        if self.allowlist.iter().any(|id| diagnostic.matches(id)) {
+            trace!("(allowed) {}", diagnostic);
+            return;
+        }
+
+        if diagnostic.is_error() {
+            error!("{}", diagnostic);
+        } else if diagnostic.severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
+            warn!("{}", diagnostic);
+        } else if diagnostic.severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
+            debug!("{}", diagnostic);
+        } else {
+            trace!("{}", diagnostic);
+        }
+
+        let mut frame = self.frame.lock().unwrap();
+        frame.errors += diagnostic.is_error() as usize;
+        frame.messages.push(diagnostic);
+    }
+
+    // Returns the messages recorded since the previous call, or fails on errors in strict mode
+    pub fn end_frame(&self) -> Result<Vec<Diagnostic>> {
+        let frame = std::mem::take(&mut *self.frame.lock().unwrap());
+
+        if self.strict && frame.errors > 0 {
+            let first = frame.messages.iter().find(|d| d.is_error()).map(|d| d.to_string()).unwrap_or_default();
+            return Err(anyhow!(ValidationError { count: frame.errors, first }));
+        }
+
+        Ok(frame.messages)
+    }
+}
+
+unsafe fn string(name: *const c_char) -> Option<String> {
+    (!name.is_null()).then(|| CStr::from_ptr(name).to_string_lossy().into_owned())
+}
+
+unsafe fn array<'a, T>(items: *const T, count: u32) -> &'a [T] {
+    if items.is_null() { &[] } else { slice::from_raw_parts(items, count as usize) }
+}
+
+// `user_data` points to the `Diagnostics` of the app, which outlives the messenger
+pub extern "system" fn debug_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    type_: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void
+) -> vk::Bool32 {
+    let data = unsafe { *data };
+
+    let diagnostic = unsafe {
+        let labels = array(data.queue_labels, data.queue_label_count)
+            .iter()
+            .chain(array(data.cmd_buf_labels, data.cmd_buf_label_count))
+            .filter_map(|l| string(l.label_name))
+            .collect();
+
+        let objects = array(data.objects, data.object_count)
+            .iter()
+            .map(|o| DiagnosticObject { object_type: o.object_type, handle: o.object_handle, name: string(o.object_name) })
+            .collect();
+
+        Diagnostic {
+            severity,
+            message_type: type_,
+            message_id_name: string(data.message_id_name),
+            message_id_number: data.message_id_number,
+            message: string(data.message).unwrap_or_default(),
+            objects,
+            labels
+        }
+    };
+
+    match unsafe { (user_data as *const Diagnostics).as_ref() } {
+        Some(diagnostics) => diagnostics.record(diagnostic),
+        None => Diagnostics::default().record(diagnostic)
+    }
+
+    vk::FALSE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(severity: vk::DebugUtilsMessageSeverityFlagsEXT, name: &str, number: i32) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message_type: vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            message_id_name: Some(name.into()),
+            message_id_number: number,
+            message: format!("{} happened", name),
+            objects: vec![],
+            labels: vec![]
+        }
+    }
+
+    fn error(name: &str, number: i32) -> Diagnostic {
+        diagnostic(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR, name, number)
+    }
+
+    #[test]
+    fn allowlist_matches_name_or_hex_id() {
+        let diagnostics = Diagnostics::new(true, ["VUID-by-name".to_string(), "0x0000beef".to_string(), "0xfffffffe".to_string()]);
+
+        diagnostics.record(error("VUID-by-name", 1));
+        diagnostics.record(error("VUID-by-id", 0xbeef));
+        // Negative IDs are printed as their 32-bit two's complement
+        diagnostics.record(error("VUID-negative-id", -2));
+        assert!(diagnostics.end_frame().unwrap().is_empty());
+
+        // Neither the decimal form nor a prefix of the name match
+        let diagnostics = Diagnostics::new(false, ["48879".to_string(), "VUID-by".to_string()]);
+        diagnostics.record(error("VUID-by-name", 0xbeef));
+        assert_eq!(diagnostics.end_frame().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn strict_mode_fails_the_frame_on_errors() {
+        let diagnostics = Diagnostics::new(true, []);
+        diagnostics.record(diagnostic(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING, "VUID-warning", 1));
+        diagnostics.record(error("VUID-first", 2));
+        diagnostics.record(error("VUID-second", 3));
+
+        let error = diagnostics.end_frame().unwrap_err();
+        let validation = error.downcast_ref::<ValidationError>().unwrap();
+        assert_eq!(validation.count, 2);
+        assert!(validation.first.starts_with("[VUID-first 0x00000002]"), "{}", validation.first);
+    }
+
+    #[test]
+    fn warnings_and_lenient_errors_pass_the_frame() {
+        let strict = Diagnostics::new(true, []);
+        strict.record(diagnostic(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING, "VUID-warning", 1));
+        assert_eq!(strict.end_frame().unwrap().len(), 1);
+
+        let lenient = Diagnostics::new(false, []);
+        lenient.record(error("VUID-error", 1));
+        let messages = lenient.end_frame().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_error());
+    }
+
+    #[test]
+    fn frames_start_empty() {
+        let diagnostics = Diagnostics::new(true, []);
+        diagnostics.record(error("VUID-error", 1));
+        assert!(diagnostics.end_frame().is_err());
+
+        // The failed frame's errors don't carry over
+        assert!(diagnostics.end_frame().unwrap().is_empty());
+
+        diagnostics.record(diagnostic(vk::DebugUtilsMessageSeverityFlagsEXT::INFO, "VUID-info", 1));
+        assert_eq!(diagnostics.end_frame().unwrap().len(), 1);
+        assert!(diagnostics.end_frame().unwrap().is_empty());
+    }
+
+    #[test]
+    fn callback_copies_objects_and_labels() {
+        let name = b"VUID-callback\0";
+        let message = b"Something went wrong\0";
+        let object_name = b"depth image\0";
+        let queue_label = b"frame\0";
+        let cmd_label = b"draw scene\0";
+
+        let objects = [vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(vk::ObjectType::IMAGE)
+            .object_handle(0x1234)
+            .object_name(object_name)
+            .build()];
+        let queue_labels = [vk::DebugUtilsLabelEXT::builder().label_name(queue_label).build()];
+        let cmd_labels = [vk::DebugUtilsLabelEXT::builder().label_name(cmd_label).build()];
+
+        let data = vk::DebugUtilsMessengerCallbackDataEXT::builder()
+            .message_id_name(name)
+            .message_id_number(7)
+            .message(message)
+            .objects(&objects)
+            .queue_labels(&queue_labels)
+            .cmd_buf_labels(&cmd_labels)
+            .build();
+
+        let diagnostics = Diagnostics::new(false, []);
+        debug_callback(
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            &data,
+            &diagnostics as *const Diagnostics as *mut c_void
+        );
+
+        let messages = diagnostics.end_frame().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message_id_name.as_deref(), Some("VUID-callback"));
+        assert_eq!(messages[0].message, "Something went wrong");
+        assert_eq!(messages[0].labels, ["frame", "draw scene"]);
+        assert_eq!(messages[0].objects[0].handle, 0x1234);
+        assert_eq!(messages[0].objects[0].name.as_deref(), Some("depth image"));
+    }
+}
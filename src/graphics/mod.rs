@@ -1,2 +1,9 @@
+pub mod allocator;
+pub mod device_report;
+pub mod diagnostics;
+pub mod pipeline_cache;
 pub mod queue_family_indices;
-pub mod swapchain_support;
\ No newline at end of file
+pub mod shader_compiler;
+pub mod shader_reflection;
+pub mod shader_watcher;
+pub mod swapchain_support;
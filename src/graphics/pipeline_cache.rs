@@ -0,0 +1,180 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use thiserror::Error;
+use vulkanalia::prelude::v1_0::*;
+
+// Size of `VkPipelineCacheHeaderVersionOne`: header size, header version, vendor ID, device ID and UUID
+const HEADER_SIZE: usize = 4 * 4 + vk::UUID_SIZE;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PipelineCacheError {
+    #[error("{0} bytes is too short for a pipeline cache header.")]
+    Truncated(usize),
+    #[error("Unsupported header version {0} with a header of {1} bytes.")]
+    UnsupportedHeader(u32, u32),
+    #[error("Written for vendor {0:#06x} device {1:#06x}, but this is vendor {2:#06x} device {3:#06x}.")]
+    DeviceMismatch(u32, u32, u32, u32),
+    #[error("Written by another driver, the pipeline cache UUID differs.")]
+    UuidMismatch
+}
+
+// Only the header is checked here, a driver must reject a body it can't use on its own
+pub fn validate_header(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> Result<(), PipelineCacheError> {
+    if data.len() < HEADER_SIZE {
+        return Err(PipelineCacheError::Truncated(data.len()));
+    }
+
+    // Written in the byte order of the host
+    let field = |i: usize| u32::from_ne_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]);
+    let (header_size, header_version, vendor_id, device_id) = (field(0), field(1), field(2), field(3));
+
+    let version_one = vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32;
+    if header_version != version_one || (header_size as usize) < HEADER_SIZE || header_size as usize > data.len() {
+        return Err(PipelineCacheError::UnsupportedHeader(header_version, header_size));
+    }
+
+    if vendor_id != properties.vendor_id || device_id != properties.device_id {
+        return Err(PipelineCacheError::DeviceMismatch(vendor_id, device_id, properties.vendor_id, properties.device_id));
+    }
+
+    if data[16..HEADER_SIZE] != properties.pipeline_cache_uuid[..] {
+        return Err(PipelineCacheError::UuidMismatch);
+    }
+
+    Ok(())
+}
+
+// Seeded from `path` when it holds a cache for this device and driver, empty otherwise
+pub unsafe fn create_pipeline_cache(
+    instance: &Instance,
+    device: &Device,
+    physical_device: vk::PhysicalDevice,
+    path: Option<&Path>
+) -> Result<vk::PipelineCache> {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let empty_cache = || device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::builder(), None);
+
+    let Some(path) = path else { return Ok(empty_cache()?) };
+    let initial_data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(empty_cache()?),
+        Err(e) => {
+            warn!("Ignoring pipeline cache `{}`: {}", path.display(), e);
+            return Ok(empty_cache()?);
+        }
+    };
+
+    if let Err(e) = validate_header(&initial_data, &properties) {
+        warn!("Ignoring pipeline cache `{}`: {}", path.display(), e);
+        return Ok(empty_cache()?);
+    }
+
+    let info = vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data);
+    match device.create_pipeline_cache(&info, None) {
+        Ok(cache) => {
+            info!("Loaded {} byte pipeline cache from `{}`.", initial_data.len(), path.display());
+            Ok(cache)
+        },
+        // The driver may still refuse a body behind a matching header
+        Err(e) => {
+            warn!("Ignoring pipeline cache `{}`: {}", path.display(), e);
+            Ok(empty_cache()?)
+        }
+    }
+}
+
+// Replaces the file in one rename, so an interrupted save or a concurrent run never leaves half a cache
+pub unsafe fn save_pipeline_cache(device: &Device, cache: vk::PipelineCache, path: &Path) -> Result<()> {
+    let data = device.get_pipeline_cache_data(cache)?;
+
+    let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temporary, &data)?;
+    if let Err(e) = fs::rename(&temporary, path) {
+        let _ = fs::remove_file(&temporary);
+        return Err(anyhow!(e));
+    }
+
+    info!("Saved {} byte pipeline cache to `{}`.", data.len(), path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; vk::UUID_SIZE] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    fn properties() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            vendor_id: 0x10005,
+            device_id: 0x0000,
+            pipeline_cache_uuid: vk::ByteArray(UUID),
+            ..Default::default()
+        }
+    }
+
+    // A header followed by a few bytes of body
+    fn cache(header_size: u32, header_version: u32, vendor_id: u32, device_id: u32, uuid: [u8; vk::UUID_SIZE]) -> Vec<u8> {
+        let mut data = [header_size, header_version, vendor_id, device_id]
+            .iter()
+            .flat_map(|f| f.to_ne_bytes())
+            .collect::<Vec<_>>();
+        data.extend_from_slice(&uuid);
+        data.extend_from_slice(&[0xAB; 8]);
+        data
+    }
+
+    #[test]
+    fn valid_header() {
+        assert_eq!(validate_header(&cache(32, 1, 0x10005, 0, UUID), &properties()), Ok(()));
+
+        // Longer headers of version one are allowed as long as they fit
+        assert_eq!(validate_header(&cache(40, 1, 0x10005, 0, UUID), &properties()), Ok(()));
+    }
+
+    #[test]
+    fn truncated() {
+        let data = cache(32, 1, 0x10005, 0, UUID);
+        assert_eq!(validate_header(&data[..31], &properties()), Err(PipelineCacheError::Truncated(31)));
+        assert_eq!(validate_header(&[], &properties()), Err(PipelineCacheError::Truncated(0)));
+    }
+
+    #[test]
+    fn unsupported_header() {
+        assert_eq!(
+            validate_header(&cache(32, 2, 0x10005, 0, UUID), &properties()),
+            Err(PipelineCacheError::UnsupportedHeader(2, 32))
+        );
+        assert_eq!(
+            validate_header(&cache(16, 1, 0x10005, 0, UUID), &properties()),
+            Err(PipelineCacheError::UnsupportedHeader(1, 16))
+        );
+        assert_eq!(
+            validate_header(&cache(41, 1, 0x10005, 0, UUID), &properties()),
+            Err(PipelineCacheError::UnsupportedHeader(1, 41))
+        );
+    }
+
+    #[test]
+    fn device_mismatch() {
+        assert_eq!(
+            validate_header(&cache(32, 1, 0x10de, 0x2204, UUID), &properties()),
+            Err(PipelineCacheError::DeviceMismatch(0x10de, 0x2204, 0x10005, 0))
+        );
+        assert_eq!(
+            validate_header(&cache(32, 1, 0x10005, 1, UUID), &properties()),
+            Err(PipelineCacheError::DeviceMismatch(0x10005, 1, 0x10005, 0))
+        );
+    }
+
+    #[test]
+    fn uuid_mismatch() {
+        let mut uuid = UUID;
+        uuid[15] ^= 1;
+        assert_eq!(validate_header(&cache(32, 1, 0x10005, 0, uuid), &properties()), Err(PipelineCacheError::UuidMismatch));
+    }
+}
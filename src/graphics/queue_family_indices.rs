@@ -18,10 +18,21 @@ impl QueueFamilyIndices {
         physical_device: vk::PhysicalDevice
     ) -> Result<Self> {
         let properties = instance.get_physical_device_queue_family_properties(physical_device);
-        let graphics = properties.iter().position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS)).map(|i| i as u32);
+
+        // The graphics queue also runs the environment precomputation dispatches
+        let graphics = properties
+            .iter()
+            .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE))
+            .map(|i| i as u32);
 
         let mut present = None;
         for (index, properties) in properties.iter().enumerate() {
+            // Headless rendering has no surface, the graphics queue stands in for presentation
+            if data.headless {
+                present = graphics;
+                break;
+            }
+
             if instance.get_physical_device_surface_support_khr(physical_device, index as u32, data.surface)? {
                 present = Some(index as u32);
                 break;
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glslang::error::GlslangError;
+use glslang::include::{IncludeHandler, IncludeResult, IncludeType};
+use glslang::{Compiler, CompilerOptions, ShaderInput, ShaderSource, ShaderStage};
+use log::*;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Failed to compile shader `{filename}`:\n  {}", .messages.join("\n  "))]
+pub struct ShaderError {
+    pub filename: String,
+    // One `file:line: message` entry per problem
+    pub messages: Vec<String>
+}
+
+impl ShaderError {
+    fn new(filename: &Path, message: impl Into<String>) -> Self {
+        ShaderError { filename: filename.display().to_string(), messages: vec![message.into()] }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompiledShader {
+    pub code: Vec<u32>,
+    // The compiled file followed by everything it included, for hot reloading
+    pub sources: Vec<PathBuf>
+}
+
+// Resolves `#include "file"` against the directory of the including file and `#include <file>`
+// against the directory of the shader being compiled
+struct Includer {
+    root: PathBuf,
+    included: Vec<PathBuf>
+}
+
+impl IncludeHandler for Includer {
+    fn include(
+        &mut self,
+        ty: IncludeType,
+        header_name: &str,
+        includer_name: &str,
+        include_depth: usize
+    ) -> Option<IncludeResult> {
+        let directory = match ty {
+            // glslang passes an empty includer name for the top-level file
+            IncludeType::Local if !includer_name.is_empty() => Path::new(includer_name).parent()?.to_path_buf(),
+            _ => self.root.clone()
+        };
+
+        let path = directory.join(header_name);
+        let data = fs::read_to_string(&path).ok()?;
+        self.included.push(path.clone());
+
+        Some(IncludeResult { name: path.display().to_string(), data })
+    }
+}
+
+// Compiles a GLSL file to SPIR-V for Vulkan 1.0, the stage is taken from the extension like glslc does.
+// Includes need `#extension GL_GOOGLE_include_directive : require` in the including file.
+pub fn compile_shader(path: impl AsRef<Path>, defines: &[(&str, Option<&str>)]) -> Result<CompiledShader, ShaderError> {
+    let path = path.as_ref();
+
+    let stage = match path.extension().and_then(|e| e.to_str()) {
+        Some("vert") => ShaderStage::Vertex,
+        Some("frag") => ShaderStage::Fragment,
+        Some("comp") => ShaderStage::Compute,
+        _ => return Err(ShaderError::new(path, "Unknown shader stage, expected a .vert, .frag or .comp file."))
+    };
+
+    let source = fs::read_to_string(path).map_err(|e| ShaderError::new(path, e.to_string()))?;
+    let source = ShaderSource::from(source);
+
+    let compiler = Compiler::acquire().ok_or_else(|| ShaderError::new(path, "Failed to initialize glslang."))?;
+    let mut includer = Includer { root: path.parent().unwrap_or(Path::new("")).to_path_buf(), included: vec![] };
+
+    let options = CompilerOptions::default();
+    let input = ShaderInput::new(&source, stage, &options, Some(defines), Some(&mut includer))
+        .map_err(|e| ShaderError::new(path, e.to_string()))?;
+
+    let result = compiler.create_shader(input).and_then(|shader| {
+        let log = shader.get_log();
+        if !log.trim().is_empty() {
+            warn!("{}", log_messages(path, &log).join("\n"));
+        }
+
+        shader.compile()
+    });
+
+    let code = result.map_err(|e| match &e {
+        GlslangError::PreprocessError(log) | GlslangError::ParseError(log) | GlslangError::LinkError(log) => {
+            ShaderError { filename: path.display().to_string(), messages: log_messages(path, &log.log) }
+        },
+        _ => ShaderError::new(path, e.to_string())
+    })?;
+
+    let mut sources = vec![path.to_path_buf()];
+    sources.extend(includer.included);
+
+    Ok(CompiledShader { code, sources })
+}
+
+// Turns `ERROR: 0:12: message` into `shaders/file.frag:12: error: message`. Source 0 is the compiled
+// file itself, included files are reported by the name `Includer` gave them.
+fn log_messages(path: &Path, log: &str) -> Vec<String> {
+    log.lines()
+        .filter_map(|line| {
+            let (severity, message) = line.split_once(": ")?;
+            if message.contains("compilation terminated") || message.contains("compilation errors.") {
+                return None;
+            }
+
+            let message = match message.strip_prefix("0:") {
+                Some(rest) => format!("{}:{}", path.display(), rest),
+                None => message.to_string()
+            };
+
+            let severity = severity.to_lowercase();
+            match message.split_once(": ") {
+                Some((location, text)) if location.contains(':') => Some(format!("{}: {}: {}", location, severity, text.trim())),
+                _ => Some(format!("{}: {}", severity, message.trim()))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory per test, tests run in parallel
+    fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("shader_compiler_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+
+        for (file, source) in files {
+            let path = directory.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        directory
+    }
+
+    #[test]
+    fn log_messages_name_file_and_line() {
+        let log = "ERROR: 0:12: 'color' : undeclared identifier\n\
+                   WARNING: 0:3: 'precision' : ignored\n\
+                   ERROR: 0:12: '' : compilation terminated\n\
+                   ERROR: 2 compilation errors.  No code generated.\n";
+
+        assert_eq!(
+            log_messages(Path::new("shaders/x.frag"), log),
+            [
+                "shaders/x.frag:12: error: 'color' : undeclared identifier",
+                "shaders/x.frag:3: warning: 'precision' : ignored"
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_error_reports_file_and_line() {
+        let directory = scratch("compile_error", &[(
+            "broken.frag",
+            "#version 450\n\nlayout(location = 0) out vec4 outColor;\n\nvoid main() {\n    outColor = color;\n}\n"
+        )]);
+        let path = directory.join("broken.frag");
+
+        let error = compile_shader(&path, &[]).unwrap_err();
+        assert_eq!(error.filename, path.display().to_string());
+
+        let location = format!("{}:6: error: ", path.display());
+        assert!(error.messages.iter().any(|m| m.starts_with(&location) && m.contains("color")), "{:?}", error.messages);
+        assert!(!error.messages.iter().any(|m| m.contains("compilation terminated")), "{:?}", error.messages);
+    }
+
+    #[test]
+    fn unknown_stage_is_rejected() {
+        let error = compile_shader("shaders/cubemap.glsl", &[]).unwrap_err();
+        assert!(error.messages[0].contains("Unknown shader stage"), "{:?}", error.messages);
+    }
+
+    #[test]
+    fn local_includes_resolve_against_the_including_file() {
+        let directory = scratch("includes", &[
+            (
+                "main.frag",
+                "#version 450\n#extension GL_GOOGLE_include_directive : require\n#include \"lib/outer.glsl\"\n\n\
+                 layout(location = 0) out vec4 outColor;\n\nvoid main() {\n    outColor = inner();\n}\n"
+            ),
+            ("lib/outer.glsl", "#include \"inner.glsl\"\n"),
+            ("lib/inner.glsl", "vec4 inner() { return vec4(1.0); }\n")
+        ]);
+
+        let shader = compile_shader(directory.join("main.frag"), &[]).unwrap();
+        assert!(!shader.code.is_empty());
+
+        let file_names = shader.sources.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(file_names, ["main.frag", "outer.glsl", "inner.glsl"]);
+        assert!(shader.sources[2].ends_with("lib/inner.glsl"), "{:?}", shader.sources);
+    }
+
+    #[test]
+    fn defines_reach_the_shader() {
+        let directory = scratch("defines", &[(
+            "defines.frag",
+            "#version 450\n\nlayout(location = 0) out vec4 outColor;\n\nvoid main() {\n    outColor = vec4(BRIGHTNESS);\n}\n"
+        )]);
+        let path = directory.join("defines.frag");
+
+        assert!(compile_shader(&path, &[]).is_err());
+        assert!(compile_shader(&path, &[("BRIGHTNESS", Some("0.5"))]).is_ok());
+    }
+
+    // The graphics shaders need defines from the renderer and are compiled with them by the reflection tests
+    #[test]
+    fn environment_shaders_compile() {
+        for shader in ["equirect_to_cube", "irradiance", "prefilter", "brdf_lut"] {
+            let compiled = compile_shader(format!("shaders/{}.comp", shader), &[]);
+            assert!(compiled.is_ok(), "{}", compiled.unwrap_err());
+        }
+    }
+}
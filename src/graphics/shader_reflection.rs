@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use spirq::prelude::*;
+use spirq::ty::ScalarType;
+use vulkanalia::prelude::v1_0::*;
+
+use super::shader_compiler::CompiledShader;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorBindingInfo {
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags
+}
+
+// A vertex shader input as (scalar type, component count), `None` for types a vertex buffer can't feed
+type VertexInputType = Option<(ScalarType, u32)>;
+
+// Descriptor sets, push constants and vertex inputs used by the stages of one pipeline
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShaderReflection {
+    // Keyed by (set, binding)
+    pub bindings: BTreeMap<(u32, u32), DescriptorBindingInfo>,
+    // A single range covering the largest push constant block, 0 bytes without one
+    pub push_constant_size: u32,
+    pub push_constant_stages: vk::ShaderStageFlags,
+    // Keyed by location, only filled for vertex shaders
+    pub vertex_inputs: BTreeMap<u32, VertexInputType>
+}
+
+impl ShaderReflection {
+    // Merges the resources of every stage, a binding used by several stages must agree on its type
+    pub fn new(shaders: &[&CompiledShader]) -> Result<Self> {
+        let mut reflection = ShaderReflection::default();
+
+        for shader in shaders {
+            let filename = shader.sources[0].display();
+            let entry_points = ReflectConfig::new()
+                .spv(&shader.code[..])
+                // Declared but unused resources still belong in the layout
+                .ref_all_rscs(true)
+                .combine_img_samplers(true)
+                .reflect()
+                .map_err(|e| anyhow!("Failed to reflect `{}`: {}", filename, e))?;
+
+            for entry_point in entry_points {
+                let stage = match entry_point.exec_model {
+                    ExecutionModel::Vertex => vk::ShaderStageFlags::VERTEX,
+                    ExecutionModel::Fragment => vk::ShaderStageFlags::FRAGMENT,
+                    ExecutionModel::GLCompute => vk::ShaderStageFlags::COMPUTE,
+                    model => return Err(anyhow!("`{}` has an unsupported execution model {:?}.", filename, model))
+                };
+
+                for variable in &entry_point.vars {
+                    match variable {
+                        Variable::Descriptor { desc_bind, desc_ty, nbind, .. } => {
+                            let key = (desc_bind.set(), desc_bind.bind());
+                            let descriptor_type = descriptor_type(desc_ty)
+                                .ok_or_else(|| anyhow!("`{}` uses an unsupported descriptor {:?} at {:?}.", filename, desc_ty, key))?;
+
+                            let binding = reflection.bindings.entry(key).or_insert(DescriptorBindingInfo {
+                                descriptor_type,
+                                count: *nbind,
+                                stages: vk::ShaderStageFlags::empty()
+                            });
+
+                            if binding.descriptor_type != descriptor_type || binding.count != *nbind {
+                                return Err(anyhow!(
+                                    "`{}` declares set {} binding {} as {} {:?}, another stage as {} {:?}.",
+                                    filename, key.0, key.1, nbind, descriptor_type, binding.count, binding.descriptor_type
+                                ));
+                            }
+
+                            binding.stages |= stage;
+                        },
+                        Variable::PushConstant { ty, .. } => {
+                            let size = ty.nbyte().unwrap_or(0) as u32;
+                            reflection.push_constant_size = reflection.push_constant_size.max(size);
+                            reflection.push_constant_stages |= stage;
+                        },
+                        Variable::Input { location, ty, .. } if stage == vk::ShaderStageFlags::VERTEX => {
+                            let input = match ty {
+                                Type::Scalar(scalar) => Some((scalar.clone(), 1)),
+                                Type::Vector(vector) => Some((vector.scalar_ty.clone(), vector.nscalar)),
+                                _ => None
+                            };
+
+                            reflection.vertex_inputs.insert(location.loc(), input);
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        Ok(reflection)
+    }
+
+    pub fn set_count(&self) -> u32 {
+        self.bindings.keys().map(|(set, _)| set + 1).max().unwrap_or(0)
+    }
+
+    pub fn set_bindings(&self, set: u32) -> Vec<vk::DescriptorSetLayoutBinding> {
+        self.bindings
+            .range((set, 0)..=(set, u32::MAX))
+            .map(|((_, binding), info)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(*binding)
+                    .descriptor_type(info.descriptor_type)
+                    .descriptor_count(info.count)
+                    .stage_flags(info.stages)
+                    .build()
+            })
+            .collect()
+    }
+
+    // Layouts for sets 0 up to the highest one used, sets without bindings get an empty layout
+    pub unsafe fn create_set_layouts(&self, device: &Device) -> Result<Vec<vk::DescriptorSetLayout>> {
+        (0..self.set_count())
+            .map(|set| {
+                let bindings = self.set_bindings(set);
+                let info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+                Ok(device.create_descriptor_set_layout(&info, None)?)
+            })
+            .collect()
+    }
+
+    // Enough descriptors for `sets` sets of `set`
+    pub fn pool_sizes(&self, set: u32, sets: u32) -> Vec<vk::DescriptorPoolSize> {
+        let mut counts = BTreeMap::<i32, u32>::new();
+        for binding in self.set_bindings(set) {
+            *counts.entry(binding.descriptor_type.as_raw()).or_default() += binding.descriptor_count * sets;
+        }
+
+        counts
+            .into_iter()
+            .map(|(type_, count)| {
+                vk::DescriptorPoolSize::builder()
+                    .type_(vk::DescriptorType::from_raw(type_))
+                    .descriptor_count(count)
+                    .build()
+            })
+            .collect()
+    }
+
+    pub fn push_constant_ranges(&self) -> Vec<vk::PushConstantRange> {
+        if self.push_constant_size == 0 {
+            return vec![];
+        }
+
+        vec![vk::PushConstantRange::builder()
+            .stage_flags(self.push_constant_stages)
+            .offset(0)
+            .size(self.push_constant_size)
+            .build()]
+    }
+
+    // Every vertex shader input needs an attribute of the same shape, extra attributes are only reported
+    pub fn check_vertex_inputs(&self, attributes: &[vk::VertexInputAttributeDescription]) -> Result<()> {
+        let mut problems = vec![];
+
+        for (location, input) in &self.vertex_inputs {
+            let attribute = attributes.iter().find(|a| a.location == *location);
+            match (input, attribute) {
+                (_, None) => problems.push(format!("location {} has no vertex attribute", location)),
+                (None, Some(_)) => problems.push(format!("location {} is not a scalar or vector input", location)),
+                (Some(input), Some(attribute)) if format_input_type(attribute.format).as_ref() != Some(input) => {
+                    problems.push(format!("location {} is {:?} in the shader but {:?} in the vertex", location, input, attribute.format));
+                },
+                _ => {}
+            }
+        }
+
+        for attribute in attributes.iter().filter(|a| !self.vertex_inputs.contains_key(&a.location)) {
+            warn!("Vertex attribute at location {} is not used by the vertex shader.", attribute.location);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Vertex attributes don't match the vertex shader inputs:\n  {}", problems.join("\n  ")))
+        }
+    }
+}
+
+fn descriptor_type(descriptor_type: &DescriptorType) -> Option<vk::DescriptorType> {
+    Some(match descriptor_type {
+        DescriptorType::Sampler() => vk::DescriptorType::SAMPLER,
+        DescriptorType::CombinedImageSampler() => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        DescriptorType::SampledImage() => vk::DescriptorType::SAMPLED_IMAGE,
+        DescriptorType::StorageImage(_) => vk::DescriptorType::STORAGE_IMAGE,
+        DescriptorType::UniformTexelBuffer() => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+        DescriptorType::StorageTexelBuffer(_) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+        DescriptorType::UniformBuffer() => vk::DescriptorType::UNIFORM_BUFFER,
+        DescriptorType::StorageBuffer(_) => vk::DescriptorType::STORAGE_BUFFER,
+        DescriptorType::InputAttachment(_) => vk::DescriptorType::INPUT_ATTACHMENT,
+        _ => return None
+    })
+}
+
+// Shape of the vertex formats in use, as the shader sees them
+fn format_input_type(format: vk::Format) -> VertexInputType {
+    match format {
+        vk::Format::R32_SFLOAT => Some((ScalarType::f32(), 1)),
+        vk::Format::R32G32_SFLOAT => Some((ScalarType::f32(), 2)),
+        vk::Format::R32G32B32_SFLOAT => Some((ScalarType::f32(), 3)),
+        vk::Format::R32G32B32A32_SFLOAT => Some((ScalarType::f32(), 4)),
+        vk::Format::R32_UINT => Some((ScalarType::u32(), 1)),
+        vk::Format::R32_SINT => Some((ScalarType::i32(), 1)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::graphics::shader_compiler::compile_shader;
+    use crate::objects::uniform_buffer_object::MAX_LIGHTS;
+    use crate::objects::vertex::Vertex;
+
+    fn reflect_pipeline_shaders() -> ShaderReflection {
+        let max_lights = MAX_LIGHTS.to_string();
+        let defines = &[("MAX_LIGHTS", Some(max_lights.as_str()))];
+
+        let vert = compile_shader("shaders/shader.vert", defines).unwrap();
+        let frag = compile_shader("shaders/shader.frag", defines).unwrap();
+        ShaderReflection::new(&[&vert, &frag]).unwrap()
+    }
+
+    fn binding(descriptor_type: vk::DescriptorType, stages: vk::ShaderStageFlags) -> DescriptorBindingInfo {
+        DescriptorBindingInfo { descriptor_type, count: 1, stages }
+    }
+
+    #[test]
+    fn pipeline_shader_layout() {
+        let reflection = reflect_pipeline_shaders();
+
+        let uniform = vk::DescriptorType::UNIFORM_BUFFER;
+        let sampler = vk::DescriptorType::COMBINED_IMAGE_SAMPLER;
+        let fragment = vk::ShaderStageFlags::FRAGMENT;
+
+        let mut expected = BTreeMap::new();
+        expected.insert((0, 0), binding(uniform, vk::ShaderStageFlags::VERTEX | fragment));
+        expected.insert((1, 0), binding(uniform, fragment));
+        for map in 1..=6 {
+            expected.insert((1, map), binding(sampler, fragment));
+        }
+        for map in 0..=2 {
+            expected.insert((2, map), binding(sampler, fragment));
+        }
+
+        assert_eq!(reflection.bindings, expected);
+        assert_eq!(reflection.set_count(), 3);
+
+        // The model matrix
+        assert_eq!(reflection.push_constant_size, 64);
+        assert_eq!(reflection.push_constant_stages, vk::ShaderStageFlags::VERTEX);
+
+        let ranges = reflection.push_constant_ranges();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].offset, ranges[0].size, ranges[0].stage_flags), (0, 64, vk::ShaderStageFlags::VERTEX));
+    }
+
+    #[test]
+    fn pool_sizes_scale_with_the_set_count() {
+        let reflection = reflect_pipeline_shaders();
+
+        let sizes = reflection.pool_sizes(1, 3)
+            .iter()
+            .map(|s| (s.type_, s.descriptor_count))
+            .collect::<Vec<_>>();
+
+        assert_eq!(sizes, [
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 18),
+            (vk::DescriptorType::UNIFORM_BUFFER, 3)
+        ]);
+    }
+
+    #[test]
+    fn vertex_attributes_match_the_shader() {
+        let reflection = reflect_pipeline_shaders();
+        assert!(reflection.check_vertex_inputs(&Vertex::attribute_descriptions()).is_ok());
+    }
+
+    #[test]
+    fn missing_vertex_attribute_is_reported() {
+        let reflection = reflect_pipeline_shaders();
+        let attributes = Vertex::attribute_descriptions()
+            .into_iter()
+            .filter(|a| a.location != 3)
+            .collect::<Vec<_>>();
+
+        let error = reflection.check_vertex_inputs(&attributes).unwrap_err().to_string();
+        assert!(error.contains("location 3 has no vertex attribute"), "{}", error);
+    }
+
+    #[test]
+    fn retyped_vertex_attribute_is_reported() {
+        let reflection = reflect_pipeline_shaders();
+        let mut attributes = Vertex::attribute_descriptions();
+        attributes[2].format = vk::Format::R32G32B32_SFLOAT;
+
+        let error = reflection.check_vertex_inputs(&attributes).unwrap_err().to_string();
+        assert!(error.contains("location 2 is"), "{}", error);
+        assert!(!error.contains("location 0"), "{}", error);
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// Time between two scans of the directory
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Reports files of a directory whose modification time changed, by polling between frames
+#[derive(Clone, Debug)]
+pub struct ShaderWatcher {
+    directory: PathBuf,
+    last_poll: Instant,
+    modified: HashMap<PathBuf, SystemTime>
+}
+
+impl ShaderWatcher {
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        let directory = directory.as_ref().to_path_buf();
+        let modified = scan(&directory);
+
+        ShaderWatcher { directory, last_poll: Instant::now(), modified }
+    }
+
+    // Files written or created since the previous call, empty until the poll interval has passed
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return vec![];
+        }
+
+        self.last_poll = Instant::now();
+
+        let modified = scan(&self.directory);
+        let changed = modified
+            .iter()
+            .filter(|(path, time)| self.modified.get(*path) != Some(*time))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        self.modified = modified;
+        changed
+    }
+}
+
+// Files that vanish in between, e.g. while an editor replaces them, show up again on the next scan
+fn scan(directory: &Path) -> HashMap<PathBuf, SystemTime> {
+    let Ok(entries) = fs::read_dir(directory) else { return HashMap::new() };
+
+    entries
+        .flatten()
+        .filter_map(|e| Some((e.path(), e.metadata().ok()?.modified().ok()?)))
+        .collect()
+}
@@ -1,11 +1,12 @@
 #![allow(dead_code, unused_variables, clippy::too_many_arguments, clippy::unnecessary_wraps)]
 
 use std::collections::HashSet;
-use std::ffi::CStr;
 
-use std::mem::size_of;
+use std::mem::{size_of, size_of_val};
 use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
 use std::ptr::copy_nonoverlapping as memcpy;
+use std::sync::Arc;
 use std::time::Instant;
 
 use log::*;
@@ -13,9 +14,9 @@ use anyhow::{anyhow, Result};
 use thiserror::Error;
 
 use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::{Window, WindowBuilder};
+use winit::window::{Fullscreen, Window, WindowBuilder};
 
 use vulkanalia::loader::{LibloadingLoader, LIBRARY};
 use vulkanalia::window as vk_window;
@@ -27,14 +28,25 @@ use vulkanalia::vk::KhrSwapchainExtension;
 
 use nalgebra_glm as glm;
 
+use clap::Parser;
+
 mod objects;
-use crate::objects::mesh::*;
-use crate::objects::texture::*;
+use crate::objects::camera::*;
+use crate::objects::environment::*;
+use crate::objects::scene::*;
+use crate::objects::scene_file::*;
 use crate::objects::vertex::*;
 use crate::objects::uniform_buffer_object::*;
 
 mod graphics;
+use crate::graphics::allocator::*;
+use crate::graphics::device_report::*;
+use crate::graphics::diagnostics::*;
+use crate::graphics::pipeline_cache::*;
 use crate::graphics::queue_family_indices::*;
+use crate::graphics::shader_compiler::*;
+use crate::graphics::shader_reflection::*;
+use crate::graphics::shader_watcher::*;
 use crate::graphics::swapchain_support::*;
 
 mod shared_memory;
@@ -42,21 +54,62 @@ use shared_memory::*;
 
 mod shared_commands;
 
-const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
+mod cli;
+use cli::*;
+
 const VALIDATION_LAYER: vk::ExtensionName = vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
 const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
-const MAX_FRAMES_IN_FLIGHT: usize = 2;
+const DEFAULT_SCENE: &str = "resources/scenes/jvctv.ron";
+const WINDOW_TITLE: &str = "Vulkan Tutorial";
+const SHADER_DIRECTORY: &str = "shaders";
+
+// Descriptor sets of the main pipeline, as numbered in the shaders
+const FRAME_SET: u32 = 0;
+const MATERIAL_SET: u32 = 1;
+const ENVIRONMENT_SET: u32 = 2;
+
+const MSAA_SAMPLES: &[vk::SampleCountFlags] = &[
+    vk::SampleCountFlags::_1,
+    vk::SampleCountFlags::_2,
+    vk::SampleCountFlags::_4,
+    vk::SampleCountFlags::_8,
+    vk::SampleCountFlags::_16,
+    vk::SampleCountFlags::_32,
+    vk::SampleCountFlags::_64
+];
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
+    // Prints the usage and exits on bad input
+    let args = Args::parse();
+
+    if args.list_devices {
+        return unsafe { list_devices(&args) };
+    }
+
+    // A bare model gets the default environment, camera and light
+    let scene = match &args.model {
+        Some(model) => {
+            let scene = SceneFile::from_model(model, args.texture.as_deref());
+            scene.validate(model)?;
+            scene
+        },
+        None => SceneFile::load(args.scene.as_deref().unwrap_or(DEFAULT_SCENE))?
+    };
+
+    if let Some(output) = &args.headless {
+        return unsafe { render_headless(&args, &scene, output) };
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
-        .with_title("Vulkan Tutorial")
-        .with_inner_size(LogicalSize::new(1024, 768))
+        .with_title(WINDOW_TITLE)
+        .with_inner_size(LogicalSize::new(args.resolution.width, args.resolution.height))
+        .with_fullscreen(args.fullscreen.then_some(Fullscreen::Borderless(None)))
         .build(&event_loop)?;
 
-    let mut app = unsafe { App::create(&window)? };
+    let mut app = unsafe { App::create(&window, &args, &scene)? };
     let mut destroying = false;
     let mut minimized = false;
 
@@ -83,6 +136,9 @@ fn main() -> Result<()> {
                 unsafe { app.destroy() };
             }
 
+            Event::WindowEvent { event, .. } => unsafe { app.handle_window_event(&event) }.unwrap(),
+            Event::DeviceEvent { event, .. } => app.camera.handle_device_event(&event),
+
             _ => {}
         }
     });
@@ -96,61 +152,111 @@ struct App {
     device: Device,
     frame: usize,
     resized: bool,
-    start: Instant
+    camera: Camera,
+    last_frame: Instant,
+    // Only with `--watch-shaders`, the environment compute shaders run once at startup and are not watched
+    shader_watcher: Option<ShaderWatcher>,
+    shader_error: bool,
+    // Where the pipeline cache is saved on exit, `None` with `--no-pipeline-cache`
+    pipeline_cache_path: Option<PathBuf>
 }
 
 impl App {
-    unsafe fn create(window: &Window) -> Result<Self> {
+    unsafe fn create(window: &Window, args: &Args, scene: &SceneFile) -> Result<Self> {
+        App::create_with_target(Some(window), args, scene)
+    }
+
+    // Renders into an offscreen image of `args.resolution` instead of a window surface
+    unsafe fn create_headless(args: &Args, scene: &SceneFile) -> Result<Self> {
+        App::create_with_target(None, args, scene)
+    }
+
+    unsafe fn create_with_target(window: Option<&Window>, args: &Args, scene: &SceneFile) -> Result<Self> {
         let loader = LibloadingLoader::new(LIBRARY)?;
         let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
-        let mut data = AppData::default();
+        let mut data = AppData {
+            headless: window.is_none(),
+            validation: args.validation_enabled(),
+            gpu: args.gpu.clone(),
+            preferred_present_mode: args.present_mode.map(vk::PresentModeKHR::from),
+            vsync: !args.no_vsync,
+            preferred_image_count: args.image_count,
+            frames_in_flight: args.frames_in_flight as usize,
+            preferred_msaa_samples: args.msaa,
+            min_sample_shading: args.min_sample_shading,
+            diagnostics: Arc::new(Diagnostics::new(args.strict_validation, args.allow_message.clone())),
+            ..Default::default()
+        };
 
         let instance = create_instance(window, &entry, &mut data)?;
-        data.surface = vk_window::create_surface(&instance, window)?;
+        if let Some(window) = window {
+            data.surface = vk_window::create_surface(&instance, window)?;
+        }
+
         pick_physical_device(&instance, &mut data)?;
 
         let device = create_logical_device(&instance, &mut data)?;
-        create_swapchain(window, &instance, &device, &mut data)?;
-        create_swapchain_image_views(&device, &mut data)?;
+        data.allocator = Arc::new(Allocator::new(&instance, data.physical_device));
+        data.pipeline_cache = create_pipeline_cache(&instance, &device, data.physical_device, args.pipeline_cache_path())?;
+
+        if let Some(window) = window {
+            create_swapchain(window, &instance, &device, &mut data)?;
+            create_swapchain_image_views(&device, &mut data)?;
+        } else {
+            create_offscreen_target(&instance, &device, &mut data, args.resolution)?;
+        }
+
         create_render_pass(&instance, &device, &mut data)?;
-        create_descriptor_set_layout(&device, &mut data)?;
-        create_pipeline(&device, &mut data)?;
+        (data.vert_shader, data.frag_shader, data.reflection) = load_pipeline_shaders()?;
+        create_descriptor_set_layouts(&device, &mut data)?;
         create_command_pool(&instance, &device, &mut data)?;
+
+        data.scene = Scene::load(&instance, &device, &data, scene)?;
+
+        data.environment = Environment::create(&instance, &device, &data, &scene.environment)?;
+
+        create_pipeline(&device, &mut data)?;
         create_color_objects(&instance, &device, &mut data)?;
         create_depth_objects(&instance, &device, &mut data)?;
         create_framebuffers(&device, &mut data)?;
 
-        data.textures = vec![
-            // Texture::from_filepath(String::from("resources/jvctv/textures/JVCTV_albedo_small.png"), &instance, &device, &data)?,
-            Texture2D::load_from_file(
-                &instance, &device, &data,
-                "resources/jvctv/textures/JVCTV_albedo_small.png",
-                vk::Format::R8G8B8A8_SRGB,
-                None, None
-            )?
-            // Texture::from_filepath(String::from("resources/jvctv/textures/JVCTV_roughness.png"), &instance, &device, &data)?,
-            // Texture::from_filepath(String::from("resources/jvctv/textures/JVCTV_metallic.png"), &instance, &device, &data)?
-        ];
-
-        create_texture_sampler(&device, &mut data)?;
-
-        data.mesh = Mesh::from_filepath(
-            String::from("resources/jvctv/jvctv.obj"),
-            &instance,
-            &device,
-            &data
-        )?;
-
         create_uniform_buffers(&instance, &device, &mut data)?;
         create_descriptor_pool(&device, &mut data)?;
         create_descriptor_sets(&device, &mut data)?;
         create_command_buffers(&device, &mut data)?;
         create_sync_objects(&device, &mut data)?;
 
-        Ok(Self { entry, instance, data, device, frame: 0, resized: false, start: Instant::now() })
+        let [x, y, z] = scene.camera.position;
+        let [tx, ty, tz] = scene.camera.target;
+        let mut camera = Camera::look_at(glm::vec3(x, y, z), glm::vec3(tx, ty, tz));
+        camera.fov = scene.camera.fov;
+        camera.near = scene.camera.near;
+        camera.far = scene.camera.far;
+
+        if args.memory_stats {
+            println!("{}", data.allocator.stats());
+        }
+
+        let shader_watcher = args.watch_shaders.then(|| ShaderWatcher::new(SHADER_DIRECTORY));
+
+        Ok(Self {
+            entry,
+            instance,
+            data,
+            device,
+            frame: 0,
+            resized: false,
+            camera,
+            last_frame: Instant::now(),
+            shader_watcher,
+            shader_error: false,
+            pipeline_cache_path: args.pipeline_cache_path().map(Path::to_path_buf)
+        })
     }
 
     unsafe fn render(&mut self, window: &Window) -> Result<()> {
+        self.reload_shaders(window)?;
+
         self.device.wait_for_fences(&[self.data.in_flight_fences[self.frame]], true, u64::MAX)?;
 
         let result = self.device.acquire_next_image_khr(
@@ -175,11 +281,16 @@ impl App {
         }
 
         self.data.images_in_flight[image_index as usize] = self.data.in_flight_fences[self.frame];
-        self.update_uniform_buffer(image_index)?;
+        let now = Instant::now();
+        self.camera.update((now - self.last_frame).as_secs_f32());
+        self.last_frame = now;
+
+        self.update_uniform_buffer(self.frame)?;
+        record_command_buffer(&self.device, &self.data, self.frame, image_index)?;
 
         let wait_semaphores = &[self.data.image_available_semaphores[self.frame]];
         let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers = &[self.data.command_buffers[image_index as usize]];
+        let command_buffers = &[self.data.command_buffers[self.frame]];
         let signal_semaphores = &[self.data.render_finished_semaphores[self.frame]];
 
         let submit_info = vk::SubmitInfo::builder()
@@ -208,28 +319,207 @@ impl App {
             return Err(anyhow!(e));
         }
 
-        self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.frame = (self.frame + 1) % self.data.frames_in_flight;
+        self.data.diagnostics.end_frame()?;
+
+        Ok(())
+    }
+
+    // Renders one frame of the headless target and writes it as an RGBA PNG
+    unsafe fn render_to_file(&mut self, filename: &str) -> Result<()> {
+        let fence = self.data.in_flight_fences[0];
+        self.device.wait_for_fences(&[fence], true, u64::MAX)?;
+        self.update_uniform_buffer(0)?;
+        record_command_buffer(&self.device, &self.data, 0, 0)?;
+
+        let command_buffers = &[self.data.command_buffers[0]];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(command_buffers);
+
+        self.device.reset_fences(&[fence])?;
+        self.device.queue_submit(self.data.graphics_queue, &[submit_info], fence)?;
+        self.device.wait_for_fences(&[fence], true, u64::MAX)?;
+
+        let vk::Extent2D { width, height } = self.data.swapchain_extent;
+        let size = (width * height * 4) as u64;
+
+        let (readback_buffer, readback_buffer_allocation) = create_buffer(
+            &self.instance, &self.device, &self.data, size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
+        )?;
+
+        copy_image_to_buffer(&self.device, &self.data, self.data.swapchain_images[0], readback_buffer, width, height)?;
+
+        let memory = readback_buffer_allocation.mapped_ptr()?;
+        let mut pixels = vec![0u8; size as usize];
+        memcpy(memory.cast(), pixels.as_mut_ptr(), pixels.len());
+
+        self.device.destroy_buffer(readback_buffer, None);
+        self.data.allocator.free(&self.device, &readback_buffer_allocation);
+
+        self.data.diagnostics.end_frame()?;
+
+        // Blending leaves the clear alpha in uncovered areas, the window would ignore it too
+        pixels.chunks_exact_mut(4).for_each(|p| p[3] = u8::MAX);
+
+        let file = std::io::BufWriter::new(std::fs::File::create(filename)?);
+        let mut encoder = png::Encoder::new(file, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)?;
+
+        info!("Wrote {}x{} frame to `{}`.", width, height, filename);
 
         Ok(())
     }
 
+    // Only the size-dependent attachments and framebuffers are rebuilt, the render pass and pipeline
+    // too when the surface format or the sample count changed
     unsafe fn recreate_swapchain(&mut self, window: &Window) -> Result<()> {
         self.device.device_wait_idle()?;
         self.destroy_swapchain();
 
+        let format = self.data.swapchain_format;
         create_swapchain(window, &self.instance, &self.device, &mut self.data)?;
         create_swapchain_image_views(&self.device, &mut self.data)?;
-        create_render_pass(&self.instance, &self.device, &mut self.data)?;
-        create_pipeline(&self.device, &mut self.data)?;
+
+        if self.data.swapchain_format != format || self.data.msaa_samples != self.data.render_pass_samples {
+            self.device.destroy_pipeline(self.data.pipeline, None);
+            self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
+            self.device.destroy_render_pass(self.data.render_pass, None);
+
+            create_render_pass(&self.instance, &self.device, &mut self.data)?;
+            create_pipeline(&self.device, &mut self.data)?;
+        }
+
         create_color_objects(&self.instance, &self.device, &mut self.data)?;
         create_depth_objects(&self.instance, &self.device, &mut self.data)?;
         create_framebuffers(&self.device, &mut self.data)?;
-        create_uniform_buffers(&self.instance, &self.device, &mut self.data)?;
-        create_descriptor_pool(&self.device, &mut self.data)?;
-        create_descriptor_sets(&self.device, &mut self.data)?;
-        create_command_buffers(&self.device, &mut self.data)?;
 
-        self.data.images_in_flight.resize(self.data.swapchain_images.len(), vk::Fence::null());
+        // Only needed after the frames in flight setting changed, the device is idle so the old ones can go
+        if self.data.in_flight_fences.len() != self.data.frames_in_flight {
+            self.destroy_sync_objects();
+            create_sync_objects(&self.device, &mut self.data)?;
+            self.frame = 0;
+        }
+
+        self.data.images_in_flight = vec![vk::Fence::null(); self.data.swapchain_images.len()];
+
+        Ok(())
+    }
+
+    // Rebuilds the pipeline when one of its sources changed, a shader that fails to compile leaves
+    // the running pipeline in place until the next change
+    unsafe fn reload_shaders(&mut self, window: &Window) -> Result<()> {
+        let Some(watcher) = &mut self.shader_watcher else { return Ok(()) };
+
+        // After an error any change is worth a retry, the fix may be in a file that was not included yet
+        let changed = watcher.changed();
+        let sources = [&self.data.vert_shader.sources, &self.data.frag_shader.sources];
+        if !changed.iter().any(|p| self.shader_error || sources.iter().any(|s| s.contains(p))) {
+            return Ok(());
+        }
+
+        let (vert, frag, reflection) = match load_pipeline_shaders() {
+            Ok(shaders) => shaders,
+            Err(e) => {
+                error!("{}", e);
+                set_shader_error_title(window, &e);
+                self.shader_error = true;
+                return Ok(());
+            }
+        };
+
+        // The descriptor sets were allocated and written against the current layouts
+        if reflection.bindings != self.data.reflection.bindings
+            || reflection.push_constant_size != self.data.reflection.push_constant_size
+            || reflection.push_constant_stages != self.data.reflection.push_constant_stages
+        {
+            error!("The shaders changed their descriptor bindings or push constants, restart to apply them.");
+            window.set_title(&format!("{} - shader error, see log", WINDOW_TITLE));
+            self.shader_error = true;
+            return Ok(());
+        }
+
+        self.device.device_wait_idle()?;
+
+        let pipeline = self.data.pipeline;
+        let pipeline_layout = self.data.pipeline_layout;
+        let vert = std::mem::replace(&mut self.data.vert_shader, vert);
+        let frag = std::mem::replace(&mut self.data.frag_shader, frag);
+        let reflection = std::mem::replace(&mut self.data.reflection, reflection);
+
+        if let Err(e) = create_pipeline(&self.device, &mut self.data) {
+            error!("Failed to rebuild the pipeline, keeping the previous one: {}", e);
+            if self.data.pipeline_layout != pipeline_layout {
+                self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
+            }
+
+            self.data.pipeline = pipeline;
+            self.data.pipeline_layout = pipeline_layout;
+            self.data.vert_shader = vert;
+            self.data.frag_shader = frag;
+            self.data.reflection = reflection;
+            window.set_title(&format!("{} - shader error, see log", WINDOW_TITLE));
+            self.shader_error = true;
+            return Ok(());
+        }
+
+        self.device.destroy_pipeline(pipeline, None);
+        self.device.destroy_pipeline_layout(pipeline_layout, None);
+
+        info!("Reloaded shaders.");
+        window.set_title(WINDOW_TITLE);
+        self.shader_error = false;
+
+        Ok(())
+    }
+
+    // Keyboard shortcuts for the swapchain and MSAA settings, everything else goes to the camera
+    unsafe fn handle_window_event(&mut self, event: &WindowEvent) -> Result<()> {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(key), .. }, ..
+        } = event {
+            // Key repeat sends more presses while the key is held, only the first one counts
+            if !self.camera.is_held(*key) {
+                self.handle_key(*key)?;
+            }
+        }
+
+        self.camera.handle_window_event(event);
+
+        Ok(())
+    }
+
+    unsafe fn handle_key(&mut self, key: VirtualKeyCode) -> Result<()> {
+        let image_count = self.data.swapchain_images.len() as u32;
+
+        match key {
+            // Back to the vsync preference, dropping any present mode picked with P
+            VirtualKeyCode::V => {
+                self.data.vsync = !self.data.vsync;
+                self.data.preferred_present_mode = None;
+            },
+            VirtualKeyCode::P => {
+                let support = SwapchainSupport::get(&self.instance, &self.data, self.data.physical_device)?;
+                self.data.preferred_present_mode = Some(next_present_mode(self.data.present_mode, &support.present_modes));
+            },
+            VirtualKeyCode::LBracket => self.data.preferred_image_count = Some(image_count.saturating_sub(1).max(1)),
+            VirtualKeyCode::RBracket => self.data.preferred_image_count = Some(image_count + 1),
+            VirtualKeyCode::Minus => self.data.frames_in_flight = (self.data.frames_in_flight - 1).max(1),
+            VirtualKeyCode::Equals => self.data.frames_in_flight = (self.data.frames_in_flight + 1).min(MAX_FRAMES_IN_FLIGHT),
+            // The render targets, render pass and pipeline are rebuilt along with the swapchain
+            VirtualKeyCode::M => {
+                let samples = next_msaa_samples(&self.instance, &self.data);
+                info!("Switching to {}x MSAA.", samples.bits());
+                self.data.preferred_msaa_samples = Some(samples);
+                self.data.msaa_samples = samples;
+            },
+            _ => return Ok(())
+        }
+
+        // Picked up by `render` like a resize once the current frame is presented
+        self.resized = true;
 
         Ok(())
     }
@@ -237,81 +527,90 @@ impl App {
     unsafe fn destroy(&mut self) {
         self.destroy_swapchain();
 
-        self.device.destroy_sampler(self.data.texture_sampler, None);
-        self.data.textures.iter().for_each(|t| t.texture.destroy(&self.device));
+        if !self.data.headless {
+            self.device.destroy_swapchain_khr(self.data.swapchain, None);
+        }
 
-        self.device.destroy_descriptor_set_layout(self.data.descriptor_set_layout, None);
-        self.data.mesh.destroy(&self.device);
-        self.data.in_flight_fences.iter().for_each(|f| self.device.destroy_fence(*f, None));
-        self.data.render_finished_semaphores.iter().for_each(|s| self.device.destroy_semaphore(*s, None));
-        self.data.image_available_semaphores.iter().for_each(|s| self.device.destroy_semaphore(*s, None));
+        self.device.destroy_descriptor_pool(self.data.descriptor_pool, None);
+        self.data.uniform_buffers.iter().for_each(|b| self.device.destroy_buffer(*b, None));
+        self.data.uniform_buffers_allocations.iter().for_each(|a| self.data.allocator.free(&self.device, a));
+        self.device.destroy_pipeline(self.data.pipeline, None);
+        self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
+        self.device.destroy_render_pass(self.data.render_pass, None);
+
+        self.data.scene.destroy(&self.device, &self.data);
+        self.data.environment.destroy(&self.device, &self.data);
+
+        self.data.descriptor_set_layouts.iter().for_each(|l| self.device.destroy_descriptor_set_layout(*l, None));
+        self.destroy_sync_objects();
         self.device.destroy_command_pool(self.data.command_pool, None);
+        self.data.allocator.destroy(&self.device);
+
+        // Losing the cache only costs the next start some time
+        if let Some(path) = &self.pipeline_cache_path {
+            if let Err(e) = save_pipeline_cache(&self.device, self.data.pipeline_cache, path) {
+                warn!("Failed to save the pipeline cache to `{}`: {}", path.display(), e);
+            }
+        }
+
+        self.device.destroy_pipeline_cache(self.data.pipeline_cache, None);
         self.device.destroy_device(None);
-        self.instance.destroy_surface_khr(self.data.surface, None);
 
-        if VALIDATION_ENABLED {
+        if !self.data.headless {
+            self.instance.destroy_surface_khr(self.data.surface, None);
+        }
+
+        if self.data.validation {
             self.instance.destroy_debug_utils_messenger_ext(self.data.messenger, None);
         }
 
         self.instance.destroy_instance(None);
     }
 
+    unsafe fn destroy_sync_objects(&mut self) {
+        self.data.in_flight_fences.drain(..).for_each(|f| self.device.destroy_fence(f, None));
+        self.data.render_finished_semaphores.drain(..).for_each(|s| self.device.destroy_semaphore(s, None));
+        self.data.image_available_semaphores.drain(..).for_each(|s| self.device.destroy_semaphore(s, None));
+    }
+
+    // Everything sized after the swapchain, the swapchain itself is handed to its successor
     unsafe fn destroy_swapchain(&mut self) {
         self.device.destroy_image_view(self.data.color_image_view, None);
-        self.device.free_memory(self.data.color_image_memory, None);
         self.device.destroy_image(self.data.color_image, None);
+        self.data.allocator.free(&self.device, &self.data.color_image_allocation);
 
         self.device.destroy_image_view(self.data.depth_image_view, None);
-        self.device.free_memory(self.data.depth_image_memory, None);
         self.device.destroy_image(self.data.depth_image, None);
-        
-        self.device.destroy_descriptor_pool(self.data.descriptor_pool, None);
-        self.data.uniform_buffers.iter().for_each(|b| self.device.destroy_buffer(*b, None));
-        self.data.uniform_buffers_memory.iter().for_each(|b| self.device.free_memory(*b, None));
+        self.data.allocator.free(&self.device, &self.data.depth_image_allocation);
+
         self.data.framebuffers.iter().for_each(|f| self.device.destroy_framebuffer(*f, None));
-        self.device.free_command_buffers(self.data.command_pool, &self.data.command_buffers);
-        self.device.destroy_pipeline(self.data.pipeline, None);
-        self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
-        self.device.destroy_render_pass(self.data.render_pass, None);
         self.data.swapchain_image_views.iter().for_each(|v| self.device.destroy_image_view(*v, None));
-        self.device.destroy_swapchain_khr(self.data.swapchain, None);
-    }
-
-    unsafe fn update_uniform_buffer(&self, image_index: usize) -> Result<()> {
-        let time = self.start.elapsed().as_secs_f32();
 
-        let model = glm::rotate(
-            &glm::identity(),
-            time * glm::radians(&glm::vec1(90.0))[0],
-            &glm::vec3(0.0, 0.0, 1.0)
-        );
-
-        let view = glm::look_at(
-            &glm::vec3(0.0, -12.0, 5.0), 
-            &glm::vec3(0.0, 0.0, 1.0), 
-            &glm::vec3(0.0, 0.0, 1.0)
-        );
+        if self.data.headless {
+            self.data.swapchain_images.iter().for_each(|i| self.device.destroy_image(*i, None));
+            self.data.allocator.free(&self.device, &self.data.offscreen_image_allocation);
+        }
+    }
 
-        let mut proj = glm::perspective_rh_zo(
-            self.data.swapchain_extent.width as f32 / self.data.swapchain_extent.height as f32,
-            glm::radians(&glm::vec1(45.0))[0],
-            0.1,
-            100.0
+    unsafe fn update_uniform_buffer(&self, frame: usize) -> Result<()> {
+        let view = self.camera.view();
+        let proj = self.camera.projection(
+            self.data.swapchain_extent.width as f32 / self.data.swapchain_extent.height as f32
         );
 
-        proj[(1, 1)] *= -1.0;
-
-        let ubo = UniformBufferObject { model, view, proj };
+        let mut lights = [LightData::default(); MAX_LIGHTS];
+        self.data.scene.lights.iter().zip(&mut lights).for_each(|(l, u)| *u = l.to_uniform());
 
-        let memory = self.device.map_memory(
-            self.data.uniform_buffers_memory[image_index],
-            0,
-            size_of::<UniformBufferObject>() as u64,
-            vk::MemoryMapFlags::empty()
-        )?;
+        let ubo = UniformBufferObject {
+            view,
+            proj,
+            lights,
+            light_count: self.data.scene.lights.len().min(MAX_LIGHTS) as u32,
+            padding: [0; 3]
+        };
 
+        let memory = self.data.uniform_buffers_allocations[frame].mapped_ptr()?;
         memcpy(&ubo, memory.cast(), 1);
-        self.device.unmap_memory(self.data.uniform_buffers_memory[image_index]);
 
         Ok(())
     }
@@ -319,21 +618,52 @@ impl App {
 
 #[derive(Clone, Debug, Default)]
 pub struct AppData {
+    // Without a window the swapchain fields describe a single offscreen image
+    headless: bool,
+    offscreen_image_allocation: Allocation,
+
+    // Command-line choices, the GPU and MSAA requests are checked against the device
+    validation: bool,
+    gpu: Option<String>,
+    preferred_msaa_samples: Option<vk::SampleCountFlags>,
+    // Fraction of the samples shaded separately, 0 shades once per pixel
+    min_sample_shading: f32,
+
+    // Swapchain settings, changeable at runtime and applied by recreating the swapchain
+    preferred_present_mode: Option<vk::PresentModeKHR>,
+    vsync: bool,
+    preferred_image_count: Option<u32>,
+    frames_in_flight: usize,
+
     surface: vk::SurfaceKHR,
     messenger: vk::DebugUtilsMessengerEXT,
+    // Referenced by the messenger callback, must outlive the instance
+    diagnostics: Arc<Diagnostics>,
     physical_device: vk::PhysicalDevice,
+    // Clones of the data share the one allocator of the device
+    allocator: Arc<Allocator>,
     msaa_samples: vk::SampleCountFlags,
+    sample_rate_shading: bool,
+    sampler_anisotropy: bool,
     graphics_queue: vk::Queue,
     present_queue: vk::Queue,
 
     swapchain_format: vk::Format,
     swapchain_extent: vk::Extent2D,
+    present_mode: vk::PresentModeKHR,
     swapchain: vk::SwapchainKHR,
     swapchain_images: Vec<vk::Image>,
     swapchain_image_views: Vec<vk::ImageView>,
 
     render_pass: vk::RenderPass,
-    descriptor_set_layout: vk::DescriptorSetLayout,
+    // What the render pass and pipeline were created for, `msaa_samples` changes ahead of them
+    render_pass_samples: vk::SampleCountFlags,
+    pipeline_cache: vk::PipelineCache,
+    vert_shader: CompiledShader,
+    frag_shader: CompiledShader,
+    // Reflected from the shaders, the layouts are indexed by set number
+    reflection: ShaderReflection,
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     pipeline_layout: vk::PipelineLayout,
     pipeline: vk::Pipeline,
 
@@ -347,33 +677,40 @@ pub struct AppData {
     images_in_flight: Vec<vk::Fence>,
 
     uniform_buffers: Vec<vk::Buffer>,
-    uniform_buffers_memory: Vec<vk::DeviceMemory>,
+    uniform_buffers_allocations: Vec<Allocation>,
 
     descriptor_pool: vk::DescriptorPool,
     descriptor_sets: Vec<vk::DescriptorSet>,
 
-    mip_levels: u32,
-    // texture: Texture,
-    textures: Vec<Texture2D>,
-    texture_sampler: vk::Sampler,
+    scene: Scene,
+    environment: Environment,
 
     depth_image: vk::Image,
-    depth_image_memory: vk::DeviceMemory,
+    depth_image_allocation: Allocation,
     depth_image_view: vk::ImageView,
 
-    mesh: Mesh,
-
     color_image: vk::Image,
-    color_image_memory: vk::DeviceMemory,
+    color_image_allocation: Allocation,
     color_image_view: vk::ImageView
 }
 
+// Renders a single frame without a window or surface, e.g. on a software driver like lavapipe
+unsafe fn render_headless(args: &Args, scene: &SceneFile, output: &str) -> Result<()> {
+    let mut app = App::create_headless(args, scene)?;
+    let result = app.render_to_file(output);
+
+    app.device.device_wait_idle()?;
+    app.destroy();
+
+    result
+}
+
 // ================================================================================================
 // INSTANCE
 // ================================================================================================
 
 unsafe fn create_instance(
-    window: &Window,
+    window: Option<&Window>,
     entry: &Entry,
     data: &mut AppData
 ) -> Result<Instance> {
@@ -390,22 +727,23 @@ unsafe fn create_instance(
         .map(|l| l.layer_name)
         .collect::<HashSet<_>>();
 
-    if VALIDATION_ENABLED && !available_layers.contains(&VALIDATION_LAYER) {
+    if data.validation && !available_layers.contains(&VALIDATION_LAYER) {
         return Err(anyhow!("Validation layer requested but not supported."));
     }
 
-    let layers = if VALIDATION_ENABLED {
+    let layers = if data.validation {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         Vec::new()
     };
 
-    let mut extensions = vk_window::get_required_instance_extensions(window)
+    let mut extensions = window
+        .map_or(&[][..], |w| vk_window::get_required_instance_extensions(w))
         .iter()
         .map(|e| e.as_ptr())
         .collect::<Vec<_>>();
 
-    if VALIDATION_ENABLED {
+    if data.validation {
         extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr());
     }
 
@@ -419,41 +757,21 @@ unsafe fn create_instance(
         .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
         .user_callback(Some(debug_callback));
 
-    if VALIDATION_ENABLED {
+    debug_info.user_data = Arc::as_ptr(&data.diagnostics) as *mut c_void;
+
+    if data.validation {
         info = info.push_next(&mut debug_info);
     }
 
     let instance = entry.create_instance(&info, None)?;
 
-    if VALIDATION_ENABLED {
+    if data.validation {
         data.messenger = instance.create_debug_utils_messenger_ext(&debug_info, None)?;
     }
 
     Ok(instance)
 }
 
-extern "system" fn debug_callback(
-    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    type_: vk::DebugUtilsMessageTypeFlagsEXT,
-    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _: *mut c_void
-) -> vk::Bool32 {
-    let data = unsafe { *data };
-    let message = unsafe { CStr::from_ptr(data.message) }.to_string_lossy();
-
-    if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
-        error!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
-        warn!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
-        debug!("({:?}) {}", type_, message);
-    } else {
-        trace!("({:?}) {}", type_, message);
-    }
-
-    vk::FALSE
-}
-
 // ================================================================================================
 // PHYSICAL DEVICE
 // ================================================================================================
@@ -463,20 +781,123 @@ extern "system" fn debug_callback(
 pub struct SuitabilityError(pub &'static str);
 
 unsafe fn pick_physical_device(instance: &Instance, data: &mut AppData) -> Result<()> {
-    for physical_device in instance.enumerate_physical_devices()? {
+    let physical_devices = instance.enumerate_physical_devices()?;
+
+    let physical_device = if let Some(gpu) = &data.gpu {
+        // A GPU picked on the command line is used as is or reported as unsuitable
+        let physical_device = find_physical_device(instance, &physical_devices, gpu)?;
         let properties = instance.get_physical_device_properties(physical_device);
+        check_physical_device(instance, data, physical_device)
+            .map_err(|e| anyhow!("Physical device (`{}`) is not suitable: {}", properties.device_name, e))?;
 
-        if let Err(error) = check_physical_device(instance, data, physical_device) {
-            warn!("Skipping physical device (`{}`): {}", properties.device_name, error);
-        } else {
-            info!("Selected physical device (`{}`).", properties.device_name);
-            data.physical_device = physical_device;
-            data.msaa_samples = get_max_msaa_samples(instance, data);
-            return Ok(());
+        physical_device
+    } else {
+        let mut suitable = vec![];
+        for physical_device in physical_devices {
+            let properties = instance.get_physical_device_properties(physical_device);
+
+            if let Err(error) = check_physical_device(instance, data, physical_device) {
+                warn!("Skipping physical device (`{}`): {}", properties.device_name, error);
+            } else {
+                suitable.push((rate_physical_device(instance, physical_device), physical_device));
+            }
         }
+
+        // Reversed so the first enumerated device wins among equally rated ones
+        suitable
+            .into_iter()
+            .rev()
+            .max_by_key(|(rating, _)| *rating)
+            .map(|(_, d)| d)
+            .ok_or_else(|| anyhow!("Failed to find suitable physical device."))?
+    };
+
+    let properties = instance.get_physical_device_properties(physical_device);
+    info!("Selected physical device (`{}`).", properties.device_name);
+
+    data.physical_device = physical_device;
+    data.msaa_samples = get_msaa_samples(instance, data);
+
+    Ok(())
+}
+
+// `gpu` is an index into the enumeration order or a case-insensitive part of the device name
+unsafe fn find_physical_device(
+    instance: &Instance,
+    physical_devices: &[vk::PhysicalDevice],
+    gpu: &str
+) -> Result<vk::PhysicalDevice> {
+    let found = match gpu.parse::<usize>() {
+        Ok(index) => physical_devices.get(index).cloned(),
+        Err(_) => physical_devices.iter().cloned().find(|d| {
+            let name = instance.get_physical_device_properties(*d).device_name.to_string();
+            name.to_lowercase().contains(&gpu.to_lowercase())
+        })
+    };
+
+    found.ok_or_else(|| anyhow!("No physical device matches `{}`, see `--list-devices`.", gpu))
+}
+
+// Compared in order: device type, device-local memory in MiB, then optional features we make use of
+unsafe fn rate_physical_device(instance: &Instance, physical_device: vk::PhysicalDevice) -> (u32, u64, u32) {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let device_type = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0
+    };
+
+    let memory = instance.get_physical_device_memory_properties(physical_device);
+    let device_local = memory.memory_heaps[..memory.memory_heap_count as usize]
+        .iter()
+        .filter(|h| h.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|h| h.size / (1024 * 1024))
+        .sum();
+
+    let features = instance.get_physical_device_features(physical_device);
+    let limits = properties.limits;
+    let sample_counts = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+    let optional = (features.sample_rate_shading == vk::TRUE) as u32
+        + (features.sampler_anisotropy == vk::TRUE) as u32
+        + (features.texture_compression_bc == vk::TRUE) as u32
+        + sample_counts.bits().count_ones();
+
+    (device_type, device_local, optional)
+}
+
+// Lists every device with its rating, suitability and capabilities, without creating a window
+unsafe fn list_devices(args: &Args) -> Result<()> {
+    let loader = LibloadingLoader::new(LIBRARY)?;
+    let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
+    let mut data = AppData { headless: true, validation: args.validation_enabled(), ..Default::default() };
+
+    let instance = create_instance(None, &entry, &mut data)?;
+
+    for (index, physical_device) in instance.enumerate_physical_devices()?.into_iter().enumerate() {
+        let properties = instance.get_physical_device_properties(physical_device);
+        let (device_type, device_local, optional) = rate_physical_device(&instance, physical_device);
+
+        println!("Device {}: {}", index, properties.device_name);
+        println!("  Rating: type {}, {} MiB device-local, {} optional features", device_type, device_local, optional);
+
+        // Swapchain support needs a surface, so only headless rendering is checked here
+        match check_physical_device(&instance, &data, physical_device) {
+            Ok(()) => println!("  Suitable for headless rendering: yes"),
+            Err(e) => println!("  Suitable for headless rendering: no, {}", e)
+        }
+
+        print!("{}", device_report(&instance, physical_device)?);
     }
 
-    Err(anyhow!("Failed to find suitable physical device."))
+    if data.validation {
+        instance.destroy_debug_utils_messenger_ext(data.messenger, None);
+    }
+
+    instance.destroy_instance(None);
+
+    Ok(())
 }
 
 unsafe fn check_physical_device(
@@ -485,16 +906,13 @@ unsafe fn check_physical_device(
     physical_device: vk::PhysicalDevice
 ) -> Result<()> {
     QueueFamilyIndices::get(instance, data, physical_device)?;
-    check_physical_device_extensions(instance, physical_device)?;
-
-    let support = SwapchainSupport::get(instance, data, physical_device)?;
-    if support.formats.is_empty() || support.present_modes.is_empty() {
-        return Err(anyhow!(SuitabilityError("Insufficient swapchain support.")));
-    }
+    check_physical_device_extensions(instance, data, physical_device)?;
 
-    let features = instance.get_physical_device_features(physical_device);
-    if features.sampler_anisotropy != vk::TRUE {
-        return Err(anyhow!(SuitabilityError("Sampler anisotropy feature support missing.")));
+    if !data.headless {
+        let support = SwapchainSupport::get(instance, data, physical_device)?;
+        if support.formats.is_empty() || support.present_modes.is_empty() {
+            return Err(anyhow!(SuitabilityError("Insufficient swapchain support.")));
+        }
     }
 
     Ok(())
@@ -502,6 +920,7 @@ unsafe fn check_physical_device(
 
 unsafe fn check_physical_device_extensions(
     instance: &Instance,
+    data: &AppData,
     physical_device: vk::PhysicalDevice
 ) -> Result<()> {
     let extensions = instance
@@ -510,29 +929,61 @@ unsafe fn check_physical_device_extensions(
         .map(|e| e.extension_name)
         .collect::<HashSet<_>>();
 
-    if DEVICE_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
+    if device_extensions(data).iter().all(|e| extensions.contains(e)) {
         Ok(())
     } else {
         Err(anyhow!(SuitabilityError("Missing required device extensions.")))
     }
 }
 
-unsafe fn get_max_msaa_samples(
+// Counts usable for both the color and the depth attachment, always includes 1
+unsafe fn get_supported_msaa_samples(
     instance: &Instance,
     data: &AppData
 ) -> vk::SampleCountFlags {
     let properties = instance.get_physical_device_properties(data.physical_device);
-    let counts = properties.limits.framebuffer_color_sample_counts & properties.limits.framebuffer_depth_sample_counts;
-
-    [
-        vk::SampleCountFlags::_64,
-        vk::SampleCountFlags::_32,
-        vk::SampleCountFlags::_16,
-        vk::SampleCountFlags::_8,
-        vk::SampleCountFlags::_4,
-        vk::SampleCountFlags::_2,
-    ]
-    .iter().cloned().find(|c| counts.contains(*c)).unwrap_or(vk::SampleCountFlags::_1)
+    properties.limits.framebuffer_color_sample_counts & properties.limits.framebuffer_depth_sample_counts
+}
+
+// The requested sample count, or the closest lower one the device supports
+unsafe fn get_msaa_samples(
+    instance: &Instance,
+    data: &AppData
+) -> vk::SampleCountFlags {
+    let supported = get_supported_msaa_samples(instance, data);
+    let requested = data.preferred_msaa_samples.unwrap_or(vk::SampleCountFlags::_64);
+
+    let samples = MSAA_SAMPLES
+        .iter()
+        .rev()
+        .cloned()
+        .find(|c| c.bits() <= requested.bits() && supported.contains(*c))
+        .unwrap_or(vk::SampleCountFlags::_1);
+
+    if data.preferred_msaa_samples.is_some_and(|s| s != samples) {
+        warn!("{}x MSAA is not supported, using {}x.", requested.bits(), samples.bits());
+    }
+
+    samples
+}
+
+// The supported count following the current one, wrapping around to 1x
+unsafe fn next_msaa_samples(
+    instance: &Instance,
+    data: &AppData
+) -> vk::SampleCountFlags {
+    let supported = get_supported_msaa_samples(instance, data);
+
+    MSAA_SAMPLES
+        .iter()
+        .cloned()
+        .find(|c| c.bits() > data.msaa_samples.bits() && supported.contains(*c))
+        .unwrap_or(vk::SampleCountFlags::_1)
+}
+
+// The swapchain extension is only needed when presenting
+fn device_extensions(data: &AppData) -> &'static [vk::ExtensionName] {
+    if data.headless { &[] } else { DEVICE_EXTENSIONS }
 }
 
 // ================================================================================================
@@ -556,17 +1007,27 @@ unsafe fn create_logical_device(
             .queue_priorities(queue_priorities)
     }).collect::<Vec<_>>();
 
-    let layers = if VALIDATION_ENABLED {
+    let layers = if data.validation {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         vec![]
     };
 
-    let extensions = DEVICE_EXTENSIONS.iter().map(|n| n.as_ptr()).collect::<Vec<_>>();
+    let extensions = device_extensions(data).iter().map(|n| n.as_ptr()).collect::<Vec<_>>();
+
+    // Without sample rate shading `min_sample_shading` is ignored and MSAA only smooths edges
+    let supported_features = instance.get_physical_device_features(data.physical_device);
+    data.sample_rate_shading = supported_features.sample_rate_shading == vk::TRUE;
+    if !data.sample_rate_shading && data.min_sample_shading > 0.0 {
+        warn!("Sample rate shading is not supported, ignoring the minimum sample shading.");
+    }
+
+    // Textures fall back to plain trilinear filtering without it
+    data.sampler_anisotropy = supported_features.sampler_anisotropy == vk::TRUE;
 
     let features = vk::PhysicalDeviceFeatures::builder()
-        .sampler_anisotropy(true)
-        .sample_rate_shading(true);
+        .sampler_anisotropy(data.sampler_anisotropy)
+        .sample_rate_shading(data.sample_rate_shading);
 
     let info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
@@ -596,16 +1057,14 @@ unsafe fn create_swapchain(
     let support = SwapchainSupport::get(instance, data, data.physical_device)?;
 
     let surface_format = get_swapchain_surface_format(&support.formats);
-    let present_mode = get_swapchain_present_mode(&support.present_modes);
+    let present_mode = get_swapchain_present_mode(data, &support.present_modes);
     let extent = get_swapchain_extent(window, support.capabilities);
 
+    let image_count = get_swapchain_image_count(data, support.capabilities);
+
     data.swapchain_format = surface_format.format;
     data.swapchain_extent = extent;
-
-    let mut image_count = support.capabilities.min_image_count + 1;
-    if support.capabilities.max_image_count != 0 && image_count > support.capabilities.max_image_count {
-        image_count = support.capabilities.max_image_count;
-    }
+    data.present_mode = present_mode;
 
     let mut queue_family_indices = vec![];
     let image_sharing_mode = if indices.graphics != indices.present {
@@ -630,11 +1089,20 @@ unsafe fn create_swapchain(
         .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
         .present_mode(present_mode)
         .clipped(true)
-        .old_swapchain(vk::SwapchainKHR::null());
+        .old_swapchain(data.swapchain);
 
+    // Creating the new swapchain retires the old one, the device is idle so it can go right away
+    let old_swapchain = data.swapchain;
     data.swapchain = device.create_swapchain_khr(&info, None)?;
+    device.destroy_swapchain_khr(old_swapchain, None);
+
     data.swapchain_images = device.get_swapchain_images_khr(data.swapchain)?;
 
+    info!(
+        "Swapchain: {}x{}, {:?}, {} images, {} frames in flight.",
+        extent.width, extent.height, present_mode, data.swapchain_images.len(), data.frames_in_flight
+    );
+
     Ok(())
 }
 
@@ -650,16 +1118,64 @@ fn get_swapchain_surface_format(
         .unwrap_or_else(|| formats[0])
 }
 
+// Order the P key cycles through
+const PRESENT_MODES: &[vk::PresentModeKHR] = &[
+    vk::PresentModeKHR::FIFO,
+    vk::PresentModeKHR::FIFO_RELAXED,
+    vk::PresentModeKHR::MAILBOX,
+    vk::PresentModeKHR::IMMEDIATE
+];
+
+// An unsupported preference falls back to the vsync setting, FIFO is the only mode every device supports
 fn get_swapchain_present_mode(
+    data: &AppData,
     present_modes: &[vk::PresentModeKHR]
 ) -> vk::PresentModeKHR {
-    present_modes
+    if let Some(preferred) = data.preferred_present_mode {
+        if present_modes.contains(&preferred) {
+            return preferred;
+        }
+
+        warn!("Present mode {:?} is not supported, falling back to vsync {}.", preferred, if data.vsync { "on" } else { "off" });
+    }
+
+    let candidates: &[vk::PresentModeKHR] = if data.vsync {
+        &[vk::PresentModeKHR::MAILBOX]
+    } else {
+        &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX]
+    };
+
+    candidates
         .iter()
         .cloned()
-        .find(|m| *m == vk::PresentModeKHR::MAILBOX)
+        .find(|m| present_modes.contains(m))
+        .unwrap_or(vk::PresentModeKHR::FIFO)
+}
+
+// The supported mode following `current` in `PRESENT_MODES`, wrapping around
+fn next_present_mode(current: vk::PresentModeKHR, present_modes: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
+    let start = PRESENT_MODES.iter().position(|m| *m == current).unwrap_or(0);
+
+    (1..=PRESENT_MODES.len())
+        .map(|i| PRESENT_MODES[(start + i) % PRESENT_MODES.len()])
+        .find(|m| present_modes.contains(m))
         .unwrap_or(vk::PresentModeKHR::FIFO)
 }
 
+// One more than the minimum unless another count was asked for, a `max_image_count` of 0 means no limit
+fn get_swapchain_image_count(data: &AppData, capabilities: vk::SurfaceCapabilitiesKHR) -> u32 {
+    let requested = data.preferred_image_count.unwrap_or(capabilities.min_image_count + 1);
+    let max = if capabilities.max_image_count == 0 { u32::MAX } else { capabilities.max_image_count };
+    let image_count = requested.clamp(capabilities.min_image_count, max);
+
+    if data.preferred_image_count.is_some_and(|c| c != image_count) {
+        warn!("{} swapchain images requested, the surface allows {} to {}, using {}.",
+            requested, capabilities.min_image_count, max, image_count);
+    }
+
+    image_count
+}
+
 fn get_swapchain_extent(
     window: &Window,
     capabilities: vk::SurfaceCapabilitiesKHR
@@ -682,30 +1198,61 @@ unsafe fn create_swapchain_image_views(
     data: &mut AppData
 ) -> Result<()> {
     data.swapchain_image_views = data.swapchain_images.iter().map(|i| {
-        create_image_view(device, *i, data.swapchain_format, vk::ImageAspectFlags::COLOR, 1)
+        create_image_view(device, *i, vk::ImageViewType::_2D, data.swapchain_format, vk::ImageAspectFlags::COLOR, 1, 1)
     }).collect::<Result<Vec<_>, _>>()?;
 
     Ok(())
 }
 
+// Single color target in place of the swapchain, read back after rendering
+unsafe fn create_offscreen_target(
+    instance: &Instance,
+    device: &Device,
+    data: &mut AppData,
+    extent: vk::Extent2D
+) -> Result<()> {
+    data.swapchain_format = vk::Format::R8G8B8A8_SRGB;
+    data.swapchain_extent = extent;
+
+    let (image, image_allocation) = create_image(
+        device, data,
+        extent.width, extent.height, 1, 1,
+        vk::ImageCreateFlags::empty(),
+        vk::SampleCountFlags::_1,
+        data.swapchain_format,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL
+    )?;
+
+    data.swapchain_images = vec![image];
+    data.offscreen_image_allocation = image_allocation;
+
+    create_swapchain_image_views(device, data)
+}
+
 // ================================================================================================
 // PIPELINE
 // ================================================================================================
 
+// Without MSAA the swapchain image is the color attachment and there is nothing to resolve
 unsafe fn create_render_pass(
     instance: &Instance,
     device: &Device,
     data: &mut AppData
 ) -> Result<()> {
+    let multisampled = data.msaa_samples != vk::SampleCountFlags::_1;
+    let output_layout = if data.headless { vk::ImageLayout::TRANSFER_SRC_OPTIMAL } else { vk::ImageLayout::PRESENT_SRC_KHR };
+
     let color_attachment = vk::AttachmentDescription::builder()
         .format(data.swapchain_format)
         .samples(data.msaa_samples)
         .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::STORE)
+        .store_op(if multisampled { vk::AttachmentStoreOp::DONT_CARE } else { vk::AttachmentStoreOp::STORE })
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        .final_layout(if multisampled { vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL } else { output_layout });
 
     let depth_stencil_attachment = vk::AttachmentDescription::builder()
         .format(get_depth_format(instance, data)?)
@@ -725,7 +1272,7 @@ unsafe fn create_render_pass(
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
+        .final_layout(output_layout);
 
     let color_attachment_ref = vk::AttachmentReference::builder()
         .attachment(0)
@@ -741,11 +1288,14 @@ unsafe fn create_render_pass(
 
     let color_attachments = &[color_attachment_ref];
     let resolve_attachments = &[color_resolve_attachment_ref];
-    let subpass = vk::SubpassDescription::builder()
+    let mut subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
         .color_attachments(color_attachments)
-        .depth_stencil_attachment(&depth_stencil_attachment_ref)
-        .resolve_attachments(resolve_attachments);
+        .depth_stencil_attachment(&depth_stencil_attachment_ref);
+
+    if multisampled {
+        subpass = subpass.resolve_attachments(resolve_attachments);
+    }
 
     let dependency = vk::SubpassDependency::builder()
         .src_subpass(vk::SUBPASS_EXTERNAL)
@@ -755,26 +1305,92 @@ unsafe fn create_render_pass(
         .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
         .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
 
-    let attachments = &[color_attachment, depth_stencil_attachment, color_resolve_attachment];
+    // The offscreen image is copied to the host right after the pass
+    let readback_dependency = vk::SubpassDependency::builder()
+        .src_subpass(0)
+        .dst_subpass(vk::SUBPASS_EXTERNAL)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
+        .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+
+    let attachments = if multisampled {
+        &[color_attachment, depth_stencil_attachment, color_resolve_attachment][..]
+    } else {
+        &[color_attachment, depth_stencil_attachment][..]
+    };
+
     let subpasses = &[subpass];
-    let dependencies = &[dependency];
+    let dependencies = if data.headless { &[dependency, readback_dependency][..] } else { &[dependency][..] };
     let info = vk::RenderPassCreateInfo::builder()
         .attachments(attachments)
         .subpasses(subpasses)
         .dependencies(dependencies);
 
     data.render_pass = device.create_render_pass(&info, None)?;
+    data.render_pass_samples = data.msaa_samples;
 
     Ok(())
 }
 
+// Compiled once up front and again by `App::reload_shaders`, not on every pipeline rebuild
+fn load_pipeline_shaders() -> Result<(CompiledShader, CompiledShader, ShaderReflection)> {
+    // The light array is sized on the Rust side
+    let max_lights = MAX_LIGHTS.to_string();
+    let defines = &[("MAX_LIGHTS", Some(max_lights.as_str()))];
+
+    let vert = compile_shader(format!("{}/shader.vert", SHADER_DIRECTORY), defines)?;
+    let frag = compile_shader(format!("{}/shader.frag", SHADER_DIRECTORY), defines)?;
+
+    let reflection = ShaderReflection::new(&[&vert, &frag])?;
+    reflection.check_vertex_inputs(&Vertex::attribute_descriptions())?;
+
+    // Materials and the environment allocate their own sets and rely on these numbers
+    if reflection.set_count() != ENVIRONMENT_SET + 1 {
+        return Err(anyhow!(
+            "The pipeline shaders use {} descriptor set(s), expected the frame ({}), material ({}) and environment ({}) sets.",
+            reflection.set_count(), FRAME_SET, MATERIAL_SET, ENVIRONMENT_SET
+        ));
+    }
+
+    Ok((vert, frag, reflection))
+}
+
+// Compile errors show their first `file:line` message, the log has the rest
+fn set_shader_error_title(window: &Window, error: &anyhow::Error) {
+    let summary = error
+        .downcast_ref::<ShaderError>()
+        .and_then(|e| e.messages.first())
+        .map_or("shader error, see log", |m| m.as_str());
+
+    window.set_title(&format!("{} - {}", WINDOW_TITLE, summary));
+}
+
 unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
-    let vert = include_bytes!("../shaders-cache/vert.spv");
-    let frag = include_bytes!("../shaders-cache/frag.spv");
+    let vert_shader_module = create_shader_module(device, &data.vert_shader.code)?;
+    let frag_shader_module = match create_shader_module(device, &data.frag_shader.code) {
+        Ok(module) => module,
+        Err(e) => {
+            device.destroy_shader_module(vert_shader_module, None);
+            return Err(e);
+        }
+    };
 
-    let vert_shader_module = create_shader_module(device, &vert[..])?;
-    let frag_shader_module = create_shader_module(device, &frag[..])?;
+    // The modules are only needed while the pipeline is created, failed hot reloads must not leak them
+    let result = create_pipeline_from_modules(device, data, vert_shader_module, frag_shader_module);
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    result
+}
 
+unsafe fn create_pipeline_from_modules(
+    device: &Device,
+    data: &mut AppData,
+    vert_shader_module: vk::ShaderModule,
+    frag_shader_module: vk::ShaderModule
+) -> Result<()> {
     let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
         .stage(vk::ShaderStageFlags::VERTEX)
         .module(vert_shader_module)
@@ -795,23 +1411,13 @@ unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
         .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
         .primitive_restart_enable(false);
 
-    let viewport = vk::Viewport::builder()
-        .x(0.0)
-        .y(0.0)
-        .width(data.swapchain_extent.width as f32)
-        .height(data.swapchain_extent.height as f32)
-        .min_depth(0.0)
-        .max_depth(1.0);
-
-    let scissor = vk::Rect2D::builder()
-        .offset(vk::Offset2D { x: 0, y: 0 })
-        .extent(data.swapchain_extent);
-
-    let viewports = &[viewport];
-    let scissors = &[scissor];
+    // Set while recording, so the pipeline survives swapchain resizes
     let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-        .viewports(viewports)
-        .scissors(scissors);
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let dynamic_states = &[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(dynamic_states);
 
     let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
         .depth_clamp_enable(false)
@@ -822,10 +1428,15 @@ unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
         .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
         .depth_bias_enable(false);
 
+    // Matches the current render pass, a pending MSAA change only applies once it is recreated
+    let sample_shading = data.sample_rate_shading
+        && data.min_sample_shading > 0.0
+        && data.render_pass_samples != vk::SampleCountFlags::_1;
+
     let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-        .sample_shading_enable(true)
-        .min_sample_shading(0.2)
-        .rasterization_samples(data.msaa_samples);
+        .sample_shading_enable(sample_shading)
+        .min_sample_shading(data.min_sample_shading)
+        .rasterization_samples(data.render_pass_samples);
 
     let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
         .depth_test_enable(true)
@@ -851,8 +1462,10 @@ unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
         .attachments(attachments)
         .blend_constants([0.0, 0.0, 0.0, 0.0]);
 
-    let set_layouts = &[data.descriptor_set_layout];
-    let layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(set_layouts);
+    let push_constant_ranges = data.reflection.push_constant_ranges();
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(&data.descriptor_set_layouts)
+        .push_constant_ranges(&push_constant_ranges);
     data.pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
 
     let stages = &[vert_stage, frag_stage];
@@ -865,55 +1478,32 @@ unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
         .multisample_state(&multisample_state)
         .depth_stencil_state(&depth_stencil_state)
         .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
         .layout(data.pipeline_layout)
         .render_pass(data.render_pass)
         .subpass(0);
 
-    data.pipeline = device.create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?.0;
-
-    device.destroy_shader_module(vert_shader_module, None);
-    device.destroy_shader_module(frag_shader_module, None);
+    data.pipeline = device.create_graphics_pipelines(data.pipeline_cache, &[info], None)?.0;
 
     Ok(())
 }
 
 unsafe fn create_shader_module(
     device: &Device,
-    bytecode: &[u8]
+    bytecode: &[u32]
 ) -> Result<vk::ShaderModule> {
-    let bytecode = Vec::<u8>::from(bytecode);
-    let (prefix, code, suffix) = bytecode.align_to::<u32>();
-    if !prefix.is_empty() || !suffix.is_empty() {
-        return Err(anyhow!("Shader bytecode is not properly aligned."));
-    }
-
     let info = vk::ShaderModuleCreateInfo::builder()
-        .code_size(bytecode.len())
-        .code(code);
+        .code_size(size_of_val(bytecode))
+        .code(bytecode);
 
     Ok(device.create_shader_module(&info, None)?)
 }
 
-unsafe fn create_descriptor_set_layout(
+unsafe fn create_descriptor_set_layouts(
     device: &Device,
     data: &mut AppData
 ) -> Result<()> {
-    let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
-        .binding(0)
-        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::VERTEX);
-
-    let sampler_binding = vk::DescriptorSetLayoutBinding::builder()
-        .binding(1)
-        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
-
-    let bindings = &[ubo_binding, sampler_binding];
-    let info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(bindings);
-
-    data.descriptor_set_layout = device.create_descriptor_set_layout(&info, None)?;
+    data.descriptor_set_layouts = data.reflection.create_set_layouts(device)?;
 
     Ok(())
 }
@@ -927,10 +1517,16 @@ unsafe fn create_framebuffers(
     data: &mut AppData
 ) -> Result<()> {
     data.framebuffers = data.swapchain_image_views.iter().map(|i| {
-        let attachments = &[data.color_image_view, data.depth_image_view, *i];
+        // Matches the attachments of `create_render_pass`
+        let attachments = if data.msaa_samples == vk::SampleCountFlags::_1 {
+            vec![*i, data.depth_image_view]
+        } else {
+            vec![data.color_image_view, data.depth_image_view, *i]
+        };
+
         let create_info = vk::FramebufferCreateInfo::builder()
             .render_pass(data.render_pass)
-            .attachments(attachments)
+            .attachments(&attachments)
             .width(data.swapchain_extent.width)
             .height(data.swapchain_extent.height)
             .layers(1);
@@ -952,8 +1548,9 @@ unsafe fn create_command_pool(
 ) -> Result<()> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
 
+    // The frame command buffers are re-recorded every frame
     let info = vk::CommandPoolCreateInfo::builder()
-        .flags(vk::CommandPoolCreateFlags::empty())
+        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
         .queue_family_index(indices.graphics);
 
     data.command_pool = device.create_command_pool(&info, None)?;
@@ -961,6 +1558,7 @@ unsafe fn create_command_pool(
     Ok(())
 }
 
+// One per frame in flight, recorded again each frame for the acquired swapchain image
 unsafe fn create_command_buffers(
     device: &Device,
     data: &mut AppData
@@ -968,60 +1566,106 @@ unsafe fn create_command_buffers(
     let allocate_info = vk::CommandBufferAllocateInfo::builder()
         .command_pool(data.command_pool)
         .level(vk::CommandBufferLevel::PRIMARY)
-        .command_buffer_count(data.framebuffers.len() as u32);
+        .command_buffer_count(MAX_FRAMES_IN_FLIGHT as u32);
 
     data.command_buffers = device.allocate_command_buffers(&allocate_info)?;
 
-    for (i, command_buffer) in data.command_buffers.iter().enumerate() {
-        let inheritance = vk::CommandBufferInheritanceInfo::builder();
+    Ok(())
+}
 
-        let info = vk::CommandBufferBeginInfo::builder();
-        
-        device.begin_command_buffer(*command_buffer, &info)?;
+unsafe fn record_command_buffer(
+    device: &Device,
+    data: &AppData,
+    frame: usize,
+    image_index: usize
+) -> Result<()> {
+    let command_buffer = data.command_buffers[frame];
 
-        let render_area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::default())
-            .extent(data.swapchain_extent);
+    // Beginning implicitly resets the command buffer
+    let info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0]
-            }
-        };
+    device.begin_command_buffer(command_buffer, &info)?;
 
-        let depth_clear_value = vk::ClearValue {
-            depth_stencil: vk::ClearDepthStencilValue {
-                depth: 1.0,
-                stencil: 0
-            }
-        };
+    let render_area = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(data.swapchain_extent);
 
-        let clear_values = &[color_clear_value, depth_clear_value];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(data.render_pass)
-            .framebuffer(data.framebuffers[i])
-            .render_area(render_area)
-            .clear_values(clear_values);
-
-        device.cmd_begin_render_pass(*command_buffer, &info, vk::SubpassContents::INLINE);
-
-        device.cmd_bind_pipeline(*command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipeline);
-        device.cmd_bind_vertex_buffers(*command_buffer, 0, &[data.mesh.vertex_buffer], &[0]);
-        device.cmd_bind_index_buffer(*command_buffer, data.mesh.index_buffer, 0, vk::IndexType::UINT32);
-        device.cmd_bind_descriptor_sets(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
+    let color_clear_value = vk::ClearValue {
+        color: vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 1.0]
+        }
+    };
+
+    let depth_clear_value = vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue {
+            depth: 1.0,
+            stencil: 0
+        }
+    };
+
+    let clear_values = &[color_clear_value, depth_clear_value];
+    let info = vk::RenderPassBeginInfo::builder()
+        .render_pass(data.render_pass)
+        .framebuffer(data.framebuffers[image_index])
+        .render_area(render_area)
+        .clear_values(clear_values);
+
+    device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipeline);
+
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(data.swapchain_extent.width as f32)
+        .height(data.swapchain_extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+    device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+
+    device.cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        data.pipeline_layout,
+        0,
+        &[data.descriptor_sets[frame], data.scene.materials[0].descriptor_set, data.environment.descriptor_set],
+        &[],
+    );
+
+    for instance in data.scene.instances() {
+        let mesh = &data.scene.meshes[instance.mesh].mesh;
+        let model = instance.model.iter().flat_map(|f| f.to_ne_bytes()).collect::<Vec<_>>();
+
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[mesh.vertex_buffer], &[0]);
+        device.cmd_bind_index_buffer(command_buffer, mesh.index_buffer, 0, vk::IndexType::UINT32);
+        device.cmd_push_constants(
+            command_buffer,
             data.pipeline_layout,
+            data.reflection.push_constant_stages,
             0,
-            &[data.descriptor_sets[i]],
-            &[],
+            &model,
         );
-        device.cmd_draw_indexed(*command_buffer, data.mesh.indices.len() as u32, 1, 0, 0, 0);
-        device.cmd_end_render_pass(*command_buffer);
 
-        device.end_command_buffer(*command_buffer)?;
+        for (submesh, material) in data.scene.draws(&instance) {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                data.pipeline_layout,
+                1,
+                &[data.scene.materials[material].descriptor_set],
+                &[],
+            );
+            device.cmd_draw_indexed(command_buffer, submesh.index_count, 1, submesh.first_index, 0, 0);
+        }
     }
 
+    device.cmd_end_render_pass(command_buffer);
+
+    device.end_command_buffer(command_buffer)?;
+
     Ok(())
 }
 
@@ -1037,7 +1681,7 @@ unsafe fn create_sync_objects(
     let fence_info = vk::FenceCreateInfo::builder()
         .flags(vk::FenceCreateFlags::SIGNALED);
 
-    for _ in 0..MAX_FRAMES_IN_FLIGHT {
+    for _ in 0..data.frames_in_flight {
         data.image_available_semaphores.push(device.create_semaphore(&semaphore_info, None)?);
         data.render_finished_semaphores.push(device.create_semaphore(&semaphore_info, None)?);
 
@@ -1053,33 +1697,17 @@ unsafe fn create_sync_objects(
 // BUFFERS
 // ================================================================================================
 
-
-unsafe fn get_memory_type_index(
-    instance: &Instance,
-    data: &AppData,
-    properties: vk::MemoryPropertyFlags,
-    requirements: vk::MemoryRequirements
-) -> Result<u32> {
-    let memory = instance.get_physical_device_memory_properties(data.physical_device);
-    (0..memory.memory_type_count)
-        .find(|i| {
-            let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
-            let memory_type = memory.memory_types[*i as usize];
-            suitable && memory_type.property_flags.contains(properties)
-        })
-        .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
-}
-
+// Written by the CPU while older frames are still in flight, so never shared between frames
 unsafe fn create_uniform_buffers(
     instance: &Instance,
     device: &Device,
     data: &mut AppData
 ) -> Result<()> {
     data.uniform_buffers.clear();
-    data.uniform_buffers_memory.clear();
+    data.uniform_buffers_allocations.clear();
     
-    for _ in 0..data.swapchain_images.len() {
-        let (uniform_buffer, uniform_buffer_memory) = create_buffer(
+    for _ in 0..MAX_FRAMES_IN_FLIGHT {
+        let (uniform_buffer, uniform_buffer_allocation) = create_buffer(
             instance, device, data,
             size_of::<UniformBufferObject>() as u64,
             vk::BufferUsageFlags::UNIFORM_BUFFER,
@@ -1087,7 +1715,7 @@ unsafe fn create_uniform_buffers(
         )?;
 
         data.uniform_buffers.push(uniform_buffer);
-        data.uniform_buffers_memory.push(uniform_buffer_memory);
+        data.uniform_buffers_allocations.push(uniform_buffer_allocation);
     }
 
     Ok(())
@@ -1101,18 +1729,10 @@ unsafe fn create_descriptor_pool(
     device: &Device,
     data: &mut AppData
 ) -> Result<()> {
-    let ubo_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(data.swapchain_images.len() as u32);
-
-    let sampler_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(data.swapchain_images.len() as u32);
-
-    let pool_sizes = &[ubo_size, sampler_size];
+    let pool_sizes = data.reflection.pool_sizes(FRAME_SET, MAX_FRAMES_IN_FLIGHT as u32);
     let info = vk::DescriptorPoolCreateInfo::builder()
-        .pool_sizes(pool_sizes)
-        .max_sets(data.swapchain_images.len() as u32);
+        .pool_sizes(&pool_sizes)
+        .max_sets(MAX_FRAMES_IN_FLIGHT as u32);
 
     data.descriptor_pool = device.create_descriptor_pool(&info, None)?;
 
@@ -1123,14 +1743,14 @@ unsafe fn create_descriptor_sets(
     device: &Device,
     data: &mut AppData
 ) -> Result<()> {
-    let layouts = vec![data.descriptor_set_layout; data.swapchain_images.len()];
+    let layouts = vec![data.descriptor_set_layouts[FRAME_SET as usize]; MAX_FRAMES_IN_FLIGHT];
     let info = vk::DescriptorSetAllocateInfo::builder()
         .descriptor_pool(data.descriptor_pool)
         .set_layouts(&layouts);
 
     data.descriptor_sets = device.allocate_descriptor_sets(&info)?;
 
-    for i in 0..data.swapchain_images.len() {
+    for i in 0..MAX_FRAMES_IN_FLIGHT {
         let info = vk::DescriptorBufferInfo::builder()
             .buffer(data.uniform_buffers[i])
             .offset(0)
@@ -1144,64 +1764,14 @@ unsafe fn create_descriptor_sets(
             .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
             .buffer_info(buffer_info);
 
-        let info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(data.textures[0].texture.image_view)
-            .sampler(data.texture_sampler);
-        let image_info = &[info];
-
-        // let image_info: &[vk::DescriptorImageInfo] = data.textures.iter().map(|t| {
-        //     vk::DescriptorImageInfo::builder()
-        //         .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-        //         .image_view(t.image_view)
-        //         .sampler(data.texture_sampler)
-        // }).collect::<Vec<vk::DescriptorImageInfo>>().try_into()?;
-
-        let sampler_write = vk::WriteDescriptorSet::builder()
-            .dst_set(data.descriptor_sets[i])
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .image_info(image_info);
-
         device.update_descriptor_sets(
-            &[ubo_write, sampler_write], 
+            &[ubo_write], 
             &[] as &[vk::CopyDescriptorSet]);
     }
 
     Ok(())
 }
 
-// ================================================================================================
-// TEXTURES
-// ================================================================================================
-
-unsafe fn create_texture_sampler(
-    device: &Device,
-    data: &mut AppData
-) -> Result<()> {
-    let info = vk::SamplerCreateInfo::builder()
-        .mag_filter(vk::Filter::LINEAR)
-        .min_filter(vk::Filter::LINEAR)
-        .address_mode_u(vk::SamplerAddressMode::REPEAT)
-        .address_mode_v(vk::SamplerAddressMode::REPEAT)
-        .address_mode_w(vk::SamplerAddressMode::REPEAT)
-        .anisotropy_enable(true)
-        .max_anisotropy(16.0)
-        .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
-        .unnormalized_coordinates(false)
-        .compare_enable(false)
-        .compare_op(vk::CompareOp::ALWAYS)
-        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-        .min_lod(0.0)
-        .max_lod(data.mip_levels as f32)
-        .mip_lod_bias(0.0);
-
-    data.texture_sampler = device.create_sampler(&info, None)?;
-
-    Ok(())
-}
-
 // ================================================================================================
 // DEPTH
 // ================================================================================================
@@ -1213,10 +1783,11 @@ unsafe fn create_depth_objects(
 ) -> Result<()> {
     let format = get_depth_format(instance, data)?;
 
-    let (depth_image, depth_image_memory) = create_image(
-        instance, device, data,
+    let (depth_image, depth_image_allocation) = create_image(
+        device, data,
         data.swapchain_extent.width, data.swapchain_extent.height,
-        1,
+        1, 1,
+        vk::ImageCreateFlags::empty(),
         data.msaa_samples,
         format,
         vk::ImageTiling::OPTIMAL,
@@ -1225,8 +1796,8 @@ unsafe fn create_depth_objects(
     )?;
 
     data.depth_image = depth_image;
-    data.depth_image_memory = depth_image_memory;
-    data.depth_image_view = create_image_view(device, data.depth_image, format, vk::ImageAspectFlags::DEPTH, 1)?;
+    data.depth_image_allocation = depth_image_allocation;
+    data.depth_image_view = create_image_view(device, data.depth_image, vk::ImageViewType::_2D, format, vk::ImageAspectFlags::DEPTH, 1, 1)?;
 
     Ok(())
 }
@@ -1266,15 +1837,24 @@ unsafe fn get_depth_format(
 // COLOR OBJECTS
 // ================================================================================================
 
+// Multisampled target resolved into the swapchain image, not needed without MSAA
 unsafe fn create_color_objects(
     instance: &Instance,
     device: &Device,
     data: &mut AppData
 ) -> Result<()> {
-    let (color_image, color_image_memory) = create_image(
-        instance, device, data,
+    if data.msaa_samples == vk::SampleCountFlags::_1 {
+        data.color_image = vk::Image::null();
+        data.color_image_allocation = Allocation::default();
+        data.color_image_view = vk::ImageView::null();
+        return Ok(());
+    }
+
+    let (color_image, color_image_allocation) = create_image(
+        device, data,
         data.swapchain_extent.width, data.swapchain_extent.height,
-        1,
+        1, 1,
+        vk::ImageCreateFlags::empty(),
         data.msaa_samples,
         data.swapchain_format,
         vk::ImageTiling::OPTIMAL,
@@ -1283,27 +1863,16 @@ unsafe fn create_color_objects(
     )?;
 
     data.color_image = color_image;
-    data.color_image_memory = color_image_memory;
+    data.color_image_allocation = color_image_allocation;
 
     data.color_image_view = create_image_view(
         device,
         data.color_image,
+        vk::ImageViewType::_2D,
         data.swapchain_format,
         vk::ImageAspectFlags::COLOR,
-        1
+        1, 1
     )?;
 
     Ok(())
 }
-
-// ================================================================================================
-// TEXTURES
-// ================================================================================================
-
-struct Textures {
-    env_cube: Texture,
-    empty: Texture,
-    lut_brdf: Texture,
-    irradiance_cube: Texture,
-    prefiltered_cube: Texture
-}
\ No newline at end of file
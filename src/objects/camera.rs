@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+
+use nalgebra_glm as glm;
+use winit::event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CameraMode {
+    // Dragging rotates around the focus point, scrolling changes the distance to it
+    #[default]
+    Orbit,
+    // WASD moves, Q/E go down/up, dragging looks around
+    Fly,
+    // Dragging slides the view sideways, scrolling dollies towards the focus point
+    Pan
+}
+
+impl CameraMode {
+    fn next(self) -> Self {
+        match self {
+            CameraMode::Orbit => CameraMode::Fly,
+            CameraMode::Fly => CameraMode::Pan,
+            CameraMode::Pan => CameraMode::Orbit
+        }
+    }
+}
+
+// Z-up camera described by its eye, a yaw/pitch direction and the distance to the focus point
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub mode: CameraMode,
+    pub position: glm::Vec3,
+    // Radians, yaw is measured from +X towards +Y and pitch from the XY plane
+    pub yaw: f32,
+    pub pitch: f32,
+    pub distance: f32,
+
+    // Vertical field of view in degrees
+    pub fov: f32,
+    pub near: f32,
+    pub far: f32,
+
+    // World units per second, doubled while shift is held
+    pub move_speed: f32,
+    // Radians per pixel of mouse motion
+    pub look_sensitivity: f32,
+    // Fraction of the distance covered per scroll line
+    pub zoom_speed: f32,
+    // Fraction of the distance covered per pixel of mouse motion
+    pub pan_speed: f32,
+
+    keys: HashSet<VirtualKeyCode>,
+    dragging: bool,
+    mouse_delta: glm::Vec2,
+    scroll: f32
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::look_at(glm::vec3(0.0, -12.0, 5.0), glm::vec3(0.0, 0.0, 1.0))
+    }
+}
+
+impl Camera {
+    pub fn look_at(position: glm::Vec3, target: glm::Vec3) -> Self {
+        let direction = target - position;
+        let distance = direction.norm().max(f32::EPSILON);
+
+        Camera {
+            mode: CameraMode::default(),
+            position,
+            yaw: direction.y.atan2(direction.x),
+            pitch: (direction.z / distance).clamp(-1.0, 1.0).asin(),
+            distance,
+            fov: 45.0,
+            near: 0.1,
+            far: 100.0,
+            move_speed: 4.0,
+            look_sensitivity: 0.005,
+            zoom_speed: 0.1,
+            pan_speed: 0.002,
+            keys: HashSet::new(),
+            dragging: false,
+            mouse_delta: glm::Vec2::zeros(),
+            scroll: 0.0
+        }
+    }
+
+    pub fn forward(&self) -> glm::Vec3 {
+        glm::vec3(self.pitch.cos() * self.yaw.cos(), self.pitch.cos() * self.yaw.sin(), self.pitch.sin())
+    }
+
+    pub fn right(&self) -> glm::Vec3 {
+        glm::vec3(self.yaw.sin(), -self.yaw.cos(), 0.0)
+    }
+
+    pub fn up(&self) -> glm::Vec3 {
+        self.right().cross(&self.forward())
+    }
+
+    pub fn target(&self) -> glm::Vec3 {
+        self.position + self.forward() * self.distance
+    }
+
+    pub fn view(&self) -> glm::Mat4 {
+        glm::look_at(&self.position, &self.target(), &glm::vec3(0.0, 0.0, 1.0))
+    }
+
+    // Vulkan clip space: depth in [0, 1] and Y pointing down
+    pub fn projection(&self, aspect: f32) -> glm::Mat4 {
+        let mut proj = glm::perspective_rh_zo(aspect, glm::radians(&glm::vec1(self.fov))[0], self.near, self.far);
+        proj[(1, 1)] *= -1.0;
+        proj
+    }
+
+    pub fn is_held(&self, key: VirtualKeyCode) -> bool {
+        self.keys.contains(&key)
+    }
+
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => {
+                let Some(key) = input.virtual_keycode else { return };
+                match input.state {
+                    ElementState::Pressed if key == VirtualKeyCode::Tab && !self.keys.contains(&key) => {
+                        self.mode = self.mode.next();
+                        self.keys.insert(key);
+                    },
+                    ElementState::Pressed => { self.keys.insert(key); },
+                    ElementState::Released => { self.keys.remove(&key); }
+                }
+            },
+            WindowEvent::MouseInput { button: MouseButton::Left, state, .. } => {
+                self.dragging = *state == ElementState::Pressed;
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.scroll += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(p) => p.y as f32 / 40.0
+                };
+            },
+            WindowEvent::Focused(false) => {
+                self.keys.clear();
+                self.dragging = false;
+            },
+            _ => {}
+        }
+    }
+
+    // Raw mouse motion is not affected by cursor acceleration or the window border
+    pub fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (x, y) } = event {
+            if self.dragging {
+                self.mouse_delta += glm::vec2(*x as f32, *y as f32);
+            }
+        }
+    }
+
+    // Applies the input gathered since the previous frame, `dt` in seconds
+    pub fn update(&mut self, dt: f32) {
+        let drag = std::mem::replace(&mut self.mouse_delta, glm::Vec2::zeros());
+        let look = drag * self.look_sensitivity;
+        let scroll = std::mem::take(&mut self.scroll);
+
+        match self.mode {
+            CameraMode::Orbit => {
+                let target = self.target();
+                self.rotate(-look.x, -look.y);
+                self.distance = (self.distance * (1.0 - scroll * self.zoom_speed)).max(self.near);
+                self.position = target - self.forward() * self.distance;
+            },
+            CameraMode::Fly => {
+                self.rotate(-look.x, -look.y);
+
+                let key = |k| self.keys.contains(&k) as i32 as f32;
+                let direction = self.forward() * (key(VirtualKeyCode::W) - key(VirtualKeyCode::S))
+                    + self.right() * (key(VirtualKeyCode::D) - key(VirtualKeyCode::A))
+                    + glm::vec3(0.0, 0.0, 1.0) * (key(VirtualKeyCode::E) - key(VirtualKeyCode::Q));
+
+                let boost = if self.keys.contains(&VirtualKeyCode::LShift) { 2.0 } else { 1.0 };
+                if let Some(direction) = direction.try_normalize(f32::EPSILON) {
+                    self.position += direction * self.move_speed * boost * dt;
+                }
+            },
+            CameraMode::Pan => {
+                // Scaled by the distance so panning feels the same close up and far away
+                let scale = self.distance * self.pan_speed;
+                self.position += (self.up() * drag.y - self.right() * drag.x) * scale;
+
+                let step = (self.distance * scroll * self.zoom_speed).min(self.distance - self.near);
+                self.position += self.forward() * step;
+                self.distance -= step;
+            }
+        }
+    }
+
+    fn rotate(&mut self, yaw: f32, pitch: f32) {
+        let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).clamp(-limit, limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32::consts::FRAC_PI_2;
+
+    use winit::event::{DeviceId, KeyboardInput, ModifiersState};
+
+    fn assert_near(a: glm::Vec3, b: glm::Vec3) {
+        assert!((a - b).norm() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[allow(deprecated)]
+    fn key(camera: &mut Camera, key: VirtualKeyCode, state: ElementState) {
+        let input = KeyboardInput { scancode: 0, state, virtual_keycode: Some(key), modifiers: ModifiersState::empty() };
+        let device_id = unsafe { DeviceId::dummy() };
+        camera.handle_window_event(&WindowEvent::KeyboardInput { device_id, input, is_synthetic: false });
+    }
+
+    fn drag(camera: &mut Camera, x: f64, y: f64) {
+        camera.dragging = true;
+        camera.handle_device_event(&DeviceEvent::MouseMotion { delta: (x, y) });
+    }
+
+    #[test]
+    fn look_at_round_trips_the_target() {
+        for (position, target) in [
+            (glm::vec3(0.0, -12.0, 5.0), glm::vec3(0.0, 0.0, 1.0)),
+            (glm::vec3(3.0, 2.0, -1.0), glm::vec3(-4.0, 7.0, 2.5)),
+            (glm::vec3(1.0, 1.0, 1.0), glm::vec3(1.5, 1.0, 1.0))
+        ] {
+            let camera = Camera::look_at(position, target);
+            assert_near(camera.position, position);
+            assert_near(camera.target(), target);
+            assert!((camera.distance - (target - position).norm()).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn orbit_keeps_the_target() {
+        let mut camera = Camera::default();
+        let target = camera.target();
+
+        drag(&mut camera, 120.0, -40.0);
+        camera.update(0.016);
+        assert_near(camera.target(), target);
+        assert!((camera.yaw - (FRAC_PI_2 - 120.0 * camera.look_sensitivity)).abs() < 1e-5);
+
+        let distance = camera.distance;
+        camera.scroll = 2.0;
+        camera.update(0.016);
+        assert_near(camera.target(), target);
+        assert!((camera.distance - distance * (1.0 - 2.0 * camera.zoom_speed)).abs() < 1e-4);
+
+        // Zooming in stops at the near plane
+        camera.scroll = 1000.0;
+        camera.update(0.016);
+        assert_eq!(camera.distance, camera.near);
+        assert_near(camera.target(), target);
+    }
+
+    #[test]
+    fn pitch_is_clamped_short_of_the_poles() {
+        let limit = FRAC_PI_2 - 0.01;
+        let mut camera = Camera::default();
+
+        drag(&mut camera, 0.0, -1e6);
+        camera.update(0.016);
+        assert_eq!(camera.pitch, limit);
+
+        drag(&mut camera, 0.0, 1e6);
+        camera.update(0.016);
+        assert_eq!(camera.pitch, -limit);
+    }
+
+    #[test]
+    fn fly_moves_at_move_speed() {
+        let mut camera = Camera { mode: CameraMode::Fly, ..Camera::default() };
+        let start = camera.position;
+
+        key(&mut camera, VirtualKeyCode::W, ElementState::Pressed);
+        camera.update(0.5);
+        assert_near(camera.position, start + camera.forward() * camera.move_speed * 0.5);
+
+        let start = camera.position;
+        key(&mut camera, VirtualKeyCode::LShift, ElementState::Pressed);
+        camera.update(0.5);
+        assert_near(camera.position, start + camera.forward() * camera.move_speed);
+
+        // Diagonals are not faster
+        let start = camera.position;
+        key(&mut camera, VirtualKeyCode::LShift, ElementState::Released);
+        key(&mut camera, VirtualKeyCode::D, ElementState::Pressed);
+        camera.update(0.5);
+        assert!(((camera.position - start).norm() - camera.move_speed * 0.5).abs() < 1e-4);
+
+        let start = camera.position;
+        key(&mut camera, VirtualKeyCode::W, ElementState::Released);
+        key(&mut camera, VirtualKeyCode::D, ElementState::Released);
+        camera.update(0.5);
+        assert_near(camera.position, start);
+    }
+
+    #[test]
+    fn pan_never_passes_the_near_plane() {
+        let mut camera = Camera { mode: CameraMode::Pan, ..Camera::default() };
+        let target = camera.target();
+
+        camera.scroll = 1000.0;
+        camera.update(0.016);
+        assert!((camera.distance - camera.near).abs() < 1e-5);
+        assert_near(camera.target(), target);
+
+        camera.scroll = 1000.0;
+        camera.update(0.016);
+        assert!((camera.distance - camera.near).abs() < 1e-5);
+
+        // Sliding sideways moves the eye and the target together
+        let (yaw, pitch, distance) = (camera.yaw, camera.pitch, camera.distance);
+        drag(&mut camera, 50.0, 0.0);
+        camera.update(0.016);
+        assert_eq!((camera.yaw, camera.pitch, camera.distance), (yaw, pitch, distance));
+        assert_near(camera.target() - target, -camera.right() * 50.0 * distance * camera.pan_speed);
+    }
+
+    #[test]
+    fn tab_cycles_modes_once_per_press() {
+        let mut camera = Camera::default();
+        assert_eq!(camera.mode, CameraMode::Orbit);
+
+        // Key repeat sends more presses without a release
+        key(&mut camera, VirtualKeyCode::Tab, ElementState::Pressed);
+        key(&mut camera, VirtualKeyCode::Tab, ElementState::Pressed);
+        assert_eq!(camera.mode, CameraMode::Fly);
+
+        key(&mut camera, VirtualKeyCode::Tab, ElementState::Released);
+        key(&mut camera, VirtualKeyCode::Tab, ElementState::Pressed);
+        assert_eq!(camera.mode, CameraMode::Pan);
+
+        key(&mut camera, VirtualKeyCode::Tab, ElementState::Released);
+        key(&mut camera, VirtualKeyCode::Tab, ElementState::Pressed);
+        assert_eq!(camera.mode, CameraMode::Orbit);
+    }
+}
@@ -0,0 +1,452 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::mem::size_of;
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::Result;
+use half::f16;
+use image::codecs::hdr::HdrDecoder;
+use vulkanalia::prelude::v1_0::*;
+
+use crate::{AppData, ENVIRONMENT_SET, create_shader_module};
+use crate::graphics::shader_compiler::compile_shader;
+use crate::graphics::shader_reflection::ShaderReflection;
+use crate::shared_commands::*;
+use crate::shared_memory::*;
+
+use super::texture::Texture;
+
+const FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const ENVIRONMENT_SIZE: u32 = 512;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+const PREFILTER_SAMPLE_COUNT: u32 = 1024;
+const BRDF_LUT_SIZE: u32 = 512;
+const WORKGROUP_SIZE: u32 = 8;
+
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    pub env_cube: Texture,
+    pub irradiance_cube: Texture,
+    pub prefiltered_cube: Texture,
+    pub lut_brdf: Texture,
+
+    descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet
+}
+
+impl Environment {
+    // Bakes the image-based lighting inputs from an equirectangular HDR panorama
+    pub unsafe fn create(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        filename: &str
+    ) -> Result<Self> {
+        let equirectangular = load_equirectangular(instance, device, data, filename)?;
+
+        // The prefilter pass reads sparse high-roughness samples from lower environment mips,
+        // without blits it falls back to aliased reads of mip 0
+        let env_mip_levels = if supports_linear_blit(instance, data, FORMAT) { ENVIRONMENT_SIZE.ilog2() + 1 } else { 1 };
+        let prefiltered_mip_levels = PREFILTERED_SIZE.ilog2() + 1;
+        let blit_usage = vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST;
+        let (env_cube, env_views) = create_storage_texture(instance, device, data, ENVIRONMENT_SIZE, env_mip_levels, 6, blit_usage)?;
+        let no_usage = vk::ImageUsageFlags::empty();
+        let (irradiance_cube, irradiance_views) = create_storage_texture(instance, device, data, IRRADIANCE_SIZE, 1, 6, no_usage)?;
+        let (prefiltered_cube, prefiltered_views) = create_storage_texture(instance, device, data, PREFILTERED_SIZE, prefiltered_mip_levels, 6, no_usage)?;
+        let (lut_brdf, lut_views) = create_storage_texture(instance, device, data, BRDF_LUT_SIZE, 1, 1, no_usage)?;
+
+        // Precomputation pipelines share a layout: binding 0 is the input, binding 1 the output
+        let shaders = ["equirect_to_cube", "irradiance", "prefilter", "brdf_lut"]
+            .iter()
+            .map(|n| compile_shader(format!("shaders/{}.comp", n), &[]))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let reflection = ShaderReflection::new(&shaders.iter().collect::<Vec<_>>())?;
+        let compute_set_layout = reflection.create_set_layouts(device)?[0];
+
+        let set_layouts = &[compute_set_layout];
+        let push_constant_ranges = reflection.push_constant_ranges();
+        let info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+
+        let compute_pipeline_layout = device.create_pipeline_layout(&info, None)?;
+
+        let equirect_pipeline = create_compute_pipeline(device, data, compute_pipeline_layout, &shaders[0].code)?;
+        let irradiance_pipeline = create_compute_pipeline(device, data, compute_pipeline_layout, &shaders[1].code)?;
+        let prefilter_pipeline = create_compute_pipeline(device, data, compute_pipeline_layout, &shaders[2].code)?;
+        let brdf_pipeline = create_compute_pipeline(device, data, compute_pipeline_layout, &shaders[3].code)?;
+
+        // One set per dispatch: equirect, irradiance, BRDF LUT and one per prefiltered mip
+        let set_count = 3 + prefiltered_mip_levels;
+
+        let pool_sizes = reflection.pool_sizes(0, set_count);
+        let info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(set_count);
+
+        let compute_pool = device.create_descriptor_pool(&info, None)?;
+
+        let layouts = vec![compute_set_layout; set_count as usize];
+        let info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(compute_pool)
+            .set_layouts(&layouts);
+
+        let sets = device.allocate_descriptor_sets(&info)?;
+
+        let inputs = [Some(&equirectangular), Some(&env_cube), None]
+            .into_iter()
+            .chain(prefiltered_views.iter().map(|_| Some(&env_cube)));
+
+        let outputs = [env_views[0], irradiance_views[0], lut_views[0]]
+            .into_iter()
+            .chain(prefiltered_views.iter().cloned());
+
+        for ((set, input), output) in sets.iter().zip(inputs).zip(outputs) {
+            let output_info = &[vk::DescriptorImageInfo::builder()
+                .image_view(output)
+                .image_layout(vk::ImageLayout::GENERAL)
+                .build()];
+
+            let output_write = vk::WriteDescriptorSet::builder()
+                .dst_set(*set)
+                .dst_binding(1)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(output_info);
+
+            match input {
+                Some(input) => {
+                    let input_info = &[input.descriptor];
+                    let input_write = vk::WriteDescriptorSet::builder()
+                        .dst_set(*set)
+                        .dst_binding(0)
+                        .dst_array_element(0)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(input_info);
+
+                    device.update_descriptor_sets(&[input_write, output_write], &[] as &[vk::CopyDescriptorSet]);
+                },
+                None => device.update_descriptor_sets(&[output_write], &[] as &[vk::CopyDescriptorSet])
+            }
+        }
+
+        let command_buffer = begin_single_time_commands(device, data)?;
+
+        let barrier = |texture: &Texture, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            let subresource = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .level_count(vk::REMAINING_MIP_LEVELS)
+                .base_array_layer(0)
+                .layer_count(vk::REMAINING_ARRAY_LAYERS);
+
+            vk::ImageMemoryBarrier::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(texture.image)
+                .subresource_range(subresource)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .build()
+        };
+
+        let outputs = [&env_cube, &irradiance_cube, &prefiltered_cube, &lut_brdf].map(|t| {
+            barrier(t, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL, vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE)
+        });
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &outputs
+        );
+
+        let dispatch = |pipeline, set, size: u32, layers, push_constants: &[u8]| {
+            let groups = size.div_ceil(WORKGROUP_SIZE);
+
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, compute_pipeline_layout, 0, &[set], &[]);
+            if !push_constants.is_empty() {
+                device.cmd_push_constants(command_buffer, compute_pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, push_constants);
+            }
+            device.cmd_dispatch(command_buffer, groups, groups, layers);
+        };
+
+        dispatch(equirect_pipeline, sets[0], ENVIRONMENT_SIZE, 6, &[]);
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier(
+                &env_cube,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE
+            )]
+        );
+
+        cmd_generate_mipmaps(
+            device, command_buffer,
+            env_cube.image,
+            ENVIRONMENT_SIZE, ENVIRONMENT_SIZE,
+            env_mip_levels, 6,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER
+        );
+
+        dispatch(irradiance_pipeline, sets[1], IRRADIANCE_SIZE, 6, &[]);
+        dispatch(brdf_pipeline, sets[2], BRDF_LUT_SIZE, 1, &[]);
+
+        for mip in 0..prefiltered_mip_levels {
+            let roughness = mip as f32 / (prefiltered_mip_levels - 1) as f32;
+            let push_constants = [roughness.to_ne_bytes(), PREFILTER_SAMPLE_COUNT.to_ne_bytes()].concat();
+
+            dispatch(prefilter_pipeline, sets[3 + mip as usize], PREFILTERED_SIZE >> mip, 6, &push_constants);
+        }
+
+        let outputs = [&irradiance_cube, &prefiltered_cube, &lut_brdf].map(|t| {
+            barrier(t, vk::ImageLayout::GENERAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::SHADER_WRITE, vk::AccessFlags::SHADER_READ)
+        });
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &outputs
+        );
+
+        end_single_time_commands(device, data, command_buffer)?;
+
+        // Precomputation resources are no longer needed
+        [equirect_pipeline, irradiance_pipeline, prefilter_pipeline, brdf_pipeline]
+            .iter()
+            .for_each(|p| device.destroy_pipeline(*p, None));
+
+        env_views.iter()
+            .chain(irradiance_views.iter())
+            .chain(prefiltered_views.iter())
+            .chain(lut_views.iter())
+            .for_each(|v| device.destroy_image_view(*v, None));
+
+        device.destroy_descriptor_pool(compute_pool, None);
+        device.destroy_pipeline_layout(compute_pipeline_layout, None);
+        device.destroy_descriptor_set_layout(compute_set_layout, None);
+        equirectangular.destroy(device, data);
+
+        // Set 2: binding 0 irradiance, binding 1 prefiltered, binding 2 BRDF LUT
+        let pool_sizes = data.reflection.pool_sizes(ENVIRONMENT_SET, 1);
+        let info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1);
+
+        let descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+        let layouts = &[data.descriptor_set_layouts[ENVIRONMENT_SET as usize]];
+        let info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts);
+
+        let descriptor_set = device.allocate_descriptor_sets(&info)?[0];
+
+        let image_infos = [&irradiance_cube, &prefiltered_cube, &lut_brdf].map(|t| [t.descriptor]);
+        let writes = image_infos.iter().enumerate().map(|(i, image_info)| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(i as u32)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(image_info)
+        }).collect::<Vec<_>>();
+
+        device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+
+        Ok(Self {
+            env_cube,
+            irradiance_cube,
+            prefiltered_cube,
+            lut_brdf,
+            descriptor_pool,
+            descriptor_set
+        })
+    }
+
+    pub unsafe fn destroy(&self, device: &Device, data: &AppData) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+
+        [&self.env_cube, &self.irradiance_cube, &self.prefiltered_cube, &self.lut_brdf]
+            .iter()
+            .for_each(|t| t.destroy(device, data));
+    }
+}
+
+unsafe fn load_equirectangular(
+    instance: &Instance,
+    device: &Device,
+    data: &AppData,
+    filename: &str
+) -> Result<Texture> {
+    let decoder = HdrDecoder::new(BufReader::new(File::open(filename)?))?;
+    let metadata = decoder.metadata();
+    let (width, height) = (metadata.width, metadata.height);
+
+    let pixels = decoder
+        .read_image_hdr()?
+        .iter()
+        .flat_map(|p| [p[0], p[1], p[2], 1.0])
+        .map(f16::from_f32)
+        .collect::<Vec<_>>();
+
+    let size = (size_of::<f16>() * pixels.len()) as u64;
+
+    let (staging_buffer, staging_buffer_allocation) = create_buffer(
+        instance, device, data,
+        size,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
+    )?;
+
+    let memory = staging_buffer_allocation.mapped_ptr()?;
+    memcpy(pixels.as_ptr(), memory.cast(), pixels.len());
+
+    let (image, image_allocation) = create_image(
+        device, data,
+        width, height,
+        1, 1,
+        vk::ImageCreateFlags::empty(),
+        vk::SampleCountFlags::_1,
+        FORMAT,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL
+    )?;
+
+    transition_image_layout(device, data, image, FORMAT, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, 1)?;
+    copy_buffer_to_image(device, data, staging_buffer, image, width, height)?;
+    transition_image_layout(device, data, image, FORMAT, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, 1)?;
+
+    device.destroy_buffer(staging_buffer, None);
+    data.allocator.free(device, &staging_buffer_allocation);
+
+    let image_view = create_image_view(device, image, vk::ImageViewType::_2D, FORMAT, vk::ImageAspectFlags::COLOR, 1, 1)?;
+    // The panorama wraps around horizontally, clamping would leave a seam where u = 0 meets u = 1
+    let sampler = create_sampler(device, 1, vk::SamplerAddressMode::REPEAT)?;
+
+    Ok(Texture::new(
+        image, image_allocation, image_view,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        width, height, 1, 1,
+        Some(sampler)
+    ))
+}
+
+// Returns the sampled texture plus one 2D array view per mip level for the storage writes,
+// `usage` is added to STORAGE and SAMPLED
+unsafe fn create_storage_texture(
+    instance: &Instance,
+    device: &Device,
+    data: &AppData,
+    size: u32,
+    mip_levels: u32,
+    layer_count: u32,
+    usage: vk::ImageUsageFlags
+) -> Result<(Texture, Vec<vk::ImageView>)> {
+    let flags = if layer_count == 6 { vk::ImageCreateFlags::CUBE_COMPATIBLE } else { vk::ImageCreateFlags::empty() };
+
+    let (image, image_allocation) = create_image(
+        device, data,
+        size, size,
+        mip_levels, layer_count,
+        flags,
+        vk::SampleCountFlags::_1,
+        FORMAT,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | usage,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL
+    )?;
+
+    let storage_view = |mip_level| {
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(mip_level)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(layer_count);
+
+        let info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::_2D_ARRAY)
+            .format(FORMAT)
+            .subresource_range(subresource_range);
+
+        device.create_image_view(&info, None)
+    };
+
+    let sampled_view_type = if layer_count == 6 { vk::ImageViewType::CUBE } else { vk::ImageViewType::_2D };
+    let image_view = create_image_view(device, image, sampled_view_type, FORMAT, vk::ImageAspectFlags::COLOR, mip_levels, layer_count)?;
+    let storage_views = (0..mip_levels)
+        .map(storage_view)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let sampler = create_sampler(device, mip_levels, vk::SamplerAddressMode::CLAMP_TO_EDGE)?;
+    let texture = Texture::new(
+        image, image_allocation, image_view,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        size, size, mip_levels, layer_count,
+        Some(sampler)
+    );
+
+    Ok((texture, storage_views))
+}
+
+unsafe fn create_sampler(device: &Device, mip_levels: u32, address_mode_u: vk::SamplerAddressMode) -> Result<vk::Sampler> {
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(vk::Filter::LINEAR)
+        .min_filter(vk::Filter::LINEAR)
+        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+        .address_mode_u(address_mode_u)
+        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .min_lod(0.0)
+        .max_lod(mip_levels as f32)
+        .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE);
+
+    Ok(device.create_sampler(&info, None)?)
+}
+
+unsafe fn create_compute_pipeline(
+    device: &Device,
+    data: &AppData,
+    layout: vk::PipelineLayout,
+    bytecode: &[u32]
+) -> Result<vk::Pipeline> {
+    let module = create_shader_module(device, bytecode)?;
+
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(b"main\0");
+
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage)
+        .layout(layout);
+
+    let pipeline = device.create_compute_pipelines(data.pipeline_cache, &[info], None)?.0;
+    device.destroy_shader_module(module, None);
+
+    Ok(pipeline)
+}
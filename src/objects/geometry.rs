@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use nalgebra_glm as glm;
+use serde::Deserialize;
+
+use super::vertex::Vertex;
+
+// Helpers working on unindexed triangle lists: three consecutive vertices per face
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum NormalGeneration {
+    // Averages the area-weighted normals of every face sharing a position, across UV seams too
+    #[default]
+    Smooth,
+    Flat
+}
+
+pub fn generate_normals(corners: &mut [Vertex], mode: NormalGeneration) {
+    let face_normal = |t: &[Vertex]| (t[1].pos - t[0].pos).cross(&(t[2].pos - t[0].pos));
+    let normalize = |n: glm::Vec3| n.try_normalize(f32::EPSILON).unwrap_or(glm::vec3(0.0, 0.0, 1.0));
+    let key = |v: &Vertex| [v.pos[0].to_bits(), v.pos[1].to_bits(), v.pos[2].to_bits()];
+
+    match mode {
+        NormalGeneration::Flat => {
+            for triangle in corners.chunks_exact_mut(3) {
+                let normal = normalize(face_normal(triangle));
+                triangle.iter_mut().for_each(|v| v.normal = normal);
+            }
+        },
+        NormalGeneration::Smooth => {
+            let mut sums = HashMap::new();
+            for triangle in corners.chunks_exact(3) {
+                let normal = face_normal(triangle);
+                for vertex in triangle {
+                    *sums.entry(key(vertex)).or_insert_with(glm::Vec3::zeros) += normal;
+                }
+            }
+
+            corners.iter_mut().for_each(|v| v.normal = normalize(sums[&key(v)]));
+        }
+    }
+}
+
+struct Corners<'a>(&'a mut [Vertex]);
+
+impl mikktspace::Geometry for Corners<'_> {
+    fn num_faces(&self) -> usize {
+        self.0.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.0[3 * face + vert].pos.into()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.0[3 * face + vert].normal.into()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.0[3 * face + vert].tex_coord.into()
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.0[3 * face + vert].tangent = glm::Vec4::from(tangent);
+    }
+}
+
+// MikkTSpace tangents with the bitangent sign in w, faces it cannot handle keep +X
+pub fn generate_tangents(corners: &mut [Vertex]) {
+    corners.iter_mut().for_each(|v| v.tangent = glm::vec4(1.0, 0.0, 0.0, 1.0));
+    mikktspace::generate_tangents(&mut Corners(corners));
+}
+
+// Merges identical corners back into an indexed vertex list, face order is preserved
+pub fn weld(corners: &[Vertex]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique_vertices = HashMap::new();
+
+    let mut vertices = vec![];
+    let mut indices = Vec::with_capacity(corners.len());
+
+    for vertex in corners {
+        if let Some(index) = unique_vertices.get(vertex) {
+            indices.push(*index as u32);
+        } else {
+            let index = vertices.len();
+            unique_vertices.insert(*vertex, index);
+            vertices.push(*vertex);
+            indices.push(index as u32)
+        }
+    }
+
+    (vertices, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corner(pos: glm::Vec3, tex_coord: glm::Vec2) -> Vertex {
+        Vertex::new(pos, glm::vec3(1.0, 1.0, 1.0), tex_coord, glm::vec3(0.0, 0.0, 1.0), glm::vec4(1.0, 0.0, 0.0, 1.0))
+    }
+
+    // Unit quad in the XY plane, `flip_v` makes V run down the quad as in glTF
+    fn quad(flip_v: bool) -> Vec<Vertex> {
+        let quad = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+            .map(|(x, y)| corner(glm::vec3(x, y, 0.0), glm::vec2(x, if flip_v { 1.0 - y } else { y })));
+        [0, 1, 2, 0, 2, 3].iter().map(|i| quad[*i]).collect()
+    }
+
+    // Faces as (normal, U axis, V axis) with U x V = normal, so each face winds counter-clockwise
+    const CUBE_FACES: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+        ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0])
+    ];
+
+    fn cube() -> Vec<Vertex> {
+        CUBE_FACES.iter().flat_map(|(n, u, v)| {
+            let (n, u, v) = (glm::Vec3::from(*n), glm::Vec3::from(*u), glm::Vec3::from(*v));
+            let face = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                .map(|(su, sv)| corner((n + u * su + v * sv) * 0.5, glm::vec2((su + 1.0) / 2.0, (sv + 1.0) / 2.0)));
+            [0, 1, 2, 0, 2, 3].map(|i| face[i])
+        }).collect()
+    }
+
+    fn assert_unit(v: &glm::Vec3) {
+        assert!((v.norm() - 1.0).abs() < 1e-5, "{:?} is not unit length", v);
+    }
+
+    #[test]
+    fn flat_normals_follow_winding() {
+        let mut corners = quad(false);
+        generate_normals(&mut corners, NormalGeneration::Flat);
+        assert!(corners.iter().all(|v| v.normal == glm::vec3(0.0, 0.0, 1.0)));
+
+        let mut corners = cube();
+        generate_normals(&mut corners, NormalGeneration::Flat);
+        for (face, triangles) in corners.chunks_exact(6).enumerate() {
+            let expected = glm::Vec3::from(CUBE_FACES[face].0);
+            assert!(triangles.iter().all(|v| v.normal == expected), "face {}", face);
+        }
+    }
+
+    #[test]
+    fn smooth_normals_are_shared_and_outward() {
+        let mut corners = cube();
+        generate_normals(&mut corners, NormalGeneration::Smooth);
+
+        for vertex in &corners {
+            assert_unit(&vertex.normal);
+            // Corners at the same position agree, whatever face they belong to
+            assert!(corners.iter().filter(|c| c.pos == vertex.pos).all(|c| c.normal == vertex.normal));
+            assert!(vertex.normal.dot(&vertex.pos) > 0.0, "{:?} points inwards at {:?}", vertex.normal, vertex.pos);
+        }
+    }
+
+    #[test]
+    fn degenerate_triangle_gets_default_normal() {
+        let mut corners = vec![corner(glm::vec3(1.0, 1.0, 1.0), glm::vec2(0.0, 0.0)); 3];
+        corners[0].normal = glm::vec3(1.0, 0.0, 0.0);
+        generate_normals(&mut corners, NormalGeneration::Flat);
+        assert!(corners.iter().all(|v| v.normal == glm::vec3(0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn tangents_follow_uv_axes_and_handedness() {
+        let mut corners = quad(false);
+        generate_tangents(&mut corners);
+        for vertex in &corners {
+            assert!(glm::distance(&vertex.tangent.xyz(), &glm::vec3(1.0, 0.0, 0.0)) < 1e-5, "{:?}", vertex.tangent);
+            assert_eq!(vertex.tangent.w, 1.0);
+        }
+
+        // V running down the quad mirrors the tangent frame
+        let mut corners = quad(true);
+        generate_tangents(&mut corners);
+        assert!(corners.iter().all(|v| v.tangent.w == -1.0));
+
+        let mut corners = cube();
+        generate_normals(&mut corners, NormalGeneration::Flat);
+        generate_tangents(&mut corners);
+        for (face, triangles) in corners.chunks_exact(6).enumerate() {
+            let u = glm::Vec3::from(CUBE_FACES[face].1);
+            for vertex in triangles {
+                assert_unit(&vertex.tangent.xyz());
+                assert!(vertex.tangent.xyz().dot(&vertex.normal).abs() < 1e-5);
+                assert!(glm::distance(&vertex.tangent.xyz(), &u) < 1e-5, "face {}: {:?}", face, vertex.tangent);
+                assert_eq!(vertex.tangent.w, 1.0, "face {}", face);
+            }
+        }
+    }
+
+    #[test]
+    fn weld_merges_only_identical_corners() {
+        let (vertices, indices) = weld(&quad(false));
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, [0, 1, 2, 0, 2, 3]);
+
+        // Same positions, but every face has its own normal and UVs
+        let mut corners = cube();
+        generate_normals(&mut corners, NormalGeneration::Flat);
+        let (vertices, indices) = weld(&corners);
+        assert_eq!(vertices.len(), 24);
+        assert_eq!(indices.len(), 36);
+        assert!(indices.iter().zip(&corners).all(|(i, c)| vertices[*i as usize] == *c));
+
+        // A single differing bit keeps a corner apart
+        let mut corners = quad(false);
+        corners[3].tex_coord.x = f32::from_bits(corners[3].tex_coord.x.to_bits() + 1);
+        let (vertices, indices) = weld(&corners);
+        assert_eq!(vertices.len(), 5);
+        assert_eq!(indices, [0, 1, 2, 3, 2, 4]);
+    }
+}
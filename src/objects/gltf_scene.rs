@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use gltf::image::Format;
+use log::*;
+use vulkanalia::prelude::v1_0::*;
+use nalgebra_glm as glm;
+
+use crate::AppData;
+
+use super::geometry::*;
+use super::material::{MapSource, MaterialFactors, MaterialMaps};
+use super::mesh::{Mesh, MeshMaterial, Submesh};
+use super::vertex::Vertex;
+
+#[derive(Clone, Debug, Default)]
+pub struct GltfNode {
+    pub name: String,
+    // Relative to the parent node, glTF is Y-up
+    pub transform: glm::Mat4,
+    pub children: Vec<usize>,
+    // Primitives of the node's mesh as indices into `Mesh::submeshes`
+    pub submeshes: Vec<usize>
+}
+
+// Every primitive of the file shares one vertex/index buffer, nodes reference their ranges
+#[derive(Clone, Debug, Default)]
+pub struct GltfScene {
+    pub mesh: Mesh,
+    pub nodes: Vec<GltfNode>,
+    pub roots: Vec<usize>
+}
+
+impl GltfScene {
+    // Loads a .gltf or .glb file together with its external or embedded buffers and images
+    pub unsafe fn from_filepath(
+        filepath: &str,
+        instance: &Instance,
+        device: &Device,
+        data: &AppData
+    ) -> Result<Self> {
+        let GltfImport { vertices, indices, materials, submeshes, nodes, roots } = GltfImport::from_filepath(filepath)?;
+        let mesh = Mesh::new(instance, device, data, vertices, indices, materials, submeshes)?;
+
+        Ok(GltfScene { mesh, nodes, roots })
+    }
+}
+
+// Everything read from a glTF file before any GPU resource exists
+#[derive(Clone, Debug, Default)]
+pub struct GltfImport {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub materials: Vec<MeshMaterial>,
+    pub submeshes: Vec<Submesh>,
+    pub nodes: Vec<GltfNode>,
+    pub roots: Vec<usize>
+}
+
+impl GltfImport {
+    pub fn from_filepath(filepath: &str) -> Result<Self> {
+        let (document, buffers, images) = gltf::import(filepath)?;
+
+        let materials = document.materials()
+            .map(|m| GltfImport::convert_material(&m, &images))
+            .collect::<Vec<_>>();
+
+        // One vertex per triangle corner, welded once every primitive is read
+        let mut corners = vec![];
+        let mut submeshes = vec![];
+
+        // Submeshes created for each glTF mesh, in document order
+        let mut mesh_submeshes = vec![];
+
+        for mesh in document.meshes() {
+            let name = mesh.name().unwrap_or_default();
+            let mut primitives = vec![];
+
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    warn!("Skipping {:?} primitive of `{}` in `{}`.", primitive.mode(), name, filepath);
+                    continue;
+                }
+
+                // Strides, sparse accessors and normalized integer types are resolved by the reader
+                let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+
+                let positions = reader
+                    .read_positions()
+                    .ok_or_else(|| anyhow!("A primitive of `{}` in `{}` has no positions.", name, filepath))?
+                    .collect::<Vec<_>>();
+
+                let normals = reader.read_normals().map(|n| n.collect::<Vec<_>>());
+                let tangents = reader.read_tangents().map(|t| t.collect::<Vec<_>>());
+                let tex_coords = reader.read_tex_coords(0).map(|t| t.into_f32().collect::<Vec<_>>());
+                let colors = reader.read_colors(0).map(|c| c.into_rgb_f32().collect::<Vec<_>>());
+
+                let indices = match reader.read_indices() {
+                    Some(read) => read.into_u32().collect::<Vec<_>>(),
+                    None => (0..positions.len() as u32).collect()
+                };
+
+                let first_index = corners.len();
+                corners.extend(indices.iter().map(|i| *i as usize).map(|i| Vertex {
+                    pos: glm::Vec3::from(positions[i]),
+                    color: colors.as_ref().map_or(glm::vec3(1.0, 1.0, 1.0), |c| glm::Vec3::from(c[i])),
+                    tex_coord: tex_coords.as_ref().map_or(glm::vec2(0.0, 0.0), |t| glm::Vec2::from(t[i])),
+                    normal: normals.as_ref().map_or(glm::vec3(0.0, 0.0, 1.0), |n| glm::Vec3::from(n[i])),
+                    tangent: tangents.as_ref().map_or(glm::vec4(1.0, 0.0, 0.0, 1.0), |t| glm::Vec4::from(t[i]))
+                }));
+
+                // The specification asks for flat normals and MikkTSpace tangents when they are missing
+                let primitive_corners = &mut corners[first_index..];
+                if normals.is_none() {
+                    generate_normals(primitive_corners, NormalGeneration::Flat);
+                }
+
+                if tangents.is_none() {
+                    generate_tangents(primitive_corners);
+                }
+
+                primitives.push(submeshes.len());
+                submeshes.push(Submesh {
+                    name: name.to_string(),
+                    first_index: first_index as u32,
+                    index_count: (corners.len() - first_index) as u32,
+                    material: primitive.material().index()
+                });
+            }
+
+            mesh_submeshes.push(primitives);
+        }
+
+        if corners.is_empty() {
+            return Err(anyhow!("`{}` contains no triangles.", filepath));
+        }
+
+        let (vertices, indices) = weld(&corners);
+
+        let nodes = document.nodes().map(|n| GltfNode {
+            name: n.name().unwrap_or_default().to_string(),
+            transform: glm::Mat4::from(n.transform().matrix()),
+            children: n.children().map(|c| c.index()).collect(),
+            submeshes: n.mesh().map_or(vec![], |m| mesh_submeshes[m.index()].clone())
+        }).collect::<Vec<_>>();
+
+        let roots = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .map_or(vec![], |s| s.nodes().map(|n| n.index()).collect());
+
+        Ok(GltfImport { vertices, indices, materials, submeshes, nodes, roots })
+    }
+
+    // Splits the packed metallic-roughness texture (B: metallic, G: roughness) into our separate maps
+    fn convert_material(material: &gltf::Material, images: &[gltf::image::Data]) -> MeshMaterial {
+        let pbr = material.pbr_metallic_roughness();
+        let rgba = |texture: gltf::Texture| to_rgba8(&images[texture.source().index()]);
+        let channel = |source: &MapSource, c: usize| match source {
+            MapSource::Pixels { pixels, width, height } => MapSource::Pixels {
+                pixels: pixels.chunks_exact(4).map(|p| p[c]).collect(),
+                width: *width,
+                height: *height
+            },
+            file => file.clone()
+        };
+
+        let metallic_roughness = pbr.metallic_roughness_texture().map(|i| rgba(i.texture()));
+        let occlusion = material.occlusion_texture();
+
+        let maps = MaterialMaps {
+            albedo: pbr.base_color_texture().map(|i| rgba(i.texture())),
+            normal: material.normal_texture().map(|n| rgba(n.texture())),
+            metallic: metallic_roughness.as_ref().map(|t| channel(t, 2)),
+            roughness: metallic_roughness.as_ref().map(|t| channel(t, 1)),
+            ao: occlusion.as_ref().map(|o| channel(&rgba(o.texture()), 0)),
+            // Opacity comes from the base color alpha
+            opacity: None
+        };
+
+        let factors = MaterialFactors {
+            base_color: glm::Vec4::from(pbr.base_color_factor()),
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            ao_strength: occlusion.as_ref().map_or(1.0, |o| o.strength()),
+            alpha_cutoff: match material.alpha_mode() {
+                gltf::material::AlphaMode::Mask => material.alpha_cutoff().unwrap_or(0.5),
+                _ => 0.0
+            }
+        };
+
+        MeshMaterial {
+            name: material.name().unwrap_or_default().to_string(),
+            maps,
+            factors
+        }
+    }
+}
+
+// Expands any decoded glTF image to RGBA8, grayscale is replicated across RGB
+fn to_rgba8(image: &gltf::image::Data) -> MapSource {
+    let (channels, bytes_per_channel) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4)
+    };
+
+    let pixels = image.pixels
+        .chunks_exact(channels * bytes_per_channel)
+        .flat_map(|texel| {
+            let sample = |c: usize| {
+                let bytes = &texel[c * bytes_per_channel..(c + 1) * bytes_per_channel];
+                match bytes_per_channel {
+                    1 => bytes[0],
+                    2 => (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8,
+                    _ => (f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).clamp(0.0, 1.0) * 255.0).round() as u8
+                }
+            };
+
+            match channels {
+                1 => [sample(0), sample(0), sample(0), u8::MAX],
+                2 => [sample(0), sample(0), sample(0), sample(1)],
+                3 => [sample(0), sample(1), sample(2), u8::MAX],
+                _ => [sample(0), sample(1), sample(2), sample(3)]
+            }
+        })
+        .collect();
+
+    MapSource::Pixels { pixels, width: image.width, height: image.height }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(filename: &str) -> GltfImport {
+        GltfImport::from_filepath(&format!("{}/tests/fixtures/gltf/{}", env!("CARGO_MANIFEST_DIR"), filename)).unwrap()
+    }
+
+    fn positions(import: &GltfImport) -> Vec<[f32; 3]> {
+        import.vertices.iter().map(|v| v.pos.into()).collect()
+    }
+
+    fn transform_point(transform: &glm::Mat4, point: glm::Vec3) -> glm::Vec3 {
+        (transform * glm::vec4(point.x, point.y, point.z, 1.0)).xyz()
+    }
+
+    #[test]
+    fn box_with_separate_views() {
+        let import = import("box.gltf");
+
+        // 6 faces of 4 vertices, u16 indices
+        assert_eq!(import.vertices.len(), 24);
+        assert_eq!(import.indices.len(), 36);
+        assert_eq!(import.submeshes.len(), 1);
+        assert_eq!((import.submeshes[0].first_index, import.submeshes[0].index_count), (0, 36));
+        assert_eq!(import.submeshes[0].material, Some(0));
+        assert!(import.vertices.iter().all(|v| (v.normal.norm() - 1.0).abs() < 1e-6));
+
+        assert_eq!(import.roots, [0]);
+        assert_eq!(import.nodes[0].children, [1]);
+        assert!(import.nodes[0].submeshes.is_empty());
+        assert_eq!(import.nodes[1].submeshes, [0]);
+
+        // The column-major root matrix turns +Y into -Z
+        let y = transform_point(&import.nodes[0].transform, glm::vec3(0.0, 1.0, 0.0));
+        assert!(glm::distance(&y, &glm::vec3(0.0, 0.0, -1.0)) < 1e-6, "{:?}", y);
+        assert_eq!(import.nodes[1].transform, glm::Mat4::identity());
+
+        let material = &import.materials[0];
+        assert_eq!(material.name, "Red");
+        assert_eq!(material.factors.base_color, glm::vec4(0.8, 0.0, 0.0, 1.0));
+        assert_eq!((material.factors.metallic, material.factors.roughness), (0.0, 1.0));
+        assert_eq!(material.factors.alpha_cutoff, 0.0);
+        assert!(material.maps.albedo.is_none());
+    }
+
+    #[test]
+    fn interleaved_box_matches_separate_views() {
+        let separate = import("box.gltf");
+        let interleaved = import("box_interleaved.gltf");
+
+        assert_eq!(positions(&interleaved), positions(&separate));
+        assert_eq!(interleaved.indices, separate.indices);
+        assert!(interleaved.vertices.iter().zip(&separate.vertices).all(|(a, b)| a.normal == b.normal));
+    }
+
+    #[test]
+    fn node_translation_rotation_scale() {
+        let import = import("box_interleaved.gltf");
+
+        // Scaled by 2, turned 90 degrees around +Y, then moved by (1, 2, 3)
+        let x = transform_point(&import.nodes[0].transform, glm::vec3(1.0, 0.0, 0.0));
+        assert!(glm::distance(&x, &glm::vec3(1.0, 2.0, 1.0)) < 1e-5, "{:?}", x);
+
+        let material = &import.materials[0];
+        assert_eq!(material.factors.base_color, glm::vec4(1.0, 1.0, 1.0, 1.0));
+        assert_eq!((material.factors.metallic, material.factors.roughness), (0.5, 0.25));
+        assert_eq!(material.factors.alpha_cutoff, 0.3);
+    }
+
+    #[test]
+    fn sparse_accessor_replaces_positions() {
+        let import = import("sparse.gltf");
+        let positions = positions(&import);
+
+        assert_eq!(import.vertices.len(), 4);
+        assert_eq!(import.indices, [0, 1, 2, 0, 2, 3]);
+        assert!(positions.contains(&[1.0, 1.0, 2.0]) && positions.contains(&[0.0, 1.0, 2.0]));
+        assert!(!positions.contains(&[1.0, 1.0, 0.0]));
+
+        // Without normals in the file every corner gets the flat normal of the tilted quad
+        let normal = glm::vec3(0.0, -2.0, 1.0).normalize();
+        assert!(import.vertices.iter().all(|v| glm::distance(&v.normal, &normal) < 1e-6));
+        assert!(import.materials.is_empty());
+        assert_eq!(import.submeshes[0].material, None);
+    }
+
+    #[test]
+    fn u8_indices_in_glb() {
+        let import = import("u8_indices.glb");
+
+        assert_eq!(import.vertices.len(), 4);
+        assert_eq!(import.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(positions(&import), [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]);
+        assert_eq!(import.vertices[2].tex_coord, glm::vec2(1.0, 0.0));
+        assert_eq!(import.nodes[0].name, "Quad");
+    }
+}
@@ -0,0 +1,176 @@
+use std::mem::size_of;
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::Result;
+use vulkanalia::prelude::v1_0::*;
+use nalgebra_glm as glm;
+
+use crate::{AppData, MATERIAL_SET};
+use crate::graphics::allocator::Allocation;
+use crate::shared_memory::*;
+
+use super::texture::Texture2D;
+
+#[derive(Clone, Debug)]
+pub enum MapSource {
+    File(String),
+    // Tightly packed texels already in the map's format
+    Pixels { pixels: Vec<u8>, width: u32, height: u32 }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MaterialMaps {
+    pub albedo: Option<MapSource>,
+    pub normal: Option<MapSource>,
+    pub metallic: Option<MapSource>,
+    pub roughness: Option<MapSource>,
+    pub ao: Option<MapSource>,
+    pub opacity: Option<MapSource>
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MaterialFactors {
+    pub base_color: glm::Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ao_strength: f32,
+    pub alpha_cutoff: f32
+}
+
+impl Default for MaterialFactors {
+    fn default() -> Self {
+        Self {
+            base_color: glm::vec4(1.0, 1.0, 1.0, 1.0),
+            metallic: 1.0,
+            roughness: 1.0,
+            ao_strength: 1.0,
+            alpha_cutoff: 0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub factors: MaterialFactors,
+
+    albedo: Texture2D,
+    metallic: Texture2D,
+    roughness: Texture2D,
+    ao: Texture2D,
+    opacity: Texture2D,
+    normal: Texture2D,
+
+    uniform_buffer: vk::Buffer,
+    uniform_buffer_allocation: Allocation,
+
+    descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet
+}
+
+impl Material {
+    pub unsafe fn create(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        maps: &MaterialMaps,
+        factors: MaterialFactors
+    ) -> Result<Self> {
+        // Missing maps become a single texel that leaves the factors untouched
+        let load = |source: &Option<MapSource>, format: vk::Format, default: &[u8]| match source {
+            Some(MapSource::File(path)) => Texture2D::load_from_file(instance, device, data, path, format, None, None),
+            Some(MapSource::Pixels { pixels, width, height }) => Texture2D::from_pixels(
+                instance, device, data,
+                pixels.clone(),
+                *width, *height,
+                format,
+                None, None
+            ),
+            None => Texture2D::from_pixels(
+                instance, device, data,
+                default.to_vec(),
+                1, 1,
+                format,
+                None, None
+            )
+        };
+
+        let albedo = load(&maps.albedo, vk::Format::R8G8B8A8_SRGB, &[u8::MAX; 4])?;
+        let metallic = load(&maps.metallic, vk::Format::R8_UNORM, &[u8::MAX])?;
+        let roughness = load(&maps.roughness, vk::Format::R8_UNORM, &[u8::MAX])?;
+        let ao = load(&maps.ao, vk::Format::R8_UNORM, &[u8::MAX])?;
+        let opacity = load(&maps.opacity, vk::Format::R8_UNORM, &[u8::MAX])?;
+        let normal = load(&maps.normal, vk::Format::R8G8B8A8_UNORM, &[128, 128, u8::MAX, u8::MAX])?;
+
+        let size = size_of::<MaterialFactors>() as u64;
+        let (uniform_buffer, uniform_buffer_allocation) = create_buffer(
+            instance, device, data,
+            size,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
+        )?;
+
+        let memory = uniform_buffer_allocation.mapped_ptr()?;
+        memcpy(&factors, memory.cast(), 1);
+
+        // Set 1: binding 0 holds the factors, bindings 1..=6 albedo, metallic, roughness, AO, opacity and normal
+        let pool_sizes = data.reflection.pool_sizes(MATERIAL_SET, 1);
+        let info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1);
+
+        let descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+        let layouts = &[data.descriptor_set_layouts[MATERIAL_SET as usize]];
+        let info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts);
+
+        let descriptor_set = device.allocate_descriptor_sets(&info)?[0];
+
+        let info = vk::DescriptorBufferInfo::builder()
+            .buffer(uniform_buffer)
+            .offset(0)
+            .range(size);
+
+        let buffer_info = &[info];
+        let ubo_write = vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(0)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .buffer_info(buffer_info);
+
+        let image_infos = [&albedo, &metallic, &roughness, &ao, &opacity, &normal].map(|t| [t.texture.descriptor]);
+        let sampler_writes = image_infos.iter().enumerate().map(|(i, image_info)| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(i as u32 + 1)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(image_info)
+        });
+
+        let writes = std::iter::once(ubo_write).chain(sampler_writes).collect::<Vec<_>>();
+        device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+
+        Ok(Self {
+            factors,
+            albedo, metallic, roughness, ao, opacity, normal,
+            uniform_buffer,
+            uniform_buffer_allocation,
+            descriptor_pool,
+            descriptor_set
+        })
+    }
+
+    pub unsafe fn destroy(&self, device: &Device, data: &AppData) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_buffer(self.uniform_buffer, None);
+        data.allocator.free(device, &self.uniform_buffer_allocation);
+
+        [&self.albedo, &self.metallic, &self.roughness, &self.ao, &self.opacity, &self.normal]
+            .iter()
+            .for_each(|t| t.texture.destroy(device, data));
+    }
+}
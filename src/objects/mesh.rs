@@ -1,40 +1,69 @@
 use std::io::BufReader;
 use std::fs::File;
-use std::collections::HashMap;
-use std::mem::size_of;
+use std::mem::size_of_val;
+use std::path::Path;
 use std::ptr::copy_nonoverlapping as memcpy;
 
 use anyhow::Result;
+use log::*;
 use vulkanalia::prelude::v1_0::*;
 use nalgebra_glm as glm;
 
 use crate::AppData;
+use crate::graphics::allocator::Allocation;
 use crate::shared_memory::*;
 
+use super::geometry::*;
+use super::material::{MapSource, MaterialFactors, MaterialMaps};
 use super::vertex::Vertex;
 
+// Material description resolved from the MTL file, GPU resources are created by the renderer
+#[derive(Clone, Debug, Default)]
+pub struct MeshMaterial {
+    pub name: String,
+    pub maps: MaterialMaps,
+    pub factors: MaterialFactors
+}
+
+// Range of the shared index buffer belonging to one OBJ object
+#[derive(Clone, Debug, Default)]
+pub struct Submesh {
+    pub name: String,
+    pub first_index: u32,
+    pub index_count: u32,
+    // Index into `Mesh::materials`
+    pub material: Option<usize>
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Mesh {
     vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
 
+    pub materials: Vec<MeshMaterial>,
+    pub submeshes: Vec<Submesh>,
+
     pub vertex_buffer: vk::Buffer,
-    pub vertex_buffer_memory: vk::DeviceMemory,
+    pub vertex_buffer_allocation: Allocation,
 
     pub index_buffer: vk::Buffer,
-    pub index_buffer_memory: vk::DeviceMemory
+    pub index_buffer_allocation: Allocation
 }
 
 impl Mesh {
+    // `normals` only applies to objects that come without their own
     pub unsafe fn from_filepath(
         filepath: String,
+        normals: NormalGeneration,
         instance: &Instance,
         device: &Device,
         data: &AppData
     ) -> Result<Self> {
-        let mut reader = BufReader::new(File::open(filepath)?);
+        let mut reader = BufReader::new(File::open(&filepath)?);
+        let directory = Path::new(&filepath).parent().unwrap_or(Path::new(""));
 
-        let (models, _) = tobj::load_obj_buf(
+        // MTL files and the textures they reference are relative to the OBJ
+        let (models, materials) = tobj::load_obj_buf(
             &mut reader, 
             &tobj::LoadOptions {
                 triangulate: true,
@@ -42,21 +71,30 @@ impl Mesh {
                 ..Default::default()
             }, 
 
-            |_| Ok(Default::default())
+            |p| tobj::load_mtl(directory.join(p))
         )?;
 
-        let mut unique_vertices = HashMap::new();
+        let materials = materials
+            .unwrap_or_else(|e| {
+                warn!("Failed to load materials for `{}`: {}", filepath, e);
+                vec![]
+            })
+            .iter()
+            .map(|m| Mesh::convert_material(m, directory))
+            .collect::<Vec<_>>();
 
-        let mut vertices = vec![];
-        let mut indices = vec![];
+        // Expanded to one vertex per triangle corner until tangents are generated
+        let mut corners = vec![];
+        let mut submeshes = vec![];
 
         for model in &models {
+            let first_index = corners.len();
+
             for index in &model.mesh.indices {
                 let pos_offset = (3 * index) as usize;
-                let tex_coords_offset = (2 * index) as usize;
 
                 let mut vtx_color = glm::vec3(1.0, 1.0, 1.0);
-                if model.mesh.vertex_color.len() > 0 {
+                if !model.mesh.vertex_color.is_empty() {
                     let vtx_color_offset = (3 * index) as usize;
                     vtx_color = glm::vec3(
                         model.mesh.vertex_color[vtx_color_offset],
@@ -65,8 +103,17 @@ impl Mesh {
                     );
                 }
 
+                let mut tex_coord = glm::vec2(0.0, 0.0);
+                if !model.mesh.texcoords.is_empty() {
+                    let tex_coords_offset = (2 * index) as usize;
+                    tex_coord = glm::vec2(
+                        model.mesh.texcoords[tex_coords_offset],
+                        1.0 - model.mesh.texcoords[tex_coords_offset + 1]
+                    );
+                }
+
                 let mut normal = glm::vec3(0.0, 0.0, 1.0);
-                if model.mesh.normals.len() > 0 {
+                if !model.mesh.normals.is_empty() {
                     let normal_offset = (3 * index) as usize;
                     normal = glm::vec3(
                         model.mesh.normals[normal_offset],
@@ -82,108 +129,165 @@ impl Mesh {
                         model.mesh.positions[pos_offset + 2],
                     ),
                     color: vtx_color,
-                    tex_coord: glm::vec2(
-                        model.mesh.texcoords[tex_coords_offset],
-                        1.0 - model.mesh.texcoords[tex_coords_offset + 1]
-                    ),
-                    normal
+                    tex_coord,
+                    normal,
+                    tangent: glm::vec4(1.0, 0.0, 0.0, 1.0)
                 };
 
-                if let Some(index) = unique_vertices.get(&vertex) {
-                    indices.push(*index as u32);
-                } else {
-                    let index = vertices.len();
-                    unique_vertices.insert(vertex, index);
-                    vertices.push(vertex);
-                    indices.push(index as u32)
-                }
+                corners.push(vertex);
+            }
+
+            if model.mesh.normals.is_empty() {
+                generate_normals(&mut corners[first_index..], normals);
             }
+
+            submeshes.push(Submesh {
+                name: model.name.clone(),
+                first_index: first_index as u32,
+                index_count: (corners.len() - first_index) as u32,
+                material: model.mesh.material_id.filter(|i| *i < materials.len())
+            });
         }
 
-        let (vertex_buffer, vertex_buffer_memory) = Mesh::create_vertex_buffer(instance, device, data, &vertices)?;
-        let (index_buffer, index_buffer_memory) = Mesh::create_index_buffer(instance, device, data, &indices)?;
+        generate_tangents(&mut corners);
+        let (vertices, indices) = weld(&corners);
+
+        Mesh::new(instance, device, data, vertices, indices, materials, submeshes)
+    }
+
+    // Uploads already assembled geometry, submesh ranges index into `indices`
+    pub unsafe fn new(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        vertices: Vec<Vertex>,
+        indices: Vec<u32>,
+        materials: Vec<MeshMaterial>,
+        submeshes: Vec<Submesh>
+    ) -> Result<Self> {
+        let (vertex_buffer, vertex_buffer_allocation) = Mesh::create_vertex_buffer(instance, device, data, &vertices)?;
+        let (index_buffer, index_buffer_allocation) = Mesh::create_index_buffer(instance, device, data, &indices)?;
 
         Ok(Mesh{
             vertices, indices,
+            materials, submeshes,
             vertex_buffer,
-            vertex_buffer_memory,
+            vertex_buffer_allocation,
             index_buffer,
-            index_buffer_memory
+            index_buffer_allocation
         })
     }
 
+    // Maps the Phong-style MTL parameters, plus the common PBR extensions, onto our PBR material
+    fn convert_material(material: &tobj::Material, directory: &Path) -> MeshMaterial {
+        let param = |key: &str| material.unknown_param.get(key).map(String::as_str);
+
+        // Texture statements may carry options such as `-bm 1.0`, the file name comes last
+        let texture = |value: Option<&str>| {
+            value
+                .and_then(|v| v.split_whitespace().last())
+                .map(|v| MapSource::File(directory.join(v).to_string_lossy().into_owned()))
+        };
+
+        fn non_empty(value: &str) -> Option<&str> {
+            Some(value).filter(|v| !v.is_empty())
+        }
+
+        let factor = |key: &str| param(key).and_then(|v| v.parse::<f32>().ok());
+
+        let maps = MaterialMaps {
+            albedo: texture(non_empty(&material.diffuse_texture)),
+            normal: texture(non_empty(&material.normal_texture).or_else(|| param("norm")).or_else(|| param("bump"))),
+            metallic: texture(param("map_Pm")),
+            roughness: texture(param("map_Pr")),
+            ao: None,
+            opacity: texture(non_empty(&material.dissolve_texture))
+        };
+
+        // Without an explicit roughness, approximate it from the Blinn-Phong exponent
+        let roughness = factor("Pr").unwrap_or_else(|| (2.0 / (material.shininess.max(0.0) + 2.0)).sqrt());
+        let [r, g, b] = material.diffuse;
+
+        let factors = MaterialFactors {
+            base_color: glm::vec4(r, g, b, material.dissolve),
+            metallic: factor("Pm").unwrap_or(0.0),
+            roughness,
+            alpha_cutoff: if maps.opacity.is_some() { 0.5 } else { 0.0 },
+            ..Default::default()
+        };
+
+        MeshMaterial { name: material.name.clone(), maps, factors }
+    }
+
     // TODO: merge functions?
     unsafe fn create_vertex_buffer(
         instance: &Instance,
         device: &Device,
         data: &AppData,
-        vertices: &Vec<Vertex>
-    ) -> Result<(vk::Buffer, vk::DeviceMemory)> {
-        let size = (size_of::<Vertex>() * vertices.len()) as u64;
+        vertices: &[Vertex]
+    ) -> Result<(vk::Buffer, Allocation)> {
+        let size = size_of_val(vertices) as u64;
     
-        let (staging_buffer, staging_buffer_memory) = create_buffer(
+        let (staging_buffer, staging_buffer_allocation) = create_buffer(
             instance, device, data, size,
             vk::BufferUsageFlags::TRANSFER_SRC,
             vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
         )?;
     
-        let memory = device.map_memory(staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty())?;
+        let memory = staging_buffer_allocation.mapped_ptr()?;
         memcpy(vertices.as_ptr(), memory.cast(), vertices.len());
     
-        let (vertex_buffer, vertex_buffer_memory) = create_buffer(instance, device, data, size,
+        let (vertex_buffer, vertex_buffer_allocation) = create_buffer(instance, device, data, size,
             vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
             vk::MemoryPropertyFlags::DEVICE_LOCAL
         )?;
     
         let buffer = vertex_buffer;
-        let buffer_memory = vertex_buffer_memory;
+        let buffer_allocation = vertex_buffer_allocation;
     
         copy_buffer(device, data, staging_buffer, buffer, size)?;
     
         device.destroy_buffer(staging_buffer, None);
-        device.free_memory(staging_buffer_memory, None);
+        data.allocator.free(device, &staging_buffer_allocation);
     
-        Ok((buffer, buffer_memory))
+        Ok((buffer, buffer_allocation))
     }
 
     unsafe fn create_index_buffer(
         instance: &Instance,
         device: &Device,
         data: &AppData,
-        indices: &Vec<u32>
-    ) -> Result<(vk::Buffer, vk::DeviceMemory)> {
-        let size = (size_of::<u32>() * indices.len()) as u64;
+        indices: &[u32]
+    ) -> Result<(vk::Buffer, Allocation)> {
+        let size = size_of_val(indices) as u64;
     
-        let (staging_buffer, staging_buffer_memory) = create_buffer(
+        let (staging_buffer, staging_buffer_allocation) = create_buffer(
             instance, device, data, size,
             vk::BufferUsageFlags::TRANSFER_SRC,
             vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
         )?;
     
-        let memory = device.map_memory(staging_buffer_memory, 0,  size, vk::MemoryMapFlags::empty())?;
+        let memory = staging_buffer_allocation.mapped_ptr()?;
         memcpy(indices.as_ptr(), memory.cast(), indices.len());
     
-        let (index_buffer, index_buffer_memory) = create_buffer(instance, device, data, size,
+        let (index_buffer, index_buffer_allocation) = create_buffer(instance, device, data, size,
             vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::INDEX_BUFFER,
             vk::MemoryPropertyFlags::DEVICE_LOCAL
         )?;
     
-        let index_buffer = index_buffer;
-        let index_buffer_memory = index_buffer_memory;
-    
         copy_buffer(device, data, staging_buffer, index_buffer, size)?;
     
         device.destroy_buffer(staging_buffer, None);
-        device.free_memory(staging_buffer_memory, None);
+        data.allocator.free(device, &staging_buffer_allocation);
     
-        Ok((index_buffer, index_buffer_memory))
+        Ok((index_buffer, index_buffer_allocation))
     }
 
-    pub unsafe fn destroy(&self, device: &Device) {
+    pub unsafe fn destroy(&self, device: &Device, data: &AppData) {
         device.destroy_buffer(self.vertex_buffer, None);
-        device.free_memory(self.vertex_buffer_memory, None);
+        data.allocator.free(device, &self.vertex_buffer_allocation);
         device.destroy_buffer(self.index_buffer, None);
-        device.free_memory(self.index_buffer_memory, None);
+        data.allocator.free(device, &self.index_buffer_allocation);
     }
     
 }
\ No newline at end of file
@@ -1,4 +1,11 @@
+pub mod camera;
+pub mod environment;
+pub mod geometry;
+pub mod gltf_scene;
+pub mod material;
 pub mod mesh;
+pub mod scene;
+pub mod scene_file;
 pub mod texture;
 pub mod vertex;
 pub mod uniform_buffer_object;
\ No newline at end of file
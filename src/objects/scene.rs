@@ -0,0 +1,415 @@
+use anyhow::Result;
+use vulkanalia::prelude::v1_0::*;
+use nalgebra_glm as glm;
+
+use crate::AppData;
+
+use super::gltf_scene::{GltfNode, GltfScene};
+use super::material::{MapSource, Material, MaterialFactors, MaterialMaps};
+use super::mesh::{Mesh, Submesh};
+use super::scene_file::{LightDesc, NodeDesc, SceneFile};
+use super::uniform_buffer_object::LightData;
+
+#[derive(Clone, Debug)]
+pub struct SceneNode {
+    pub name: String,
+    // Relative to the parent node
+    pub transform: glm::Mat4,
+    pub children: Vec<usize>,
+    // Index into `Scene::meshes`
+    pub mesh: Option<usize>,
+    // Submeshes of `mesh` drawn by this node, all of them when `None`
+    pub submeshes: Option<Vec<usize>>,
+    // Index into `Scene::materials`, overrides the materials of the mesh
+    pub material: Option<usize>,
+    // Hides the node together with its children
+    pub visible: bool
+}
+
+impl Default for SceneNode {
+    fn default() -> Self {
+        SceneNode {
+            name: String::new(),
+            transform: glm::identity(),
+            children: vec![],
+            mesh: None,
+            submeshes: None,
+            material: None,
+            visible: true
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SceneMesh {
+    pub mesh: Mesh,
+    // Scene material created for each of `mesh.materials`
+    pub materials: Vec<usize>
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Light {
+    // `direction` is the way the light travels, `color` includes the intensity
+    Directional { direction: glm::Vec3, color: glm::Vec3 },
+    Point { position: glm::Vec3, color: glm::Vec3 }
+}
+
+impl Light {
+    pub fn to_uniform(self) -> LightData {
+        match self {
+            Light::Directional { direction, color } => LightData {
+                position: glm::vec3_to_vec4(&-direction.normalize()),
+                color: glm::vec3_to_vec4(&color)
+            },
+            Light::Point { position, color } => LightData {
+                position: glm::vec4(position.x, position.y, position.z, 1.0),
+                color: glm::vec3_to_vec4(&color)
+            }
+        }
+    }
+}
+
+// A node with a mesh and its world transform, as drawn in one frame
+#[derive(Clone, Debug)]
+pub struct SceneInstance {
+    pub node: usize,
+    pub mesh: usize,
+    pub model: glm::Mat4
+}
+
+// Owns every mesh and material on screen, nodes place meshes in the Z-up world
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    pub meshes: Vec<SceneMesh>,
+    pub materials: Vec<Material>,
+    pub nodes: Vec<SceneNode>,
+    pub roots: Vec<usize>,
+    pub lights: Vec<Light>
+}
+
+// Mesh entry of a scene file once loaded, glTF meshes bring their node hierarchy along
+enum LoadedMesh {
+    Obj(usize),
+    Gltf { mesh: usize, nodes: Vec<GltfNode>, roots: Vec<usize> }
+}
+
+impl Scene {
+    // Expects a validated file, every name it references exists
+    pub unsafe fn load(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        file: &SceneFile
+    ) -> Result<Self> {
+        let mut scene = Scene::default();
+
+        // Created first so submeshes without a material fall back to a neutral one
+        let neutral = MaterialFactors { metallic: 0.0, ..Default::default() };
+        scene.add_material(Material::create(instance, device, data, &MaterialMaps::default(), neutral)?);
+
+        let texture = |name: &Option<String>| name.as_ref().map(|n| {
+            let path = &file.textures.iter().find(|t| &t.name == n).unwrap().path;
+            MapSource::File(path.clone())
+        });
+
+        let mut materials = vec![];
+        for material in &file.materials {
+            let maps = MaterialMaps {
+                albedo: texture(&material.albedo_map),
+                normal: texture(&material.normal_map),
+                metallic: texture(&material.metallic_map),
+                roughness: texture(&material.roughness_map),
+                ao: texture(&material.ao_map),
+                opacity: texture(&material.opacity_map)
+            };
+
+            let factors = MaterialFactors {
+                base_color: glm::Vec4::from(material.base_color),
+                metallic: material.metallic,
+                roughness: material.roughness,
+                ao_strength: material.ao_strength,
+                alpha_cutoff: material.alpha_cutoff
+            };
+
+            let index = scene.add_material(Material::create(instance, device, data, &maps, factors)?);
+            materials.push((material.name.as_str(), index));
+        }
+
+        let mut meshes = vec![];
+        for mesh in &file.meshes {
+            let loaded = if mesh.is_gltf() {
+                let GltfScene { mesh, nodes, roots } = GltfScene::from_filepath(&mesh.path, instance, device, data)?;
+                LoadedMesh::Gltf { mesh: scene.add_mesh(instance, device, data, mesh)?, nodes, roots }
+            } else {
+                let mesh = Mesh::from_filepath(mesh.path.clone(), mesh.normals, instance, device, data)?;
+                LoadedMesh::Obj(scene.add_mesh(instance, device, data, mesh)?)
+            };
+
+            meshes.push((mesh.name.as_str(), loaded));
+        }
+
+        let mut pending = file.nodes.iter().rev().map(|n| (n, None)).collect::<Vec<(&NodeDesc, Option<usize>)>>();
+        while let Some((node, parent)) = pending.pop() {
+            let mesh = node.mesh.as_ref().map(|m| &meshes.iter().find(|(n, _)| n == m).unwrap().1);
+            let material = node.material.as_ref().map(|m| materials.iter().find(|(n, _)| n == m).unwrap().1);
+
+            let [tx, ty, tz] = node.translation;
+            let [rx, ry, rz] = node.rotation.map(|r| r.to_radians());
+            let transform = glm::translation(&glm::vec3(tx, ty, tz))
+                * glm::rotation(rz, &glm::vec3(0.0, 0.0, 1.0))
+                * glm::rotation(ry, &glm::vec3(0.0, 1.0, 0.0))
+                * glm::rotation(rx, &glm::vec3(1.0, 0.0, 0.0))
+                * glm::scaling(&glm::Vec3::from(node.scale));
+
+            let index = scene.add_node(SceneNode {
+                name: node.name.clone(),
+                transform,
+                mesh: match mesh { Some(LoadedMesh::Obj(mesh)) => Some(*mesh), _ => None },
+                material,
+                visible: node.visible,
+                ..Default::default()
+            }, parent);
+
+            // glTF is Y-up, its hierarchy hangs below a node turning it into the Z-up world
+            if let Some(LoadedMesh::Gltf { mesh, nodes, roots }) = mesh {
+                let up = scene.add_node(SceneNode {
+                    name: format!("{} (Y-up)", node.name),
+                    transform: glm::rotation(glm::half_pi(), &glm::vec3(1.0, 0.0, 0.0)),
+                    ..Default::default()
+                }, Some(index));
+
+                scene.add_gltf_nodes(*mesh, nodes, roots, Some(up));
+            }
+
+            pending.extend(node.children.iter().rev().map(|c| (c, Some(index))));
+        }
+
+        scene.lights = file.lights.iter().map(|l| match *l {
+            LightDesc::Directional { direction, color, intensity } => Light::Directional {
+                direction: glm::Vec3::from(direction),
+                color: glm::Vec3::from(color) * intensity
+            },
+            LightDesc::Point { position, color, intensity } => Light::Point {
+                position: glm::Vec3::from(position),
+                color: glm::Vec3::from(color) * intensity
+            }
+        }).collect();
+
+        Ok(scene)
+    }
+
+    // Creates the materials described by the mesh, returns its index for `SceneNode::mesh`
+    pub unsafe fn add_mesh(
+        &mut self,
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        mesh: Mesh
+    ) -> Result<usize> {
+        let mut materials = vec![];
+        for material in &mesh.materials {
+            materials.push(self.add_material(Material::create(instance, device, data, &material.maps, material.factors)?));
+        }
+
+        self.meshes.push(SceneMesh { mesh, materials });
+        Ok(self.meshes.len() - 1)
+    }
+
+    pub fn add_material(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    // Children listed in `node` must already be part of the scene
+    pub fn add_node(&mut self, node: SceneNode, parent: Option<usize>) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(node);
+
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(index),
+            None => self.roots.push(index)
+        }
+
+        index
+    }
+
+    // Instantiates a glTF hierarchy whose geometry was already added as `mesh`
+    pub fn add_gltf_nodes(&mut self, mesh: usize, nodes: &[GltfNode], roots: &[usize], parent: Option<usize>) {
+        let offset = self.nodes.len();
+
+        self.nodes.extend(nodes.iter().map(|n| SceneNode {
+            name: n.name.clone(),
+            transform: n.transform,
+            children: n.children.iter().map(|c| c + offset).collect(),
+            mesh: (!n.submeshes.is_empty()).then_some(mesh),
+            submeshes: Some(n.submeshes.clone()),
+            ..Default::default()
+        }));
+
+        for root in roots {
+            match parent {
+                Some(parent) => self.nodes[parent].children.push(root + offset),
+                None => self.roots.push(root + offset)
+            }
+        }
+    }
+
+    // Walks the hierarchy depth first, skipping hidden subtrees
+    pub fn instances(&self) -> Vec<SceneInstance> {
+        let mut instances = vec![];
+        let mut stack = self.roots.iter().rev().map(|r| (*r, glm::Mat4::identity())).collect::<Vec<_>>();
+
+        while let Some((index, parent)) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.visible {
+                continue;
+            }
+
+            let model = parent * node.transform;
+            if let Some(mesh) = node.mesh {
+                instances.push(SceneInstance { node: index, mesh, model });
+            }
+
+            stack.extend(node.children.iter().rev().map(|c| (*c, model)));
+        }
+
+        instances
+    }
+
+    // Submeshes drawn for an instance together with the scene material of each
+    pub fn draws<'a>(&'a self, instance: &SceneInstance) -> impl Iterator<Item = (&'a Submesh, usize)> + 'a {
+        let node = &self.nodes[instance.node];
+        let mesh = &self.meshes[instance.mesh];
+
+        let submeshes = match &node.submeshes {
+            Some(indices) => indices.iter().map(|i| &mesh.mesh.submeshes[*i]).collect::<Vec<_>>(),
+            None => mesh.mesh.submeshes.iter().collect()
+        };
+
+        // Submeshes without a material fall back to the first one of the scene
+        submeshes.into_iter().map(move |s| {
+            let material = node.material.or_else(|| s.material.map(|m| mesh.materials[m])).unwrap_or(0);
+            (s, material)
+        })
+    }
+
+    pub unsafe fn destroy(&self, device: &Device, data: &AppData) {
+        self.materials.iter().for_each(|m| m.destroy(device, data));
+        self.meshes.iter().for_each(|m| m.mesh.destroy(device, data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submesh(name: &str, material: Option<usize>) -> Submesh {
+        Submesh { name: name.into(), material, ..Default::default() }
+    }
+
+    fn node(name: &str, transform: glm::Mat4, mesh: Option<usize>) -> SceneNode {
+        SceneNode { name: name.into(), transform, mesh, ..Default::default() }
+    }
+
+    fn translation(x: f32, y: f32, z: f32) -> glm::Mat4 {
+        glm::translation(&glm::vec3(x, y, z))
+    }
+
+    // One mesh with submeshes using its materials 0, 1 and none, mapped to scene materials 3 and 5
+    fn scene() -> Scene {
+        let mut mesh = Mesh::default();
+        mesh.submeshes = vec![submesh("a", Some(0)), submesh("b", Some(1)), submesh("c", None)];
+
+        Scene { meshes: vec![SceneMesh { mesh, materials: vec![3, 5] }], ..Default::default() }
+    }
+
+    fn instance_names(scene: &Scene) -> Vec<&str> {
+        scene.instances().iter().map(|i| scene.nodes[i.node].name.as_str()).collect()
+    }
+
+    #[test]
+    fn instances_compose_transforms_depth_first() {
+        let mut scene = scene();
+        let root = scene.add_node(node("root", translation(1.0, 0.0, 0.0), Some(0)), None);
+        let scaled = scene.add_node(node("scaled", glm::scaling(&glm::vec3(2.0, 2.0, 2.0)), None), Some(root));
+        scene.add_node(node("leaf", translation(0.0, 1.0, 0.0), Some(0)), Some(scaled));
+        scene.add_node(node("sibling", translation(0.0, 0.0, 1.0), Some(0)), Some(root));
+        scene.add_node(node("second root", glm::identity(), Some(0)), None);
+
+        let instances = scene.instances();
+        assert_eq!(instance_names(&scene), ["root", "leaf", "sibling", "second root"]);
+
+        // Children apply after their parents: the leaf offset is scaled, then moved by the root
+        let origin = |i: usize| (instances[i].model * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz();
+        assert_eq!(origin(0), glm::vec3(1.0, 0.0, 0.0));
+        assert_eq!(origin(1), glm::vec3(1.0, 2.0, 0.0));
+        assert_eq!(origin(2), glm::vec3(1.0, 0.0, 1.0));
+        assert_eq!(origin(3), glm::vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn hidden_nodes_hide_their_subtree() {
+        let mut scene = scene();
+        let root = scene.add_node(node("root", glm::identity(), Some(0)), None);
+        let hidden = scene.add_node(SceneNode { visible: false, ..node("hidden", glm::identity(), Some(0)) }, Some(root));
+        scene.add_node(node("under hidden", glm::identity(), Some(0)), Some(hidden));
+        scene.add_node(node("visible", glm::identity(), Some(0)), Some(root));
+
+        assert_eq!(instance_names(&scene), ["root", "visible"]);
+
+        scene.nodes[root].visible = false;
+        assert!(scene.instances().is_empty());
+    }
+
+    #[test]
+    fn nodes_without_mesh_are_not_instances() {
+        let mut scene = scene();
+        let group = scene.add_node(node("group", glm::identity(), None), None);
+        scene.add_node(node("child", glm::identity(), Some(0)), Some(group));
+
+        assert_eq!(instance_names(&scene), ["child"]);
+    }
+
+    #[test]
+    fn draws_resolve_material_precedence() {
+        let mut scene = scene();
+        let plain = scene.add_node(node("plain", glm::identity(), Some(0)), None);
+        scene.add_node(SceneNode { material: Some(7), ..node("override", glm::identity(), Some(0)) }, None);
+        scene.add_node(SceneNode { submeshes: Some(vec![2, 1]), ..node("subset", glm::identity(), Some(0)) }, None);
+
+        let draws = |instance: &SceneInstance| scene.draws(instance).map(|(s, m)| (s.name.clone(), m)).collect::<Vec<_>>();
+        let instances = scene.instances();
+        let pair = |name: &str, material| (name.to_string(), material);
+
+        // Mesh materials map to scene materials, a submesh without one gets the neutral material 0
+        assert_eq!(instances[0].node, plain);
+        assert_eq!(draws(&instances[0]), [pair("a", 3), pair("b", 5), pair("c", 0)]);
+
+        // A node material wins over every submesh material
+        assert_eq!(draws(&instances[1]), [pair("a", 7), pair("b", 7), pair("c", 7)]);
+
+        // Only the listed submeshes are drawn, in the order of the node
+        assert_eq!(draws(&instances[2]), [pair("c", 0), pair("b", 5)]);
+    }
+
+    #[test]
+    fn gltf_nodes_hang_below_parent() {
+        let mut scene = scene();
+        let parent = scene.add_node(node("parent", glm::identity(), None), None);
+
+        let gltf_node = |name: &str, children: Vec<usize>, submeshes: Vec<usize>| GltfNode {
+            name: name.into(),
+            transform: glm::identity(),
+            children,
+            submeshes
+        };
+        let nodes = [gltf_node("root", vec![1], vec![]), gltf_node("child", vec![], vec![0, 2])];
+        scene.add_gltf_nodes(0, &nodes, &[0], Some(parent));
+
+        assert_eq!(scene.roots, [parent]);
+        assert_eq!(scene.nodes[parent].children, [1]);
+        assert_eq!(scene.nodes[1].children, [2]);
+        assert_eq!((scene.nodes[1].mesh, scene.nodes[2].mesh), (None, Some(0)));
+        assert_eq!(instance_names(&scene), ["child"]);
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::geometry::NormalGeneration;
+use super::uniform_buffer_object::MAX_LIGHTS;
+
+// Declarative scene loaded from a RON file, paths inside are relative to the file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename = "Scene", deny_unknown_fields)]
+pub struct SceneFile {
+    // Equirectangular HDR panorama used for image-based lighting
+    pub environment: String,
+    #[serde(default)]
+    pub camera: CameraDesc,
+    #[serde(default)]
+    pub textures: Vec<TextureDesc>,
+    #[serde(default)]
+    pub materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    pub meshes: Vec<MeshDesc>,
+    #[serde(default)]
+    pub nodes: Vec<NodeDesc>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraDesc {
+    pub position: [f32; 3],
+    pub target: [f32; 3],
+    // Vertical, in degrees
+    pub fov: f32,
+    pub near: f32,
+    pub far: f32
+}
+
+impl Default for CameraDesc {
+    fn default() -> Self {
+        CameraDesc { position: [0.0, -12.0, 5.0], target: [0.0, 0.0, 1.0], fov: 45.0, near: 0.1, far: 100.0 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextureDesc {
+    pub name: String,
+    pub path: String
+}
+
+// Maps name entries of `SceneFile::textures`, factors multiply them like in `MaterialFactors`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaterialDesc {
+    pub name: String,
+    pub albedo_map: Option<String>,
+    pub normal_map: Option<String>,
+    pub metallic_map: Option<String>,
+    pub roughness_map: Option<String>,
+    pub ao_map: Option<String>,
+    pub opacity_map: Option<String>,
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ao_strength: f32,
+    pub alpha_cutoff: f32
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        MaterialDesc {
+            name: String::new(),
+            albedo_map: None,
+            normal_map: None,
+            metallic_map: None,
+            roughness_map: None,
+            ao_map: None,
+            opacity_map: None,
+            base_color: [1.0; 4],
+            metallic: 1.0,
+            roughness: 1.0,
+            ao_strength: 1.0,
+            alpha_cutoff: 0.0
+        }
+    }
+}
+
+// OBJ, glTF or GLB file, loaded once however many nodes use it
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDesc {
+    pub name: String,
+    pub path: String,
+    // Only used for OBJ objects without normals
+    #[serde(default)]
+    pub normals: NormalGeneration
+}
+
+impl MeshDesc {
+    pub fn is_gltf(&self) -> bool {
+        matches!(extension(&self.path).as_str(), "gltf" | "glb")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeDesc {
+    pub name: String,
+    pub mesh: Option<String>,
+    // Replaces the materials of an OBJ mesh
+    pub material: Option<String>,
+    pub translation: [f32; 3],
+    // Euler angles in degrees, applied around X, then Y, then Z
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+    pub visible: bool,
+    pub children: Vec<NodeDesc>
+}
+
+impl Default for NodeDesc {
+    fn default() -> Self {
+        NodeDesc {
+            name: String::new(),
+            mesh: None,
+            material: None,
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+            visible: true,
+            children: vec![]
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum LightDesc {
+    // `direction` is the way the light travels
+    Directional {
+        direction: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        intensity: f32
+    },
+    Point {
+        position: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        intensity: f32
+    }
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn non_negative(value: f32) -> bool {
+    value.is_finite() && value >= 0.0
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default()
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid scene `{filename}`:\n  {}", .problems.join("\n  "))]
+pub struct SceneFileError {
+    pub filename: String,
+    // One line per offending entry
+    pub problems: Vec<String>
+}
+
+impl SceneFile {
+    pub fn load(filename: &str) -> Result<Self> {
+        let source = fs::read_to_string(filename).map_err(|e| anyhow!("Failed to read scene `{}`: {}", filename, e))?;
+        let mut scene = ron::from_str::<SceneFile>(&source).map_err(|e| anyhow!("{}:{}", filename, e))?;
+
+        let directory = Path::new(filename).parent().unwrap_or(Path::new(""));
+        scene.resolve_paths(directory);
+        scene.validate(filename)?;
+
+        Ok(scene)
+    }
+
+    // Single OBJ or glTF model at the origin, `albedo` textures OBJ files without an MTL file
+    pub fn from_model(path: &str, albedo: Option<&str>) -> Self {
+        let textures = albedo
+            .map(|a| TextureDesc { name: String::from("albedo"), path: a.to_string() })
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let materials = albedo
+            .map(|_| MaterialDesc {
+                name: String::from("albedo"),
+                albedo_map: Some(String::from("albedo")),
+                metallic: 0.0,
+                ..Default::default()
+            })
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        SceneFile {
+            environment: String::from("resources/environment/sky.hdr"),
+            camera: CameraDesc::default(),
+            meshes: vec![MeshDesc { name: String::from("model"), path: path.to_string(), normals: NormalGeneration::Smooth }],
+            nodes: vec![NodeDesc {
+                name: String::from("model"),
+                mesh: Some(String::from("model")),
+                material: materials.first().map(|m| m.name.clone()),
+                ..Default::default()
+            }],
+            textures,
+            materials,
+            lights: vec![LightDesc::Directional { direction: [-0.3, 0.6, -1.0], color: white(), intensity: 3.0 }]
+        }
+    }
+
+    fn resolve_paths(&mut self, directory: &Path) {
+        let resolve = |path: &mut String| *path = directory.join(&*path).to_string_lossy().into_owned();
+
+        resolve(&mut self.environment);
+        self.textures.iter_mut().for_each(|t| resolve(&mut t.path));
+        self.meshes.iter_mut().for_each(|m| resolve(&mut m.path));
+    }
+
+    pub fn validate(&self, filename: &str) -> Result<(), SceneFileError> {
+        let mut problems = vec![];
+
+        let mut check_file = |entry: &str, path: &str, extensions: &[&str]| {
+            if !Path::new(path).is_file() {
+                problems.push(format!("{}: file `{}` does not exist", entry, path));
+            } else if !extensions.contains(&extension(path).as_str()) {
+                problems.push(format!("{}: `{}` is not one of {}", entry, path, extensions.join(", ")));
+            }
+        };
+
+        check_file("environment", &self.environment, &["hdr"]);
+        self.textures.iter().for_each(|t| check_file(&format!("texture `{}`", t.name), &t.path, &["png"]));
+        self.meshes.iter().for_each(|m| check_file(&format!("mesh `{}`", m.name), &m.path, &["obj", "gltf", "glb"]));
+
+        let texture_names = unique_names(&mut problems, "texture", self.textures.iter().map(|t| &t.name));
+        let material_names = unique_names(&mut problems, "material", self.materials.iter().map(|m| &m.name));
+        let mesh_names = unique_names(&mut problems, "mesh", self.meshes.iter().map(|m| &m.name));
+
+        let camera = &self.camera;
+        if !(camera.fov > 0.0 && camera.fov < 180.0) {
+            problems.push(format!("camera: fov {} is outside (0, 180) degrees", camera.fov));
+        }
+
+        if !(camera.near > 0.0 && camera.far > camera.near) {
+            problems.push(format!("camera: near {} and far {} need 0 < near < far", camera.near, camera.far));
+        }
+
+        if camera.position == camera.target {
+            problems.push(String::from("camera: position and target are the same point"));
+        }
+
+        for material in &self.materials {
+            let entry = format!("material `{}`", material.name);
+            let maps = [
+                ("albedo_map", &material.albedo_map),
+                ("normal_map", &material.normal_map),
+                ("metallic_map", &material.metallic_map),
+                ("roughness_map", &material.roughness_map),
+                ("ao_map", &material.ao_map),
+                ("opacity_map", &material.opacity_map)
+            ];
+
+            for (field, texture) in maps {
+                if let Some(texture) = texture.as_ref().filter(|t| !texture_names.contains(t.as_str())) {
+                    problems.push(format!("{}: {} references unknown texture `{}`", entry, field, texture));
+                }
+            }
+
+            let factors = [
+                ("metallic", material.metallic),
+                ("roughness", material.roughness),
+                ("ao_strength", material.ao_strength),
+                ("alpha_cutoff", material.alpha_cutoff)
+            ];
+
+            for (field, value) in factors {
+                if !(0.0..=1.0).contains(&value) {
+                    problems.push(format!("{}: {} {} is outside [0, 1]", entry, field, value));
+                }
+            }
+
+            if material.base_color.iter().any(|c| !non_negative(*c)) {
+                problems.push(format!("{}: base_color has negative components", entry));
+            }
+        }
+
+        let mut nodes = self.nodes.iter().enumerate().map(|(i, n)| (node_entry("", i, n), n)).collect::<Vec<_>>();
+        while let Some((entry, node)) = nodes.pop() {
+            let mesh = node.mesh.as_ref().map(|m| (m, self.meshes.iter().find(|d| &d.name == m)));
+
+            if let Some((name, None)) = mesh {
+                problems.push(format!("{}: references unknown mesh `{}`", entry, name));
+            }
+
+            if let Some(material) = node.material.as_ref().filter(|m| !material_names.contains(m.as_str())) {
+                problems.push(format!("{}: references unknown material `{}`", entry, material));
+            }
+
+            if let (Some(_), Some((_, Some(mesh)))) = (&node.material, mesh) {
+                if mesh.is_gltf() {
+                    problems.push(format!("{}: glTF mesh `{}` keeps its own materials, remove `material`", entry, mesh.name));
+                }
+            }
+
+            if node.scale.contains(&0.0) {
+                problems.push(format!("{}: scale {:?} has a zero component", entry, node.scale));
+            }
+
+            let values = node.translation.iter().chain(&node.rotation).chain(&node.scale);
+            if values.into_iter().any(|v| !v.is_finite()) {
+                problems.push(format!("{}: transform is not finite", entry));
+            }
+
+            nodes.extend(node.children.iter().enumerate().map(|(i, c)| (node_entry(&entry, i, c), c)));
+        }
+
+        if self.lights.len() > MAX_LIGHTS {
+            problems.push(format!("lights: {} lights exceed the limit of {}", self.lights.len(), MAX_LIGHTS));
+        }
+
+        for (i, light) in self.lights.iter().enumerate() {
+            let (color, intensity) = match light {
+                LightDesc::Directional { direction, color, intensity } => {
+                    if direction.iter().all(|d| *d == 0.0) {
+                        problems.push(format!("light {}: direction is zero", i));
+                    }
+
+                    (color, intensity)
+                },
+                LightDesc::Point { color, intensity, .. } => (color, intensity)
+            };
+
+            if !non_negative(*intensity) || color.iter().any(|c| !non_negative(*c)) {
+                problems.push(format!("light {}: color and intensity must not be negative", i));
+            }
+        }
+
+        // Unused meshes are still loaded, most likely a typo in a node
+        for mesh in &mesh_names {
+            if !uses_mesh(&self.nodes, mesh) {
+                problems.push(format!("mesh `{}`: not referenced by any node", mesh));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SceneFileError { filename: filename.to_string(), problems })
+        }
+    }
+}
+
+fn unique_names<'a>(
+    problems: &mut Vec<String>,
+    kind: &str,
+    names: impl Iterator<Item = &'a String>
+) -> HashSet<&'a str> {
+    let mut unique = HashSet::new();
+    for name in names {
+        if name.is_empty() {
+            problems.push(format!("{}: every {} needs a name", kind, kind));
+        } else if !unique.insert(name.as_str()) {
+            problems.push(format!("{} `{}`: name is used more than once", kind, name));
+        }
+    }
+
+    unique
+}
+
+// Nested nodes read as `node `room` > `lamp``, unnamed ones by their position
+fn node_entry(parent: &str, index: usize, node: &NodeDesc) -> String {
+    let name = if node.name.is_empty() { format!("#{}", index) } else { format!("`{}`", node.name) };
+    if parent.is_empty() { format!("node {}", name) } else { format!("{} > {}", parent, name) }
+}
+
+fn uses_mesh(nodes: &[NodeDesc], mesh: &str) -> bool {
+    nodes.iter().any(|n| n.mesh.as_deref() == Some(mesh) || uses_mesh(&n.children, mesh))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Paths resolve like a file in resources/scenes
+    fn problems(source: &str) -> Vec<String> {
+        let mut scene = ron::from_str::<SceneFile>(source).unwrap();
+        scene.resolve_paths(&Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/scenes"));
+
+        match scene.validate("test.ron") {
+            Ok(()) => vec![],
+            Err(e) => e.problems
+        }
+    }
+
+    fn assert_problems(source: &str, expected: &[&str]) {
+        let mut problems = problems(source);
+        problems.sort();
+        let mut expected = expected.to_vec();
+        expected.sort();
+        assert_eq!(problems, expected);
+    }
+
+    const ROOM: &str = r#"
+        environment: "../environment/sky.hdr",
+        meshes: [(name: "room", path: "../viking_room.obj")],
+    "#;
+
+    #[test]
+    fn shipped_scenes_are_valid() {
+        for name in ["viking_room", "jvctv"] {
+            let path = format!("{}/resources/scenes/{}.ron", env!("CARGO_MANIFEST_DIR"), name);
+            SceneFile::load(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn minimal_scene_is_valid() {
+        assert_problems(&format!(r#"Scene({} nodes: [(name: "room", mesh: Some("room"))])"#, ROOM), &[]);
+    }
+
+    #[test]
+    fn missing_files_and_wrong_extensions() {
+        let problems = problems(r#"Scene(
+            environment: "../viking_room.png",
+            textures: [(name: "gone", path: "missing.png"), (name: "obj", path: "../viking_room.obj")],
+            meshes: [(name: "png", path: "../texture.png")],
+            nodes: [(mesh: Some("png"))],
+        )"#);
+
+        assert_eq!(problems.len(), 4, "{:#?}", problems);
+        assert!(problems[0].starts_with("environment: ") && problems[0].ends_with("viking_room.png` is not one of hdr"));
+        assert!(problems[1].starts_with("texture `gone`: file `") && problems[1].ends_with("missing.png` does not exist"));
+        assert!(problems[2].ends_with("viking_room.obj` is not one of png"));
+        assert!(problems[3].ends_with("texture.png` is not one of obj, gltf, glb"));
+    }
+
+    #[test]
+    fn names_must_be_unique_and_present() {
+        assert_problems(&format!(r#"Scene({}
+            textures: [(name: "", path: "../texture.png")],
+            materials: [(name: "a"), (name: "a"), (name: "b")],
+            nodes: [(mesh: Some("room"))],
+        )"#, ROOM), &[
+            "texture: every texture needs a name",
+            "material `a`: name is used more than once"
+        ]);
+    }
+
+    #[test]
+    fn unknown_references() {
+        assert_problems(&format!(r#"Scene({}
+            materials: [(name: "wood", albedo_map: Some("bark"), ao_map: Some("dirt"))],
+            nodes: [
+                (name: "room", mesh: Some("room"), material: Some("stone"), children: [
+                    (name: "lamp", mesh: Some("lamp")),
+                    (mesh: Some("room"), material: Some("wood")),
+                ]),
+            ],
+        )"#, ROOM), &[
+            "material `wood`: albedo_map references unknown texture `bark`",
+            "material `wood`: ao_map references unknown texture `dirt`",
+            "node `room`: references unknown material `stone`",
+            "node `room` > `lamp`: references unknown mesh `lamp`"
+        ]);
+    }
+
+    #[test]
+    fn gltf_meshes_keep_their_materials() {
+        assert_problems(r#"Scene(
+            environment: "../environment/sky.hdr",
+            materials: [(name: "red", base_color: (1.0, 0.0, 0.0, 1.0))],
+            meshes: [(name: "box", path: "../../tests/fixtures/gltf/box.gltf")],
+            nodes: [(name: "box", mesh: Some("box"), material: Some("red"))],
+        )"#, &["node `box`: glTF mesh `box` keeps its own materials, remove `material`"]);
+    }
+
+    #[test]
+    fn factors_camera_and_transforms_are_checked() {
+        assert_problems(&format!(r#"Scene({}
+            camera: (fov: 180.0, near: 1.0, far: 1.0, position: (1.0, 1.0, 1.0), target: (1.0, 1.0, 1.0)),
+            materials: [(name: "bad", metallic: 1.5, roughness: -0.1, alpha_cutoff: 2.0, base_color: (1.0, -1.0, 1.0, 1.0))],
+            nodes: [(mesh: Some("room"), scale: (1.0, 0.0, 1.0), rotation: (inf, 0.0, 0.0))],
+        )"#, ROOM), &[
+            "camera: fov 180 is outside (0, 180) degrees",
+            "camera: near 1 and far 1 need 0 < near < far",
+            "camera: position and target are the same point",
+            "material `bad`: metallic 1.5 is outside [0, 1]",
+            "material `bad`: roughness -0.1 is outside [0, 1]",
+            "material `bad`: alpha_cutoff 2 is outside [0, 1]",
+            "material `bad`: base_color has negative components",
+            "node #0: scale [1.0, 0.0, 1.0] has a zero component",
+            "node #0: transform is not finite"
+        ]);
+    }
+
+    #[test]
+    fn lights_are_checked() {
+        assert_problems(&format!(r#"Scene({}
+            nodes: [(mesh: Some("room"))],
+            lights: [
+                Directional(direction: (0.0, 0.0, 0.0), intensity: 1.0),
+                Point(position: (0.0, 0.0, 0.0), intensity: -1.0),
+                Point(position: (0.0, 0.0, 0.0), color: (1.0, -0.5, 1.0), intensity: 1.0),
+            ],
+        )"#, ROOM), &[
+            "light 0: direction is zero",
+            "light 1: color and intensity must not be negative",
+            "light 2: color and intensity must not be negative"
+        ]);
+
+        let lights = ["Point(position: (0.0, 0.0, 0.0), intensity: 1.0)"; MAX_LIGHTS + 1].join(", ");
+        assert_eq!(
+            problems(&format!(r#"Scene({} nodes: [(mesh: Some("room"))], lights: [{}])"#, ROOM, lights)),
+            [format!("lights: {} lights exceed the limit of {}", MAX_LIGHTS + 1, MAX_LIGHTS)]
+        );
+    }
+
+    #[test]
+    fn unused_meshes_are_reported() {
+        assert_problems(&format!(r#"Scene({} nodes: [(name: "empty")])"#, ROOM), &["mesh `room`: not referenced by any node"]);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let source = format!(r#"Scene({} materials: [(name: "typo", metalic: 0.0)])"#, ROOM);
+        let error = ron::from_str::<SceneFile>(&source).unwrap_err().to_string();
+        assert!(error.contains("metalic"), "{}", error);
+    }
+}
@@ -1,18 +1,32 @@
 use std::fs::File;
+use std::io::Read;
 use std::ptr::copy_nonoverlapping as memcpy;
 
 use vulkanalia::prelude::v1_0::*;
-use anyhow::{Result, Ok};
+use anyhow::{anyhow, Result};
+use thiserror::Error;
 
 use crate::AppData;
-use crate::shared_commands::begin_single_time_commands;
+use crate::graphics::allocator::*;
 use crate::shared_memory::*;
 
-#[derive(Clone, Debug)]
+const MAX_ANISOTROPY: f32 = 16.0;
+
+#[derive(Debug, Error)]
+pub enum TextureError {
+    #[error("`{0}` ({1:?}, {2:?}) cannot be loaded as {3:?}.")]
+    IncompatibleFormat(String, png::ColorType, png::BitDepth, vk::Format),
+    #[error("`{0}` is {1}x{2} but the other layers are {3}x{4}.")]
+    MismatchedLayerSize(String, u32, u32, u32, u32),
+    #[error("A cubemap needs 6 square faces, got {0} of {1}x{2}.")]
+    InvalidCubemap(usize, u32, u32)
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Texture {
-    image: vk::Image,
+    pub image: vk::Image,
     image_layout: vk::ImageLayout,
-    device_memory: vk::DeviceMemory,
+    allocation: Allocation,
     pub image_view: vk::ImageView,
 
     width: u32,
@@ -20,26 +34,48 @@ pub struct Texture {
     mip_levels: u32,
     layer_count: u32,
 
-    descriptor: vk::DescriptorImageInfo,
+    pub descriptor: vk::DescriptorImageInfo,
     sampler: Option<vk::Sampler>
 }
 
 impl Texture {
+    pub fn new(
+        image: vk::Image,
+        allocation: Allocation,
+        image_view: vk::ImageView,
+        image_layout: vk::ImageLayout,
+        width: u32,
+        height: u32,
+        mip_levels: u32,
+        layer_count: u32,
+        sampler: Option<vk::Sampler>
+    ) -> Self {
+        let mut texture = Texture {
+            image, image_layout, allocation, image_view,
+            width, height, mip_levels, layer_count,
+            descriptor: vk::DescriptorImageInfo::default(),
+            sampler
+        };
+
+        texture.update_descriptor();
+        texture
+    }
+
     fn update_descriptor(&mut self) {
         self.descriptor.sampler = self.sampler.unwrap_or(vk::Sampler::null());
         self.descriptor.image_view = self.image_view;
         self.descriptor.image_layout = self.image_layout;
     }
 
-    pub unsafe fn destroy(&self, device: &Device) {
+    pub unsafe fn destroy(&self, device: &Device, data: &AppData) {
         device.destroy_image_view(self.image_view, None);
         device.destroy_image(self.image, None);
 
-        if self.sampler.is_some() {
-            device.destroy_sampler(self.sampler.unwrap(), None);
+        if let Some(sampler) = self.sampler {
+            device.destroy_sampler(sampler, None);
         }
 
-        device.free_memory(self.device_memory, None);
+        data.allocator.free(device, &self.allocation);
     }
 }
 
@@ -52,398 +88,758 @@ impl Texture2D {
     pub unsafe fn load_from_file(
         instance: &Instance,
         device: &Device,
-        data: &AppData, // TODO: move appdata to device?
+        data: &AppData,
         filename: &str,
         format: vk::Format,
-        // copy_queue: vk::Queue, TODO: restore queue while removing data
-        image_usage_flags: Option<vk::ImageUsageFlags>, // VK_IMAGE_USAGE_SAMPLED_BIT
-        image_layout: Option<vk::ImageLayout> // VK_IMAGE_LAYOUT_SHADER_READ_ONLY_OPTIMAL // TODO: explicit optional params
+        // SAMPLED and SHADER_READ_ONLY_OPTIMAL when `None`
+        image_usage_flags: Option<vk::ImageUsageFlags>,
+        image_layout: Option<vk::ImageLayout>
     ) -> Result<Self> {
-        let image = File::open(filename).unwrap();
-        let decoder = png::Decoder::new(image);
-        let mut reader = decoder.read_info().unwrap();
-
-        let mut pixels = vec![0; reader.info().raw_bytes()];
-        reader.next_frame(&mut pixels)?;
-
-        let size = reader.info().raw_bytes() as u64;
-        let (width, height) = reader.info().size();
-        let mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
-
-        // TODO: move to dedicated create_command_buffer function
-        let cmd_info = vk::CommandBufferAllocateInfo::builder()
-            .level(vk::CommandBufferLevel::PRIMARY)
-            .command_pool(data.command_pool)
-            .command_buffer_count(1)
-            .build();
+        let (pixels, width, height, format, _) = decode_png(filename, format)?;
+        Texture2D::from_pixels(instance, device, data, pixels, width, height, format, image_usage_flags, image_layout)
+    }
 
-        let copy_cmd = device.allocate_command_buffers(&cmd_info)?[0];
+    // Uploads tightly packed level 0 texels of `format` and builds the mip chain
+    pub unsafe fn from_pixels(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        image_usage_flags: Option<vk::ImageUsageFlags>,
+        image_layout: Option<vk::ImageLayout>
+    ) -> Result<Self> {
+        let texture = upload_layers(
+            instance, device, data,
+            vec![pixels],
+            width, height,
+            format,
+            vk::ImageViewType::_2D,
+            vk::SamplerAddressMode::REPEAT,
+            image_usage_flags, image_layout
+        )?;
 
-        // Begin command buffer
-        let begin_info = vk::CommandBufferBeginInfo::builder().build();
-        device.begin_command_buffer(copy_cmd, &begin_info)?;
+        Ok(Texture2D { texture })
+    }
+}
 
-        let staging_buffer_info = vk::BufferCreateInfo::builder()
-            .size(size)
-            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .build();
+#[derive(Clone, Debug)]
+pub struct TextureCube {
+    pub texture: Texture
+}
 
-        let staging_buffer = device.create_buffer(&staging_buffer_info, None)?;
-        let mut mem_reqs = device.get_buffer_memory_requirements(staging_buffer);
+impl TextureCube {
+    // Faces in Vulkan layer order: +X, -X, +Y, -Y, +Z, -Z
+    pub unsafe fn load_from_files(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        filenames: &[&str; 6],
+        format: vk::Format,
+        image_usage_flags: Option<vk::ImageUsageFlags>,
+        image_layout: Option<vk::ImageLayout>
+    ) -> Result<Self> {
+        let (faces, width, height, format) = decode_layers(filenames, format)?;
+        TextureCube::from_pixels(instance, device, data, faces, width, height, format, image_usage_flags, image_layout)
+    }
 
-        let mut mem_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(size)
-            .memory_type_index(get_memory_type_index(
-                instance, data,
-                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-                mem_reqs
-            )?)
-            .build();
+    pub unsafe fn from_pixels(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        faces: Vec<Vec<u8>>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        image_usage_flags: Option<vk::ImageUsageFlags>,
+        image_layout: Option<vk::ImageLayout>
+    ) -> Result<Self> {
+        if faces.len() != 6 || width != height {
+            return Err(anyhow!(TextureError::InvalidCubemap(faces.len(), width, height)));
+        }
 
-        let staging_mem = device.allocate_memory(&mem_info, None)?;
-        device.bind_buffer_memory(staging_buffer, staging_mem, 0)?;
-
-        let mem_dst = device.map_memory(staging_mem, 0, mem_reqs.size, vk::MemoryMapFlags::empty())?;
-        memcpy(pixels.as_ptr(), mem_dst.cast(), pixels.len());
-        device.unmap_memory(staging_mem);
-
-        // ========================================================
-        // GENERATE MIPMAPS
-        // ========================================================
-
-        // let mut buffer_copy_regions: Vec<vk::BufferImageCopy> = vec![];
-        // let mut offset: u64 = 0;
-
-        // TODO: here's the problem: the library used (GLI) includes mipmaps generation
-        // We are reading the raw image, so no mipmaps are available
-        // Thus, it's trying to read on nothing
-        // TODO: add mipmap generation using blit
-
-        // for i in 0..mip_levels {
-        //     let subres = vk::ImageSubresourceLayers::builder()
-        //         .aspect_mask(vk::ImageAspectFlags::COLOR)
-        //         .mip_level(i)
-        //         .base_array_layer(0)
-        //         .layer_count(1)
-        //         .build();
-
-        //     let buffer_copy_region = vk::BufferImageCopy::builder()
-        //         .image_subresource(subres)
-        //         .image_extent(vk::Extent3D { width: mip_width, height: mip_height, depth: 1 })
-        //         .buffer_offset(offset)
-        //         .build();
-
-        //     buffer_copy_regions.push(buffer_copy_region);
-        //     offset += size;
-        // }
-
-        let cmd_buffer = begin_single_time_commands(device, data)?;
-        let subres = vk::ImageSubresourceRange::builder()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_array_layer(0)
-            .layer_count(1)
-            .level_count(1)
-            .build();
+        let texture = upload_layers(
+            instance, device, data,
+            faces,
+            width, height,
+            format,
+            vk::ImageViewType::CUBE,
+            vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            image_usage_flags, image_layout
+        )?;
+
+        Ok(TextureCube { texture })
+    }
+}
 
-        let mut mip_barrier = vk::ImageMemoryBarrier::builder()
-            .image(image)
+#[derive(Clone, Debug)]
+pub struct Texture2DArray {
+    pub texture: Texture
+}
+
+impl Texture2DArray {
+    pub unsafe fn load_from_files(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        filenames: &[&str],
+        format: vk::Format,
+        image_usage_flags: Option<vk::ImageUsageFlags>,
+        image_layout: Option<vk::ImageLayout>
+    ) -> Result<Self> {
+        let (layers, width, height, format) = decode_layers(filenames, format)?;
+        Texture2DArray::from_pixels(instance, device, data, layers, width, height, format, image_usage_flags, image_layout)
+    }
+
+    pub unsafe fn from_pixels(
+        instance: &Instance,
+        device: &Device,
+        data: &AppData,
+        layers: Vec<Vec<u8>>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        image_usage_flags: Option<vk::ImageUsageFlags>,
+        image_layout: Option<vk::ImageLayout>
+    ) -> Result<Self> {
+        if layers.is_empty() {
+            return Err(anyhow!("A texture array needs at least one layer."));
+        }
+
+        let texture = upload_layers(
+            instance, device, data,
+            layers,
+            width, height,
+            format,
+            vk::ImageViewType::_2D_ARRAY,
+            vk::SamplerAddressMode::REPEAT,
+            image_usage_flags, image_layout
+        )?;
+
+        Ok(Texture2DArray { texture })
+    }
+}
+
+// Uploads one tightly packed level 0 per layer and builds the mip chain of every layer
+unsafe fn upload_layers(
+    instance: &Instance,
+    device: &Device,
+    data: &AppData,
+    layers: Vec<Vec<u8>>,
+    width: u32,
+    height: u32,
+    format: vk::Format,
+    view_type: vk::ImageViewType,
+    address_mode: vk::SamplerAddressMode,
+    image_usage_flags: Option<vk::ImageUsageFlags>,
+    image_layout: Option<vk::ImageLayout>
+) -> Result<Texture> {
+    let layout = pixel_layout(format).ok_or_else(|| anyhow!("Unsupported texture format {:?}.", format))?;
+    let mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
+    let layer_count = layers.len() as u32;
+    let image_layout = image_layout.unwrap_or(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+    // Blit the mip chain on the GPU when possible, otherwise upload box-filtered levels
+    let blit_mipmaps = supports_linear_blit(instance, data, format);
+
+    // Every layer lands in the same staging buffer, each starting on an aligned offset
+    let alignment = 4 * layout.channels * layout.bytes_per_channel;
+    let mut pixels = Vec::new();
+    let mut buffer_copy_regions = Vec::new();
+
+    for (layer, mut layer_pixels) in layers.into_iter().enumerate() {
+        let mip_chain = if blit_mipmaps {
+            vec![MipLevel { offset: 0, width, height }]
+        } else {
+            generate_mip_chain(&mut layer_pixels, width, height, layout, mip_levels)
+        };
+
+        pixels.resize(pixels.len().div_ceil(alignment) * alignment, 0);
+        let layer_offset = pixels.len() as u64;
+        pixels.extend_from_slice(&layer_pixels);
+
+        buffer_copy_regions.extend(mip_chain.iter().enumerate().map(|(i, level)| {
+            let subres = vk::ImageSubresourceLayers::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(i as u32)
+                .base_array_layer(layer as u32)
+                .layer_count(1)
+                .build();
+
+            vk::BufferImageCopy::builder()
+                .image_subresource(subres)
+                .image_extent(vk::Extent3D { width: level.width, height: level.height, depth: 1 })
+                .buffer_offset(layer_offset + level.offset)
+                .build()
+        }));
+    }
+
+    let size = pixels.len() as u64;
+
+    let cmd_info = vk::CommandBufferAllocateInfo::builder()
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_pool(data.command_pool)
+        .command_buffer_count(1)
+        .build();
+
+    let copy_cmd = device.allocate_command_buffers(&cmd_info)?[0];
+
+    let begin_info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+        .build();
+    device.begin_command_buffer(copy_cmd, &begin_info)?;
+
+    let staging_buffer_info = vk::BufferCreateInfo::builder()
+        .size(size)
+        .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .build();
+
+    let staging_buffer = device.create_buffer(&staging_buffer_info, None)?;
+
+    let staging_allocation = data.allocator.allocate_buffer(
+        device,
+        staging_buffer,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT
+    )?;
+    device.bind_buffer_memory(staging_buffer, staging_allocation.memory, staging_allocation.offset)?;
+
+    let mem_dst = staging_allocation.mapped_ptr()?;
+    memcpy(pixels.as_ptr(), mem_dst.cast(), pixels.len());
+
+    let image_flags = if view_type == vk::ImageViewType::CUBE {
+        vk::ImageCreateFlags::CUBE_COMPATIBLE
+    } else {
+        vk::ImageCreateFlags::empty()
+    };
+
+    let image_info = vk::ImageCreateInfo::builder()
+        .flags(image_flags)
+        .image_type(vk::ImageType::_2D)
+        .format(format)
+        .mip_levels(mip_levels)
+        .array_layers(layer_count)
+        .samples(vk::SampleCountFlags::_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .extent(vk::Extent3D { width, height, depth: 1 })
+        .usage(
+            image_usage_flags.unwrap_or(vk::ImageUsageFlags::SAMPLED) |
+            vk::ImageUsageFlags::TRANSFER_DST |
+            vk::ImageUsageFlags::TRANSFER_SRC
+        )
+        .build();
+
+    let image = device.create_image(&image_info, None)?;
+
+    let allocation = data.allocator.allocate_image(device, image, vk::MemoryPropertyFlags::DEVICE_LOCAL, ResourceKind::Optimal)?;
+    device.bind_image_memory(image, allocation.memory, allocation.offset)?;
+
+    let subres_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .layer_count(layer_count)
+        .build();
+    
+    // Barrier
+    {
+        let image_memory_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
             .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
             .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-            .subresource_range(subres)
+            .image(image)
+            .subresource_range(subres_range)
             .build();
 
-        // ========================================================
-        // END GENERATE MIPMAPS
-        // ========================================================
-
-        let image_info = vk::ImageCreateInfo::builder()
-            .image_type(vk::ImageType::_2D)
-            .format(format)
-            .mip_levels(mip_levels)
-            .array_layers(1)
-            .samples(vk::SampleCountFlags::_1)
-            .tiling(vk::ImageTiling::OPTIMAL)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .initial_layout(vk::ImageLayout::UNDEFINED)
-            .extent(vk::Extent3D { width, height, depth: 1 })
-            .usage(image_usage_flags.unwrap_or(vk::ImageUsageFlags::SAMPLED) | vk::ImageUsageFlags::TRANSFER_DST)
+        device.cmd_pipeline_barrier(
+            copy_cmd,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[image_memory_barrier]
+        );
+    }
+
+    device.cmd_copy_buffer_to_image(
+        copy_cmd, 
+        staging_buffer,
+        image, 
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        &buffer_copy_regions
+    );
+
+    if blit_mipmaps {
+        cmd_generate_mipmaps(device, copy_cmd, image, width, height, mip_levels, layer_count, image_layout, vk::PipelineStageFlags::FRAGMENT_SHADER);
+    } else {
+        let image_memory_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(image_layout)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subres_range)
             .build();
 
-        let image = device.create_image(&image_info, None)?;
+        device.cmd_pipeline_barrier(
+            copy_cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[image_memory_barrier]
+        );
+    }
 
-        mem_reqs = device.get_image_memory_requirements(image);
-        mem_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_reqs.size)
-            .memory_type_index(get_memory_type_index(
-                instance, data,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-                mem_reqs
-            )?)
-            .build();
+    device.end_command_buffer(copy_cmd)?;
+
+    let command_buffers = &[copy_cmd];
+    let submit_info = vk::SubmitInfo::builder().command_buffers(command_buffers).build();
+    let fence_info = vk::FenceCreateInfo::builder().build();
+    let fence = device.create_fence(&fence_info, None)?;
+
+    // Blits need a graphics queue, transfers alone would do on any queue
+    device.queue_submit(data.graphics_queue, &[submit_info], fence)?;
+    device.wait_for_fences(&[fence], true, 100000000000)?;
+    device.destroy_fence(fence, None);
+    device.free_command_buffers(data.command_pool, &[copy_cmd]);
+
+    // Clean up staging
+    device.destroy_buffer(staging_buffer, None);
+    data.allocator.free(device, &staging_allocation);
+
+    // Ignored unless the feature is enabled
+    let max_anisotropy = if data.sampler_anisotropy {
+        instance.get_physical_device_properties(data.physical_device).limits.max_sampler_anisotropy.min(MAX_ANISOTROPY)
+    } else {
+        1.0
+    };
+
+    let sampler_info = vk::SamplerCreateInfo::builder()
+        .mag_filter(vk::Filter::LINEAR)
+        .min_filter(vk::Filter::LINEAR)
+        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+        .address_mode_u(address_mode)
+        .address_mode_v(address_mode)
+        .address_mode_w(address_mode)
+        .mip_lod_bias(0.0)
+        .compare_op(vk::CompareOp::NEVER)
+        .min_lod(0.0)
+        .max_lod(mip_levels as f32)
+        .anisotropy_enable(data.sampler_anisotropy)
+        .max_anisotropy(max_anisotropy)
+        .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
+        .build();
+
+    let sampler = device.create_sampler(&sampler_info, None)?;
+
+    let image_view_subres_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(layer_count)
+        .build();
+
+    let image_view_info = vk::ImageViewCreateInfo::builder()
+        .view_type(view_type)
+        .format(format)
+        .components(vk::ComponentMapping {
+            r: vk::ComponentSwizzle::R,
+            g: vk::ComponentSwizzle::G,
+            b: vk::ComponentSwizzle::B,
+            a: vk::ComponentSwizzle::A
+        })
+        .subresource_range(image_view_subres_range)
+        .image(image)
+        .build();
+
+    let image_view = device.create_image_view(&image_view_info, None)?;
+
+    Ok(Texture::new(
+        image, allocation, image_view, image_layout,
+        width, height, mip_levels, layer_count,
+        Some(sampler)
+    ))
+}
 
-        let device_memory = device.allocate_memory(&mem_info, None)?;
-        device.bind_image_memory(image, device_memory, 0)?;
+struct MipLevel {
+    offset: u64,
+    width: u32,
+    height: u32
+}
 
-        let subres_range = vk::ImageSubresourceRange::builder()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_mip_level(0)
-            .level_count(mip_levels)
-            .layer_count(1)
-            .build();
-        
-        // Barrier
-        {
-            let image_memory_barrier = vk::ImageMemoryBarrier::builder()
-                .old_layout(vk::ImageLayout::UNDEFINED)
-                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                .src_access_mask(vk::AccessFlags::empty())
-                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-                .image(image)
-                .subresource_range(subres_range)
-                .build();
+#[derive(Copy, Clone, Debug)]
+struct PixelLayout {
+    channels: usize,
+    bytes_per_channel: usize,
+    // Color channels are sRGB encoded, alpha is always linear
+    srgb: bool
+}
+
+fn pixel_layout(format: vk::Format) -> Option<PixelLayout> {
+    let (channels, bytes_per_channel) = match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SRGB => (1, 1),
+        vk::Format::R8G8_UNORM | vk::Format::R8G8_SRGB => (2, 1),
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => (4, 1),
+        vk::Format::R16_UNORM => (1, 2),
+        vk::Format::R16G16_UNORM => (2, 2),
+        vk::Format::R16G16B16A16_UNORM => (4, 2),
+        _ => return None
+    };
+
+    let srgb = matches!(format, vk::Format::R8_SRGB | vk::Format::R8G8_SRGB | vk::Format::R8G8B8A8_SRGB);
+    Some(PixelLayout { channels, bytes_per_channel, srgb })
+}
+
+// Format used when the caller passes vk::Format::UNDEFINED
+fn matching_format(color_type: png::ColorType, bit_depth: png::BitDepth) -> vk::Format {
+    match (color_type, bit_depth) {
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => vk::Format::R16_UNORM,
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen) => vk::Format::R16G16_UNORM,
+        (_, png::BitDepth::Sixteen) => vk::Format::R16G16B16A16_UNORM,
+        (png::ColorType::Grayscale, _) => vk::Format::R8_UNORM,
+        (png::ColorType::GrayscaleAlpha, _) => vk::Format::R8G8_UNORM,
+        _ => vk::Format::R8G8B8A8_UNORM
+    }
+}
+
+fn decode_png(
+    filename: &str,
+    format: vk::Format
+) -> Result<(Vec<u8>, u32, u32, vk::Format, PixelLayout)> {
+    decode_png_from(File::open(filename)?, filename, format)
+}
+
+// Decodes a PNG into tightly packed texels of `format`, expanding palettes,
+// low bit depths and missing channels as needed. Color images loaded into
+// one or two channels keep their Rec. 709 luminance, plus alpha for two.
+fn decode_png_from(
+    source: impl Read,
+    filename: &str,
+    format: vk::Format
+) -> Result<(Vec<u8>, u32, u32, vk::Format, PixelLayout)> {
+    let mut decoder = png::Decoder::new(source);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+
+    let mut raw = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut raw)?;
+    let (width, height) = (info.width, info.height);
+    let (color_type, bit_depth) = (info.color_type, info.bit_depth);
+
+    let format = if format == vk::Format::UNDEFINED { matching_format(color_type, bit_depth) } else { format };
+    let incompatible = || anyhow!(TextureError::IncompatibleFormat(filename.into(), color_type, bit_depth, format));
+
+    let layout = pixel_layout(format).ok_or_else(incompatible)?;
+
+    let src_channels = color_type.samples();
+    let src_bytes = if bit_depth == png::BitDepth::Sixteen { 2 } else { 1 };
+    let texels = (width * height) as usize;
+
+    let mut pixels = Vec::with_capacity(texels * layout.channels * layout.bytes_per_channel);
+    for texel in raw[..texels * src_channels * src_bytes].chunks_exact(src_channels * src_bytes) {
+        // Widen everything to 16 bits, PNG stores samples big-endian
+        let sample = |c: usize| if src_bytes == 2 {
+            u16::from_be_bytes([texel[2 * c], texel[2 * c + 1]])
+        } else {
+            texel[c] as u16 * 257
+        };
+
+        // The weights sum to 10000, so gray stored as RGB comes out unchanged
+        let luminance = || ((2126 * sample(0) as u32 + 7152 * sample(1) as u32 + 722 * sample(2) as u32 + 5000) / 10000) as u16;
+
+        let values = match (src_channels, layout.channels) {
+            (1 | 2, 1) => [sample(0), 0, 0, 0],
+            (3 | 4, 1) => [luminance(), 0, 0, 0],
+            (1, 2) => [sample(0), u16::MAX, 0, 0],
+            (2, 2) => [sample(0), sample(1), 0, 0],
+            (3, 2) => [luminance(), u16::MAX, 0, 0],
+            (4, 2) => [luminance(), sample(3), 0, 0],
+            (1, 4) => [sample(0), sample(0), sample(0), u16::MAX],
+            (2, 4) => [sample(0), sample(0), sample(0), sample(1)],
+            (3, 4) => [sample(0), sample(1), sample(2), u16::MAX],
+            _ => [sample(0), sample(1), sample(2), sample(3)]
+        };
 
-            device.cmd_pipeline_barrier(
-                copy_cmd,
-                vk::PipelineStageFlags::ALL_COMMANDS,
-                vk::PipelineStageFlags::ALL_COMMANDS,
-                vk::DependencyFlags::empty(),
-                &[] as &[vk::MemoryBarrier],
-                &[] as &[vk::BufferMemoryBarrier],
-                &[image_memory_barrier]
-            );
+        for value in &values[..layout.channels] {
+            if layout.bytes_per_channel == 2 {
+                pixels.extend_from_slice(&value.to_le_bytes());
+            } else {
+                pixels.push((value >> 8) as u8);
+            }
         }
+    }
 
-        device.cmd_copy_buffer_to_image(
-            copy_cmd, 
-            staging_buffer,
-            image, 
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            &buffer_copy_regions
-        );
+    Ok((pixels, width, height, format, layout))
+}
 
-        {
-            let image_memory_barrier = vk::ImageMemoryBarrier::builder()
-                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                .new_layout(image_layout.unwrap_or(vk::ImageLayout::READ_ONLY_OPTIMAL))
-                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
-                .image(image)
-                .subresource_range(subres_range)
-                .build();
+// Decodes every file with the format resolved from the first one, all layers must match in size
+fn decode_layers(
+    filenames: &[&str],
+    format: vk::Format
+) -> Result<(Vec<Vec<u8>>, u32, u32, vk::Format)> {
+    let (first, rest) = filenames.split_first().ok_or_else(|| anyhow!("No layers to load."))?;
+    let (pixels, width, height, format, _) = decode_png(first, format)?;
+    let mut layers = vec![pixels];
+
+    for filename in rest {
+        let (pixels, layer_width, layer_height, _, _) = decode_png(filename, format)?;
+        if (layer_width, layer_height) != (width, height) {
+            return Err(anyhow!(TextureError::MismatchedLayerSize((*filename).into(), layer_width, layer_height, width, height)));
+        }
+
+        layers.push(pixels);
+    }
 
-            device.cmd_pipeline_barrier(
-                copy_cmd,
-                vk::PipelineStageFlags::ALL_COMMANDS,
-                vk::PipelineStageFlags::ALL_COMMANDS,
-                vk::DependencyFlags::empty(),
-                &[] as &[vk::MemoryBarrier],
-                &[] as &[vk::BufferMemoryBarrier],
-                &[image_memory_barrier]
-            );
+    Ok((layers, width, height, format))
+}
+
+// Appends box-filtered levels 1..mip_levels to the level 0 pixels. sRGB texels are averaged in linear
+// space like the GPU blit does, otherwise the levels darken.
+fn generate_mip_chain(
+    pixels: &mut Vec<u8>,
+    width: u32,
+    height: u32,
+    layout: PixelLayout,
+    mip_levels: u32
+) -> Vec<MipLevel> {
+    let bytes_per_pixel = layout.channels * layout.bytes_per_channel;
+    let mut levels = vec![MipLevel { offset: 0, width, height }];
+    let linear: [f32; 256] = std::array::from_fn(|i| srgb_to_linear(i as f32 / 255.0));
+
+    for _ in 1..mip_levels {
+        let src = levels.last().unwrap();
+        let (src_offset, src_width, src_height) = (src.offset as usize, src.width as usize, src.height as usize);
+        let dst_width = (src_width / 2).max(1);
+        let dst_height = (src_height / 2).max(1);
+
+        // Copy offsets must be a multiple of both the texel size and 4
+        let alignment = 4 * bytes_per_pixel;
+        pixels.resize(pixels.len().div_ceil(alignment) * alignment, 0);
+        let dst_offset = pixels.len();
+
+        for y in 0..dst_height {
+            let y0 = (2 * y).min(src_height - 1);
+            let y1 = (2 * y + 1).min(src_height - 1);
+
+            for x in 0..dst_width {
+                let x0 = (2 * x).min(src_width - 1);
+                let x1 = (2 * x + 1).min(src_width - 1);
+
+                for c in 0..layout.channels {
+                    let texel = |x: usize, y: usize| {
+                        let i = src_offset + (y * src_width + x) * bytes_per_pixel + c * layout.bytes_per_channel;
+                        if layout.bytes_per_channel == 2 {
+                            u16::from_le_bytes([pixels[i], pixels[i + 1]]) as u32
+                        } else {
+                            pixels[i] as u32
+                        }
+                    };
+
+                    let texels = [texel(x0, y0), texel(x1, y0), texel(x0, y1), texel(x1, y1)];
+                    let average = if layout.srgb && c < 3 {
+                        let average = texels.iter().map(|t| linear[*t as usize]).sum::<f32>() / 4.0;
+                        (linear_to_srgb(average) * 255.0).round() as u32
+                    } else {
+                        (texels.iter().sum::<u32>() + 2) / 4
+                    };
+
+                    if layout.bytes_per_channel == 2 {
+                        pixels.extend_from_slice(&(average as u16).to_le_bytes());
+                    } else {
+                        pixels.push(average as u8);
+                    }
+                }
+            }
         }
 
-        // End command buffer and flush
-        // TODO: move to separate function
-        device.end_command_buffer(copy_cmd)?;
-
-        let submit_info = vk::SubmitInfo::builder().command_buffers(&[copy_cmd]).build();
-        let fence_info = vk::FenceCreateInfo::builder().build();
-        let fence = device.create_fence(&fence_info, None)?;
-
-        // TODO: not sure about the queue if it's the right one
-        device.queue_submit(data.graphics_queue, &[submit_info], fence)?;
-        device.wait_for_fences(&[fence], true, 100000000000)?;
-        device.destroy_fence(fence, None);
-        device.free_command_buffers(data.command_pool, &[copy_cmd]);
-
-        // Clean up staging
-        device.free_memory(staging_mem, None);
-        device.destroy_buffer(staging_buffer, None);
-
-        let sampler_info = vk::SamplerCreateInfo::builder()
-            .mag_filter(vk::Filter::LINEAR)
-            .min_filter(vk::Filter::LINEAR)
-            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-            .address_mode_u(vk::SamplerAddressMode::REPEAT)
-            .address_mode_v(vk::SamplerAddressMode::REPEAT)
-            .address_mode_w(vk::SamplerAddressMode::REPEAT)
-            .mip_lod_bias(0.0)
-            .compare_op(vk::CompareOp::NEVER)
-            .min_lod(0.0)
-            .max_lod(mip_levels as f32)
-            .max_anisotropy(16.0) // TODO: check if device supports aniso
-            .anisotropy_enable(true)
-            .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
-            .build();
+        levels.push(MipLevel { offset: dst_offset as u64, width: dst_width as u32, height: dst_height as u32 });
+    }
 
-        let sampler = device.create_sampler(&sampler_info, None)?;
+    levels
+}
 
-        let image_view_subres_range = vk::ImageSubresourceRange::builder()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_mip_level(0)
-            .level_count(mip_levels) // TODO: why is it different?
-            .base_array_layer(0)
-            .layer_count(1)
-            .build();
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
 
-        let image_view_info = vk::ImageViewCreateInfo::builder()
-            .view_type(vk::ImageViewType::_2D)
-            .format(format)
-            .components(vk::ComponentMapping {
-                r: vk::ComponentSwizzle::R,
-                g: vk::ComponentSwizzle::G,
-                b: vk::ComponentSwizzle::B,
-                a: vk::ComponentSwizzle::A
-            })
-            .subresource_range(image_view_subres_range)
-            .image(image)
-            .build();
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
 
-        let image_view = device.create_image_view(&image_view_info, None)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let descriptor = vk::DescriptorImageInfo::builder()
-            .sampler(sampler)
-            .image_view(image_view)
-            .image_layout(image_layout.unwrap_or(vk::ImageLayout::READ_ONLY_OPTIMAL))
-            .build();
+    const R8: PixelLayout = PixelLayout { channels: 1, bytes_per_channel: 1, srgb: false };
+    const R16: PixelLayout = PixelLayout { channels: 1, bytes_per_channel: 2, srgb: false };
 
-        let texture = Texture {
-            image,
-            image_layout: image_layout.unwrap_or(vk::ImageLayout::READ_ONLY_OPTIMAL),
-            image_view,
-            device_memory,
+    fn level_sizes(levels: &[MipLevel]) -> Vec<(u64, u32, u32)> {
+        levels.iter().map(|l| (l.offset, l.width, l.height)).collect()
+    }
 
-            width, height, mip_levels,
-            layer_count: 1,
+    #[test]
+    fn mip_chain_of_odd_non_square_image() {
+        // 5x3, each texel is 20 * y + 4 * x
+        let mut pixels = (0..3).flat_map(|y| (0..5).map(move |x| 20 * y + 4 * x)).collect::<Vec<u8>>();
+        let levels = generate_mip_chain(&mut pixels, 5, 3, R8, 3);
+
+        // Levels start on multiples of 4 bytes, the odd last column and row are dropped
+        assert_eq!(level_sizes(&levels), [(0, 5, 3), (16, 2, 1), (20, 1, 1)]);
+        assert_eq!(pixels.len(), 21);
+        assert_eq!(pixels[16..18], [(4 + 20 + 24 + 2) / 4, (8 + 12 + 28 + 32 + 2) / 4]);
+        assert_eq!(pixels[20], (12 + 20 + 12 + 20 + 2) / 4);
+    }
 
-            sampler: Some(sampler),
-            descriptor
-        };
+    #[test]
+    fn mip_chain_of_tall_image_clamps_width() {
+        let mut pixels = vec![0, 255, 100, 200];
+        let levels = generate_mip_chain(&mut pixels, 1, 4, R8, 3);
 
-        Ok(Texture2D { texture })
+        assert_eq!(level_sizes(&levels), [(0, 1, 4), (4, 1, 2), (8, 1, 1)]);
+        assert_eq!(pixels[4..6], [128, 150]);
+        assert_eq!(pixels[8], 139);
     }
-}
 
-/*
-
-X load texture in library
-X get width, height, mip levels
-X get VkFormatProperties (not used)
-X create copy command buffer
-X create staging buffer
-X get memory requirements for staging buffer
-X allocate and bind staging buffer
-X memcpy inside this buffer
-X unmap staging memory
-X create mip levels
-    X for each mip level, create a new VkBufferImageCopy
-X create image
-X get memory requirement for image
-X allocate and bind device memory for images
-- create subresource range
-- in a closure, create image memory barrier
-- add command copy buffer to image
-- assign image layout property
-- in a closure, create another image memory barrier
-- flush copy command buffer
-- free staging memory and destroy the staging buffer
-- create sampler
-- create image view
-- update texture descriptor
-
-*/
-
-// #[derive(Copy, Clone, Debug, Default)]
-// pub struct Texture {
-//     mip_levels: u32,
-
-//     pub image: vk::Image,
-//     pub image_memory: vk::DeviceMemory,
-//     pub image_view: vk::ImageView,
-// }
-
-// impl Texture {
-//     pub unsafe fn from_filepath(
-//         filepath: String,
-//         instance: &Instance,
-//         device: &Device,
-//         data: &AppData
-//     ) -> Result<Self> {
-//         let image = File::open(filepath)?;
-//         let decoder = png::Decoder::new(image);
-//         let mut reader = decoder.read_info()?;
-
-//         let mut pixels = vec![0; reader.info().raw_bytes()];
-//         reader.next_frame(&mut pixels)?;
-
-//         let size = reader.info().raw_bytes() as u64;
-//         let (width, height) = reader.info().size();
-//         let mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
-
-//         let (staging_buffer, staging_buffer_memory) = create_buffer(
-//             instance, device, data,
-//             size,
-//             vk::BufferUsageFlags::TRANSFER_SRC,
-//             vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
-//         )?;
-        
-//         let memory = device.map_memory(
-//             staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty()
-//         )?;
-        
-//         memcpy(pixels.as_ptr(), memory.cast(), pixels.len());
-//         device.unmap_memory(staging_buffer_memory);
-        
-//         // TODO: add format determination
-//         // println!("{} bit - {:?}", reader.info().bit_depth as usize, reader.info().color_type);
-
-//         let (image, image_memory) = create_image(
-//             instance, device, data,
-//             width, height,
-//             mip_levels,
-//             vk::SampleCountFlags::_1,
-//             vk::Format::R8G8B8A8_SRGB,
-//             vk::ImageTiling::OPTIMAL,
-//             vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC,
-//             vk::MemoryPropertyFlags::DEVICE_LOCAL
-//         )?;
-
-//         transition_image_layout(
-//             device, data,
-//             image,
-//             vk::Format::R8G8B8A8_SRGB,
-//             vk::ImageLayout::UNDEFINED,
-//             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-//             mip_levels
-//         )?;
-
-//         copy_buffer_to_image(device, data, staging_buffer, image, width, height)?;
-
-//         device.destroy_buffer(staging_buffer, None);
-//         device.free_memory(staging_buffer_memory, None);
-
-//         generate_mipmaps(
-//             instance, device, data,
-//             image,
-//             vk::Format::R8G8B8A8_SRGB,
-//             width, height,
-//             mip_levels
-//         )?;
-
-//         let image_view = create_image_view(device, image, vk::Format::R8G8B8A8_SRGB, vk::ImageAspectFlags::COLOR, mip_levels)?;
-
-//         Ok(Self {
-//             mip_levels,
-//             image,
-//             image_memory,
-//             image_view
-//         })
-//     }
-
-//     pub unsafe fn destroy(&self, device: &Device) {
-//         device.destroy_image_view(self.image_view, None);
-//         device.destroy_image(self.image, None);
-//         device.free_memory(self.image_memory, None);
-//     }
-// }
+    #[test]
+    fn mip_chain_of_16_bit_texels() {
+        let mut pixels = [1000u16, 3000, 5000, 65535].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        let levels = generate_mip_chain(&mut pixels, 2, 2, R16, 2);
+
+        assert_eq!(level_sizes(&levels), [(0, 2, 2), (8, 1, 1)]);
+        assert_eq!(u16::from_le_bytes([pixels[8], pixels[9]]), 18634);
+    }
+
+    #[test]
+    fn srgb_mips_average_in_linear_space() {
+        let layout = pixel_layout(vk::Format::R8G8B8A8_SRGB).unwrap();
+
+        // Black and white texels with alpha 0 and 255, plus a level of uniform gray
+        let mut pixels = vec![0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 255, 255, 255, 255];
+        let levels = generate_mip_chain(&mut pixels, 2, 2, layout, 2);
+
+        // Half the light is sRGB 188, not 128, alpha stays a plain average
+        assert_eq!(level_sizes(&levels), [(0, 2, 2), (16, 1, 1)]);
+        assert_eq!(pixels[16..], [188, 188, 188, 128]);
+
+        for value in [0, 1, 10, 54, 128, 200, 254, 255] {
+            let mut pixels = vec![value; 4];
+            generate_mip_chain(&mut pixels, 2, 2, pixel_layout(vk::Format::R8_SRGB).unwrap(), 2);
+            assert_eq!(pixels[4], value);
+        }
+    }
+
+    fn encode_png(width: u32, height: u32, color_type: png::ColorType, bit_depth: png::BitDepth, data: &[u8], palette: Option<&[u8]>) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(bit_depth);
+        if let Some(palette) = palette {
+            encoder.set_palette(palette.to_vec());
+        }
+
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+        png
+    }
+
+    fn decode(png: &[u8], format: vk::Format) -> Result<(Vec<u8>, vk::Format)> {
+        let (pixels, _, _, format, _) = decode_png_from(png, "test.png", format)?;
+        Ok((pixels, format))
+    }
+
+    #[test]
+    fn palette_expands_to_rgba() {
+        let palette = [255, 0, 0, 0, 128, 255];
+        let png = encode_png(2, 1, png::ColorType::Indexed, png::BitDepth::Eight, &[1, 0], Some(&palette));
+
+        let (pixels, format) = decode(&png, vk::Format::UNDEFINED).unwrap();
+        assert_eq!(format, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!(pixels, [0, 128, 255, 255, 255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn low_bit_gray_scales_to_full_range() {
+        // One row of 8 pixels, packed from the most significant bit
+        let cases = [
+            (png::BitDepth::One, vec![0b1010_0000], vec![255, 0, 255, 0, 0, 0, 0, 0]),
+            (png::BitDepth::Two, vec![0b1110_0100, 0], vec![255, 170, 85, 0, 0, 0, 0, 0]),
+            (png::BitDepth::Four, vec![0xF0, 0x08, 0, 0], vec![255, 0, 0, 136, 0, 0, 0, 0])
+        ];
+
+        for (bit_depth, data, expected) in cases {
+            let png = encode_png(8, 1, png::ColorType::Grayscale, bit_depth, &data, None);
+            let (pixels, format) = decode(&png, vk::Format::UNDEFINED).unwrap();
+            assert_eq!(format, vk::Format::R8_UNORM, "{:?}", bit_depth);
+            assert_eq!(pixels, expected, "{:?}", bit_depth);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_gray_is_little_endian_or_narrowed() {
+        let png = encode_png(2, 1, png::ColorType::Grayscale, png::BitDepth::Sixteen, &[0x12, 0x34, 0xFF, 0x00], None);
+
+        let (pixels, format) = decode(&png, vk::Format::UNDEFINED).unwrap();
+        assert_eq!(format, vk::Format::R16_UNORM);
+        assert_eq!(pixels, [0x34, 0x12, 0x00, 0xFF]);
+
+        let (pixels, _) = decode(&png, vk::Format::R8_UNORM).unwrap();
+        assert_eq!(pixels, [0x12, 0xFF]);
+    }
+
+    #[test]
+    fn gray_alpha_fills_two_and_four_channels() {
+        let png = encode_png(1, 1, png::ColorType::GrayscaleAlpha, png::BitDepth::Eight, &[100, 50], None);
+
+        let (pixels, format) = decode(&png, vk::Format::UNDEFINED).unwrap();
+        assert_eq!(format, vk::Format::R8G8_UNORM);
+        assert_eq!(pixels, [100, 50]);
+
+        let (pixels, _) = decode(&png, vk::Format::R8G8B8A8_UNORM).unwrap();
+        assert_eq!(pixels, [100, 100, 100, 50]);
+    }
+
+    #[test]
+    fn rgb_gains_opaque_alpha() {
+        let png = encode_png(1, 1, png::ColorType::Rgb, png::BitDepth::Eight, &[10, 20, 30], None);
+
+        let (pixels, format) = decode(&png, vk::Format::UNDEFINED).unwrap();
+        assert_eq!(format, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!(pixels, [10, 20, 30, 255]);
+
+        let png = encode_png(1, 1, png::ColorType::Rgb, png::BitDepth::Sixteen, &[0, 10, 0, 20, 0, 30], None);
+        let (pixels, format) = decode(&png, vk::Format::UNDEFINED).unwrap();
+        assert_eq!(format, vk::Format::R16G16B16A16_UNORM);
+        assert_eq!(pixels, [10, 0, 20, 0, 30, 0, 255, 255]);
+    }
+
+    #[test]
+    fn color_into_fewer_channels_keeps_luminance() {
+        let png = encode_png(2, 1, png::ColorType::Rgba, png::BitDepth::Eight, &[77, 77, 77, 10, 255, 0, 0, 20], None);
+
+        // Gray stays exact, pure red keeps its 0.2126 weight
+        let (pixels, _) = decode(&png, vk::Format::R8_UNORM).unwrap();
+        assert_eq!(pixels, [77, 54]);
+
+        let (pixels, _) = decode(&png, vk::Format::R8G8_UNORM).unwrap();
+        assert_eq!(pixels, [77, 10, 54, 20]);
+    }
+
+    #[test]
+    fn unsupported_format_is_rejected() {
+        let png = encode_png(1, 1, png::ColorType::Rgb, png::BitDepth::Eight, &[1, 2, 3], None);
+        let error = decode(&png, vk::Format::BC1_RGB_UNORM_BLOCK).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(TextureError::IncompatibleFormat(..))), "{}", error);
+    }
+}
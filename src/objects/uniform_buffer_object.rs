@@ -1,9 +1,24 @@
 use nalgebra_glm as glm;
 
+// Must match the array size in shader.frag
+pub const MAX_LIGHTS: usize = 8;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LightData {
+    // Point lights have w = 1, directional lights store the direction towards the light with w = 0
+    pub position: glm::Vec4,
+    // Color premultiplied by intensity
+    pub color: glm::Vec4
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct UniformBufferObject {
-    pub model: glm::Mat4,
     pub view: glm::Mat4,
     pub proj: glm::Mat4,
+    pub lights: [LightData; MAX_LIGHTS],
+    pub light_count: u32,
+    // std140 rounds the block up to 16 bytes
+    pub padding: [u32; 3]
 }
@@ -10,12 +10,14 @@ pub struct Vertex {
     pub pos: glm::Vec3,
     pub color: glm::Vec3,
     pub tex_coord: glm::Vec2,
-    pub normal: glm::Vec3
+    pub normal: glm::Vec3,
+    // Bitangent sign in w
+    pub tangent: glm::Vec4
 }
 
 impl Vertex {
-    pub fn new(pos: glm::Vec3, color: glm::Vec3, tex_coord: glm::Vec2, normal: glm::Vec3) -> Self {
-        Self { pos, color, tex_coord, normal }
+    pub fn new(pos: glm::Vec3, color: glm::Vec3, tex_coord: glm::Vec2, normal: glm::Vec3, tangent: glm::Vec4) -> Self {
+        Self { pos, color, tex_coord, normal, tangent }
     }
 
     pub fn binding_description() -> vk::VertexInputBindingDescription {
@@ -26,7 +28,7 @@ impl Vertex {
             .build()
     }
 
-    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 4] {
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
         let pos = vk::VertexInputAttributeDescription::builder()
             .binding(0)
             .location(0)
@@ -55,7 +57,14 @@ impl Vertex {
             .offset((size_of::<glm::Vec3>() + size_of::<glm::Vec3>() + size_of::<glm::Vec2>()) as u32)
             .build();
 
-        [pos, color, tex_coord, normal]
+        let tangent = vk::VertexInputAttributeDescription::builder()
+            .binding(0)
+            .location(4)
+            .format(vk::Format::R32G32B32A32_SFLOAT)
+            .offset((size_of::<glm::Vec3>() + size_of::<glm::Vec3>() + size_of::<glm::Vec2>() + size_of::<glm::Vec3>()) as u32)
+            .build();
+
+        [pos, color, tex_coord, normal, tangent]
     }
 }
 
@@ -64,7 +73,8 @@ impl PartialEq for Vertex {
         self.pos == other.pos &&
         self.color == other.color &&
         self.tex_coord == other.tex_coord &&
-        self.normal == other.normal
+        self.normal == other.normal &&
+        self.tangent == other.tangent
     }
 }
 
@@ -86,5 +96,10 @@ impl Hash for Vertex {
         self.normal[0].to_bits().hash(state);
         self.normal[1].to_bits().hash(state);
         self.normal[2].to_bits().hash(state);
+
+        self.tangent[0].to_bits().hash(state);
+        self.tangent[1].to_bits().hash(state);
+        self.tangent[2].to_bits().hash(state);
+        self.tangent[3].to_bits().hash(state);
     }
 }
\ No newline at end of file
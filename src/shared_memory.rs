@@ -2,6 +2,7 @@ use anyhow::{anyhow, Result};
 use vulkanalia::prelude::v1_0::*;
 
 use crate::AppData;
+use crate::graphics::allocator::*;
 use crate::shared_commands::*;
 
 pub unsafe fn copy_buffer(
@@ -52,6 +53,38 @@ pub unsafe fn copy_buffer_to_image(
     Ok(())
 }
 
+// The image is expected in TRANSFER_SRC_OPTIMAL, tightly packed rows are written to `buffer`
+pub unsafe fn copy_image_to_buffer(
+    device: &Device,
+    data: &AppData,
+    image: vk::Image,
+    buffer: vk::Buffer,
+    width: u32,
+    height: u32
+) -> Result<()> {
+    let command_buffer = begin_single_time_commands(device, data)?;
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(vk::Extent3D { width, height, depth: 1 });
+
+    device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buffer, &[region]);
+
+    end_single_time_commands(device, data, command_buffer)?;
+
+    Ok(())
+}
+
 pub unsafe fn create_buffer(
     instance: &Instance,
     device: &Device,
@@ -59,44 +92,40 @@ pub unsafe fn create_buffer(
     size: vk::DeviceSize,
     usage: vk::BufferUsageFlags,
     properties: vk::MemoryPropertyFlags
-) -> Result<(vk::Buffer, vk::DeviceMemory)> {
+) -> Result<(vk::Buffer, Allocation)> {
     let buffer_info = vk::BufferCreateInfo::builder()
         .size(size)
         .usage(usage)
         .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
     let buffer = device.create_buffer(&buffer_info, None)?;
-    let requirements = device.get_buffer_memory_requirements(buffer);
-
-    let memory_info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(instance, data, properties, requirements)?);
-
-    let buffer_memory = device.allocate_memory(&memory_info, None)?;
-    device.bind_buffer_memory(buffer, buffer_memory, 0)?;
+    let allocation = data.allocator.allocate_buffer(device, buffer, properties)?;
+    device.bind_buffer_memory(buffer, allocation.memory, allocation.offset)?;
 
-    Ok((buffer, buffer_memory))
+    Ok((buffer, allocation))
 }
 
 pub unsafe fn create_image(
-    instance: &Instance,
     device: &Device,
     data: &AppData,
     width: u32,
     height: u32,
     mip_levels: u32,
+    layer_count: u32,
+    flags: vk::ImageCreateFlags,
     samples: vk::SampleCountFlags,
     format: vk::Format,
     tiling: vk::ImageTiling,
     usage: vk::ImageUsageFlags,
     properties: vk::MemoryPropertyFlags
-) -> Result<(vk::Image, vk::DeviceMemory)> {
+) -> Result<(vk::Image, Allocation)> {
     let info = vk::ImageCreateInfo::builder()
+        .flags(flags)
         .image_type(vk::ImageType::_2D)
         .extent(vk::Extent3D { width, height, depth: 1 })
         .mip_levels(mip_levels)
         .samples(samples)
-        .array_layers(1)
+        .array_layers(layer_count)
         .format(format)
         .tiling(tiling)
         .initial_layout(vk::ImageLayout::UNDEFINED)
@@ -104,34 +133,33 @@ pub unsafe fn create_image(
         .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
     let image = device.create_image(&info, None)?;
-    let requirements = device.get_image_memory_requirements(image);
-    let info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(instance, data, properties, requirements)?);
+    let kind = if tiling == vk::ImageTiling::OPTIMAL { ResourceKind::Optimal } else { ResourceKind::Linear };
 
-    let image_memory = device.allocate_memory(&info, None)?;
-    device.bind_image_memory(image, image_memory, 0)?;
+    let allocation = data.allocator.allocate_image(device, image, properties, kind)?;
+    device.bind_image_memory(image, allocation.memory, allocation.offset)?;
 
-    Ok((image, image_memory))
+    Ok((image, allocation))
 }
 
 pub unsafe fn create_image_view(
     device: &Device,
     image: vk::Image,
+    view_type: vk::ImageViewType,
     format: vk::Format,
     aspects: vk::ImageAspectFlags,
-    mip_levels: u32
+    mip_levels: u32,
+    layer_count: u32
 ) -> Result<vk::ImageView> {
     let subresource_range = vk::ImageSubresourceRange::builder()
         .aspect_mask(aspects)
         .base_mip_level(0)
         .level_count(mip_levels)
         .base_array_layer(0)
-        .layer_count(1);
+        .layer_count(layer_count);
 
     let info = vk::ImageViewCreateInfo::builder()
         .image(image)
-        .view_type(vk::ImageViewType::_2D)
+        .view_type(view_type)
         .format(format)
         .subresource_range(subresource_range);
 
@@ -149,19 +177,58 @@ pub unsafe fn generate_mipmaps(
     height: u32,
     mip_levels: u32
 ) -> Result<()> {
-    if !instance
-        .get_physical_device_format_properties(data.physical_device, format)
-        .optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
-    {
+    if !supports_linear_blit(instance, data, format) {
         return Err(anyhow!("Texture image format does not support linear blitting!"));
     }
 
     let command_buffer = begin_single_time_commands(device, data)?;
 
+    cmd_generate_mipmaps(
+        device, command_buffer,
+        image,
+        width, height,
+        mip_levels, 1,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        vk::PipelineStageFlags::FRAGMENT_SHADER
+    );
+
+    end_single_time_commands(device, data, command_buffer)?;
+
+    Ok(())
+}
+
+pub unsafe fn supports_linear_blit(
+    instance: &Instance,
+    data: &AppData,
+    format: vk::Format
+) -> bool {
+    instance
+        .get_physical_device_format_properties(data.physical_device, format)
+        .optimal_tiling_features
+        .contains(
+            vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR |
+            vk::FormatFeatureFlags::BLIT_SRC |
+            vk::FormatFeatureFlags::BLIT_DST
+        )
+}
+
+// Expects every level in TRANSFER_DST_OPTIMAL with level 0 of each layer already written,
+// `dst_stage` is the first stage that reads the levels in `final_layout`
+pub unsafe fn cmd_generate_mipmaps(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+    layer_count: u32,
+    final_layout: vk::ImageLayout,
+    dst_stage: vk::PipelineStageFlags
+) {
     let subresource = vk::ImageSubresourceRange::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .base_array_layer(0)
-        .layer_count(1)
+        .layer_count(layer_count)
         .level_count(1);
 
     let mut barrier = vk::ImageMemoryBarrier::builder()
@@ -194,13 +261,13 @@ pub unsafe fn generate_mipmaps(
             .aspect_mask(vk::ImageAspectFlags::COLOR)
             .mip_level(i - 1)
             .base_array_layer(0)
-            .layer_count(1);
+            .layer_count(layer_count);
 
         let dst_subresource = vk::ImageSubresourceLayers::builder()
             .aspect_mask(vk::ImageAspectFlags::COLOR)
             .mip_level(i)
             .base_array_layer(0)
-            .layer_count(1);
+            .layer_count(layer_count);
 
         let blit = vk::ImageBlit::builder()
             .src_offsets([
@@ -229,14 +296,14 @@ pub unsafe fn generate_mipmaps(
         );
 
         barrier.old_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
-        barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        barrier.new_layout = final_layout;
         barrier.src_access_mask = vk::AccessFlags::TRANSFER_READ;
         barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
 
         device.cmd_pipeline_barrier(
             command_buffer,
             vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            dst_stage,
             vk::DependencyFlags::empty(),
             &[] as &[vk::MemoryBarrier],
             &[] as &[vk::BufferMemoryBarrier],
@@ -254,39 +321,19 @@ pub unsafe fn generate_mipmaps(
 
     barrier.subresource_range.base_mip_level = mip_levels - 1;
     barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
-    barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
-    barrier.src_access_mask = vk::AccessFlags::TRANSFER_READ;
+    barrier.new_layout = final_layout;
+    barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
     barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
 
     device.cmd_pipeline_barrier(
         command_buffer,
-        vk::PipelineStageFlags::TRANSFER, 
-        vk::PipelineStageFlags::FRAGMENT_SHADER, 
+        vk::PipelineStageFlags::TRANSFER,
+        dst_stage,
         vk::DependencyFlags::empty(), 
         &[] as &[vk::MemoryBarrier], 
         &[] as &[vk::BufferMemoryBarrier], 
         &[barrier]
     );
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
-}
-
-pub unsafe fn get_memory_type_index(
-    instance: &Instance,
-    data: &AppData,
-    properties: vk::MemoryPropertyFlags,
-    requirements: vk::MemoryRequirements
-) -> Result<u32> {
-    let memory = instance.get_physical_device_memory_properties(data.physical_device);
-    (0..memory.memory_type_count)
-        .find(|i| {
-            let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
-            let memory_type = memory.memory_types[*i as usize];
-            suitable && memory_type.property_flags.contains(properties)
-        })
-        .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
 }
 
 pub unsafe fn transition_image_layout(
@@ -0,0 +1,35 @@
+// Shared by the integration tests, each of which only uses some of the helpers
+#![allow(dead_code)]
+
+use vulkanalia::loader::{LibloadingLoader, LIBRARY};
+use vulkanalia::prelude::v1_0::*;
+
+// Tests that render need a Vulkan implementation such as lavapipe and are skipped without one
+pub fn vulkan_available() -> bool {
+    unsafe {
+        let Ok(loader) = LibloadingLoader::new(LIBRARY) else { return false };
+        let Ok(entry) = Entry::new(loader) else { return false };
+        let Ok(instance) = entry.create_instance(&vk::InstanceCreateInfo::builder(), None) else { return false };
+
+        let available = instance.enumerate_physical_devices().is_ok_and(|d| !d.is_empty());
+        instance.destroy_instance(None);
+        available
+    }
+}
+
+// Returns whether the test can run, `GOLDEN_REQUIRE_VULKAN` turns a missing device into a failure
+// so CI, which provides lavapipe, can't pass without running anything
+pub fn require_vulkan_or_skip(name: &str) -> bool {
+    if vulkan_available() {
+        return true;
+    }
+
+    assert!(
+        std::env::var_os("GOLDEN_REQUIRE_VULKAN").is_none(),
+        "`{}` needs a Vulkan device, but none is available and GOLDEN_REQUIRE_VULKAN is set.",
+        name
+    );
+
+    eprintln!("Skipping `{}`: no Vulkan device available.", name);
+    false
+}
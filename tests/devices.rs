@@ -0,0 +1,66 @@
+// Device selection and the `--list-devices` report, run against whatever Vulkan implementation
+// is installed. On CI that is lavapipe, which shows up as an llvmpipe CPU device.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use common::require_vulkan_or_skip;
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_vulkan-tutorial"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .arg("--no-validation")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn list_devices() {
+    if !require_vulkan_or_skip("--list-devices") {
+        return;
+    }
+
+    let output = run(&["--list-devices"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with("Device 0: "), "{}", report);
+
+    for section in ["Rating:", "Memory heaps:", "Queue families:", "Formats (optimal tiling):", "Extensions (", "Limits:"] {
+        assert!(report.contains(section), "Report is missing `{}`:\n{}", section, report);
+    }
+
+    if report.contains("llvmpipe") {
+        assert!(report.contains("Type: CPU"), "{}", report);
+        assert!(report.contains("Suitable for headless rendering: yes"), "{}", report);
+    }
+}
+
+#[test]
+fn select_gpu_by_index() {
+    if !require_vulkan_or_skip("--gpu 0") {
+        return;
+    }
+
+    let output_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("select_gpu_by_index.png");
+    let output = run(&["--gpu", "0", "--headless", output_path.to_str().unwrap()]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(Path::new(&output_path).exists());
+}
+
+#[test]
+fn unknown_gpu_fails() {
+    if !require_vulkan_or_skip("--gpu no-such-gpu") {
+        return;
+    }
+
+    let output_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("unknown_gpu.png");
+    let output = run(&["--gpu", "no-such-gpu", "--headless", output_path.to_str().unwrap()]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No physical device matches `no-such-gpu`"));
+}
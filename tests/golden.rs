@@ -0,0 +1,141 @@
+// Renders fixed scenes through the headless mode of the binary and compares them against the
+// reference images in `tests/golden`. Run with `GOLDEN_UPDATE=1` to (re)write the references,
+// they are rendered on lavapipe. Without a Vulkan device the tests are skipped, set
+// `GOLDEN_REQUIRE_VULKAN` where a device is expected, e.g. on CI, to fail them instead.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+mod common;
+
+use common::require_vulkan_or_skip;
+
+// Root mean square error over RGB, in [0, 1]
+const TOLERANCE: f64 = 0.02;
+
+// Differences are scaled up in the diff image so small ones remain visible
+const DIFF_SCALE: u8 = 4;
+
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>
+}
+
+fn read_png(path: &Path) -> Image {
+    let decoder = png::Decoder::new(File::open(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e)));
+    let mut reader = decoder.read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+
+    assert_eq!(info.color_type, png::ColorType::Rgba, "{} is not RGBA", path.display());
+    assert_eq!(info.bit_depth, png::BitDepth::Eight, "{} is not 8-bit", path.display());
+
+    pixels.truncate(info.buffer_size());
+    Image { width: info.width, height: info.height, pixels }
+}
+
+fn write_png(path: &Path, image: &Image) {
+    let mut encoder = png::Encoder::new(File::create(path).unwrap(), image.width, image.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(&image.pixels).unwrap();
+}
+
+fn rmse(a: &Image, b: &Image) -> f64 {
+    let (sum, count) = a.pixels
+        .chunks_exact(4)
+        .zip(b.pixels.chunks_exact(4))
+        .flat_map(|(a, b)| (0..3).map(move |c| (a[c] as f64 - b[c] as f64) / 255.0))
+        .fold((0.0, 0), |(sum, count), d| (sum + d * d, count + 1));
+
+    (sum / count as f64).sqrt()
+}
+
+fn diff(a: &Image, b: &Image) -> Image {
+    let pixels = a.pixels
+        .chunks_exact(4)
+        .zip(b.pixels.chunks_exact(4))
+        .flat_map(|(a, b)| {
+            let d = |c: usize| a[c].abs_diff(b[c]).saturating_mul(DIFF_SCALE);
+            [d(0), d(1), d(2), u8::MAX]
+        })
+        .collect();
+
+    Image { width: a.width, height: a.height, pixels }
+}
+
+fn check_golden(name: &str, args: &[&str]) {
+    if !require_vulkan_or_skip(name) {
+        return;
+    }
+
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let reference_path = manifest.join("tests/golden").join(format!("{}.png", name));
+
+    let output_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    fs::create_dir_all(&output_dir).unwrap();
+
+    let actual_path = output_dir.join(format!("{}.actual.png", name));
+    let diff_path = output_dir.join(format!("{}.diff.png", name));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_vulkan-tutorial"))
+        .current_dir(manifest)
+        .arg("--headless")
+        .arg(&actual_path)
+        .arg("--strict-validation")
+        .args(args)
+        .status()
+        .unwrap();
+
+    assert!(status.success(), "Rendering `{}` failed with {}", name, status);
+
+    if std::env::var_os("GOLDEN_UPDATE").is_some() {
+        fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        fs::copy(&actual_path, &reference_path).unwrap();
+        return;
+    }
+
+    assert!(
+        reference_path.exists(),
+        "Missing reference {}, rendered frame is at {}. Run with GOLDEN_UPDATE=1 to accept it.",
+        reference_path.display(),
+        actual_path.display()
+    );
+
+    let reference = read_png(&reference_path);
+    let actual = read_png(&actual_path);
+
+    assert_eq!(
+        (actual.width, actual.height),
+        (reference.width, reference.height),
+        "`{}` was rendered at a different size than its reference",
+        name
+    );
+
+    let error = rmse(&actual, &reference);
+    if error > TOLERANCE {
+        write_png(&diff_path, &diff(&actual, &reference));
+        panic!(
+            "`{}` differs from its reference: RMSE {:.4} > {:.4}. Rendered frame: {}, diff: {}",
+            name, error, TOLERANCE, actual_path.display(), diff_path.display()
+        );
+    }
+}
+
+#[test]
+fn viking_room() {
+    check_golden("viking_room", &["--scene", "resources/scenes/viking_room.ron"]);
+}
+
+#[test]
+fn jvctv() {
+    check_golden("jvctv", &["--scene", "resources/scenes/jvctv.ron"]);
+}
+
+// Renders straight into the output image, without a multisampled target to resolve
+#[test]
+fn viking_room_no_msaa() {
+    check_golden("viking_room_no_msaa", &["--scene", "resources/scenes/viking_room.ron", "--msaa", "1"]);
+}
@@ -0,0 +1,40 @@
+// GPU memory sub-allocation, checked through the `--memory-stats` report of a headless render.
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use common::require_vulkan_or_skip;
+
+// The default scene creates a couple dozen buffers and images, a handful of blocks should back them
+const MAX_DEVICE_ALLOCATIONS: u32 = 16;
+
+#[test]
+fn memory_stats() {
+    if !require_vulkan_or_skip("--memory-stats") {
+        return;
+    }
+
+    let output_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("memory_stats.png");
+    let output = Command::new(env!("CARGO_BIN_EXE_vulkan-tutorial"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("RUST_LOG", "warn")
+        .args(["--no-validation", "--memory-stats", "--headless", output_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(!stderr.contains("were not freed"), "{}", stderr);
+
+    let report = String::from_utf8(output.stdout).unwrap();
+    let allocations = report
+        .strip_prefix("GPU memory: ")
+        .and_then(|r| r.split_whitespace().next())
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or_else(|| panic!("Unexpected report:\n{}", report));
+
+    assert!(allocations <= MAX_DEVICE_ALLOCATIONS, "{}", report);
+    assert!(report.contains("sub-allocation(s)"), "{}", report);
+}
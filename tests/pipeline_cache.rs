@@ -0,0 +1,66 @@
+// The on-disk pipeline cache, saved by one headless run and picked up by the next.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use common::require_vulkan_or_skip;
+
+fn render(name: &str, cache: &Path) -> Output {
+    let output_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.png", name));
+    Command::new(env!("CARGO_BIN_EXE_vulkan-tutorial"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("RUST_LOG", "info")
+        .args(["--no-validation", "--pipeline-cache", cache.to_str().unwrap()])
+        .args(["--headless", output_path.to_str().unwrap()])
+        .output()
+        .unwrap()
+}
+
+// Header size and version of `VkPipelineCacheHeaderVersionOne`
+fn assert_valid_header(cache: &Path) {
+    let data = fs::read(cache).unwrap();
+    assert!(data.len() >= 32, "{} bytes", data.len());
+
+    let header_size = u32::from_ne_bytes(data[0..4].try_into().unwrap());
+    let header_version = u32::from_ne_bytes(data[4..8].try_into().unwrap());
+    assert!(header_size >= 32);
+    assert_eq!(header_version, 1);
+}
+
+#[test]
+fn saved_and_reloaded() {
+    if !require_vulkan_or_skip("saved_and_reloaded") {
+        return;
+    }
+
+    let cache = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("saved_and_reloaded.bin");
+    let _ = fs::remove_file(&cache);
+
+    let output = render("saved_and_reloaded", &cache);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_valid_header(&cache);
+
+    let output = render("saved_and_reloaded", &cache);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Loaded"), "{}", stderr);
+}
+
+#[test]
+fn corrupt_cache_is_replaced() {
+    if !require_vulkan_or_skip("corrupt_cache_is_replaced") {
+        return;
+    }
+
+    let cache = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("corrupt_cache_is_replaced.bin");
+    fs::write(&cache, b"not a pipeline cache, but long enough for a header").unwrap();
+
+    let output = render("corrupt_cache_is_replaced", &cache);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Ignoring pipeline cache"), "{}", stderr);
+    assert_valid_header(&cache);
+}